@@ -0,0 +1,108 @@
+use super::{binary, parsing, utility};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// `gqldbms bench`, built with `--features bench` as it counts every allocation
+
+// Bytes allocated now, and at most since PEAK was last reset
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+struct CountingAlloc;
+
+unsafe impl GlobalAlloc for CountingAlloc {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		let ptr = System.alloc(layout);
+		if !ptr.is_null() {
+			let now = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+			PEAK.fetch_max(now, Ordering::Relaxed);
+		}
+		ptr
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		System.dealloc(ptr, layout);
+		ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+	}
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+// Load a database from data.json (with utility::read_database) and from data.bin,
+// both written to a temporary directory from its present records
+pub fn run(name: &str) -> i32 {
+	let db = match utility::read_data(name) {
+		Ok(v) => v,
+		Err(e) => {
+			eprintln!("{}", e);
+			return 1;
+		}
+	};
+	let records = db.values().map(Vec::len).sum::<usize>();
+	let dir = std::env::temp_dir();
+	let json_path = dir.join(format!("gqldbms-bench-{}.json", name)).to_string_lossy().into_owned();
+	let bin_path = dir.join(format!("gqldbms-bench-{}.bin", name)).to_string_lossy().into_owned();
+	utility::write_file(&json_path, json!(db).to_string().into_bytes());
+	utility::write_file(&bin_path, binary::encode(&db));
+	drop(db);
+
+	println!("{}: {} collections, {} records, average of {} loads", name, collections_of(&bin_path), records, RUNS);
+	println!("{:<8}{:>12}{:>12}{:>14}{:>12}", "format", "file size", "load time", "peak memory", "retained");
+	let results = vec![
+		("json", &json_path, measure(|| utility::read_database(&json_path))),
+		("binary", &bin_path, measure(|| {
+			binary::decode(&std::fs::read(&bin_path).expect("Unable to read the binary database"))
+				.expect("Binary database is not valid")
+		})),
+	];
+	for (format, path, (time, peak, retained)) in results {
+		let size = std::fs::metadata(path).map(|m| m.len() as usize).unwrap_or(0);
+		println!(
+			"{:<8}{:>12}{:>9.1} ms{:>14}{:>12}",
+			format,
+			bytes(size),
+			time.as_secs_f64() * 1000.0,
+			bytes(peak),
+			bytes(retained)
+		);
+	}
+	let _ = std::fs::remove_file(&json_path);
+	let _ = std::fs::remove_file(&bin_path);
+	0
+}
+
+const RUNS: u32 = 5;
+
+// Average load time, then the peak and retained allocations of a load
+fn measure(load: impl Fn() -> parsing::DatabaseIndex) -> (Duration, usize, usize) {
+	let (mut time, mut peak, mut retained) = (Duration::from_secs(0), 0, 0);
+	for _ in 0..RUNS {
+		let base = ALLOCATED.load(Ordering::Relaxed);
+		PEAK.store(base, Ordering::Relaxed);
+		let start = Instant::now();
+		let db = load();
+		time += start.elapsed();
+		peak = PEAK.load(Ordering::Relaxed) - base;
+		retained = ALLOCATED.load(Ordering::Relaxed).saturating_sub(base);
+		drop(db);
+	}
+	(time / RUNS, peak, retained)
+}
+
+fn collections_of(bin_path: &str) -> usize {
+	std::fs::read(bin_path)
+		.ok()
+		.and_then(|data| binary::collections(&data).ok())
+		.map_or(0, |c| c.len())
+}
+
+// e.g. "1.5 MiB"
+fn bytes(n: usize) -> String {
+	match n {
+		n if n >= 1 << 20 => format!("{:.1} MiB", n as f64 / (1 << 20) as f64),
+		n if n >= 1 << 10 => format!("{:.1} KiB", n as f64 / (1 << 10) as f64),
+		n => format!("{} B", n),
+	}
+}
@@ -0,0 +1,368 @@
+use super::parsing;
+use serde_json::Value as JSONValue;
+use std::convert::TryInto;
+
+// Compact storage of a database, read without parsing JSON text:
+//
+//   "GQDB" version:u8 count:u32
+//   count × (name_len:u16 name records:u32 offset:u64 length:u64)   offset table
+//   count × (keys records × (length:u32 value))                      collections
+//
+// Keys of objects are written once per collection, as a list of varint length prefixed
+// strings, and values refer to them by index. Numbers are LE, varints are LEB128.
+
+const MAGIC: &[u8] = b"GQDB";
+const VERSION: u8 = 1;
+
+const NULL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const INT: u8 = 3; // zigzag varint
+const UINT: u8 = 4; // varint, beyond i64
+const FLOAT: u8 = 5; // f64
+const STRING: u8 = 6;
+const ARRAY: u8 = 7;
+const OBJECT: u8 = 8;
+
+// Whether the data is in this format, rather than JSON
+pub fn is_binary(data: &[u8]) -> bool {
+	data.starts_with(MAGIC)
+}
+
+// Collections are written in the order of their names, so the same data gives the same file
+pub fn encode<'a>(collections: impl IntoIterator<Item = (&'a String, &'a Vec<JSONValue>)>) -> Vec<u8> {
+	let mut collections = collections.into_iter().collect::<Vec<(&String, &Vec<JSONValue>)>>();
+	collections.sort_by(|a, b| a.0.cmp(b.0));
+	let sections = collections
+		.iter()
+		.map(|(_, records)| encode_collection(records))
+		.collect::<Vec<Vec<u8>>>();
+	let table_len = collections.iter().map(|(name, _)| 2 + name.len() + 4 + 8 + 8).sum::<usize>();
+	let mut out = Vec::with_capacity(9 + table_len + sections.iter().map(Vec::len).sum::<usize>());
+	out.extend_from_slice(MAGIC);
+	out.push(VERSION);
+	out.extend_from_slice(&(collections.len() as u32).to_le_bytes());
+	let mut offset = (9 + table_len) as u64;
+	for ((name, records), section) in collections.iter().zip(&sections) {
+		out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+		out.extend_from_slice(name.as_bytes());
+		out.extend_from_slice(&(records.len() as u32).to_le_bytes());
+		out.extend_from_slice(&offset.to_le_bytes());
+		out.extend_from_slice(&(section.len() as u64).to_le_bytes());
+		offset += section.len() as u64;
+	}
+	for section in sections {
+		out.extend(section);
+	}
+	out
+}
+
+fn encode_collection(records: &[JSONValue]) -> Vec<u8> {
+	let mut keys = Vec::new();
+	let mut body = Vec::new();
+	let mut value = Vec::new();
+	for record in records {
+		value.clear();
+		encode_value(record, &mut keys, &mut value);
+		body.extend_from_slice(&(value.len() as u32).to_le_bytes());
+		body.extend_from_slice(&value);
+	}
+	let mut out = Vec::with_capacity(body.len() + keys.len() * 8);
+	write_varint(&mut out, keys.len() as u64);
+	for key in keys {
+		write_varint(&mut out, key.len() as u64);
+		out.extend_from_slice(key.as_bytes());
+	}
+	out.extend(body);
+	out
+}
+
+fn encode_value<'a>(v: &'a JSONValue, keys: &mut Vec<&'a str>, out: &mut Vec<u8>) {
+	match v {
+		JSONValue::Null => out.push(NULL),
+		JSONValue::Bool(false) => out.push(FALSE),
+		JSONValue::Bool(true) => out.push(TRUE),
+		JSONValue::Number(n) => match (n.as_i64(), n.as_u64()) {
+			(Some(i), _) => {
+				out.push(INT);
+				write_varint(out, ((i << 1) ^ (i >> 63)) as u64);
+			}
+			(None, Some(u)) => {
+				out.push(UINT);
+				write_varint(out, u);
+			}
+			_ => {
+				out.push(FLOAT);
+				out.extend_from_slice(&n.as_f64().unwrap_or(0.0).to_le_bytes());
+			}
+		},
+		JSONValue::String(s) => {
+			out.push(STRING);
+			write_varint(out, s.len() as u64);
+			out.extend_from_slice(s.as_bytes());
+		}
+		JSONValue::Array(arr) => {
+			out.push(ARRAY);
+			write_varint(out, arr.len() as u64);
+			for x in arr {
+				encode_value(x, keys, out);
+			}
+		}
+		JSONValue::Object(obj) => {
+			out.push(OBJECT);
+			write_varint(out, obj.len() as u64);
+			for (k, x) in obj {
+				// Collections have few distinct keys, so a linear search is enough
+				let index = match keys.iter().position(|key| key == k) {
+					Some(i) => i,
+					None => {
+						keys.push(k);
+						keys.len() - 1
+					}
+				};
+				write_varint(out, index as u64);
+				encode_value(x, keys, out);
+			}
+		}
+	}
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+	while n >= 0x80 {
+		out.push((n as u8) | 0x80);
+		n >>= 7;
+	}
+	out.push(n as u8);
+}
+
+pub fn decode(data: &[u8]) -> Result<parsing::DatabaseIndex, String> {
+	read_table(data)?
+		.into_iter()
+		.map(|entry| Ok((entry.name.clone(), decode_section(data, &entry)?)))
+		.collect()
+}
+
+// Records of one collection only, or None when there's no such collection
+pub fn decode_collection(data: &[u8], name: &str) -> Result<Option<Vec<JSONValue>>, String> {
+	match read_table(data)?.into_iter().find(|entry| entry.name == name) {
+		Some(entry) => decode_section(data, &entry).map(Some),
+		None => Ok(None),
+	}
+}
+
+// Names of the collections with their number of records, without reading any record
+pub fn collections(data: &[u8]) -> Result<Vec<(String, usize)>, String> {
+	Ok(read_table(data)?.into_iter().map(|e| (e.name, e.records)).collect())
+}
+
+struct TableEntry {
+	name: String,
+	records: usize,
+	offset: usize,
+	length: usize,
+}
+
+fn read_table(data: &[u8]) -> Result<Vec<TableEntry>, String> {
+	if !is_binary(data) {
+		return Err("Not a binary database (no GQDB header)".to_owned());
+	}
+	let mut r = Reader { data, pos: MAGIC.len() };
+	let version = r.bytes(1)?[0];
+	if version != VERSION {
+		return Err(format!("Binary database version {} is not supported", version));
+	}
+	let count = r.u32()?;
+	let mut table = Vec::with_capacity(count as usize);
+	for _ in 0..count {
+		let len = u16::from_le_bytes(r.bytes(2)?.try_into().unwrap()) as usize;
+		let name = r.str(len)?.to_owned();
+		let records = r.u32()? as usize;
+		let offset = u64::from_le_bytes(r.bytes(8)?.try_into().unwrap()) as usize;
+		let length = u64::from_le_bytes(r.bytes(8)?.try_into().unwrap()) as usize;
+		if offset.checked_add(length).is_none_or(|end| end > data.len()) {
+			return Err(format!("Collection {} is cut off", name));
+		}
+		table.push(TableEntry { name, records, offset, length });
+	}
+	Ok(table)
+}
+
+fn decode_section(data: &[u8], entry: &TableEntry) -> Result<Vec<JSONValue>, String> {
+	let mut r = Reader {
+		data: &data[entry.offset..entry.offset + entry.length],
+		pos: 0,
+	};
+	let key_count = r.varint()? as usize;
+	let mut keys = Vec::with_capacity(key_count);
+	for _ in 0..key_count {
+		let len = r.varint()? as usize;
+		keys.push(r.str(len)?.to_owned());
+	}
+	let mut records = Vec::with_capacity(entry.records.min(entry.length / 4));
+	for _ in 0..entry.records {
+		let len = r.u32()? as usize;
+		let end = r.pos + len;
+		let record = r.value(&keys)?;
+		if r.pos != end {
+			return Err(format!("A record of {} has a wrong length", entry.name));
+		}
+		records.push(record);
+	}
+	Ok(records)
+}
+
+struct Reader<'a> {
+	data: &'a [u8],
+	pos: usize,
+}
+
+impl<'a> Reader<'a> {
+	fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+		match self.data.get(self.pos..self.pos.saturating_add(len)) {
+			Some(b) => {
+				self.pos += len;
+				Ok(b)
+			}
+			None => Err("Binary database is cut off".to_owned()),
+		}
+	}
+
+	fn u32(&mut self) -> Result<u32, String> {
+		Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+	}
+
+	fn str(&mut self, len: usize) -> Result<&'a str, String> {
+		std::str::from_utf8(self.bytes(len)?).map_err(|_| "Binary database has invalid UTF-8".to_owned())
+	}
+
+	fn varint(&mut self) -> Result<u64, String> {
+		let mut n = 0u64;
+		for shift in (0..64).step_by(7) {
+			let b = self.bytes(1)?[0];
+			n |= ((b & 0x7f) as u64) << shift;
+			if b < 0x80 {
+				return Ok(n);
+			}
+		}
+		Err("Binary database has a varint too long".to_owned())
+	}
+
+	fn value(&mut self, keys: &[String]) -> Result<JSONValue, String> {
+		Ok(match self.bytes(1)?[0] {
+			NULL => JSONValue::Null,
+			FALSE => JSONValue::Bool(false),
+			TRUE => JSONValue::Bool(true),
+			INT => {
+				let n = self.varint()?;
+				json!(((n >> 1) as i64) ^ -((n & 1) as i64))
+			}
+			UINT => json!(self.varint()?),
+			FLOAT => json!(f64::from_le_bytes(self.bytes(8)?.try_into().unwrap())),
+			STRING => {
+				let len = self.varint()? as usize;
+				JSONValue::String(self.str(len)?.to_owned())
+			}
+			ARRAY => {
+				let len = self.varint()? as usize;
+				// Capacity is bounded by the bytes left, so a broken length can't exhaust memory
+				let mut arr = Vec::with_capacity(len.min(self.data.len() - self.pos));
+				for _ in 0..len {
+					arr.push(self.value(keys)?);
+				}
+				JSONValue::Array(arr)
+			}
+			OBJECT => {
+				let len = self.varint()? as usize;
+				let mut obj = serde_json::Map::new();
+				for _ in 0..len {
+					let key = keys
+						.get(self.varint()? as usize)
+						.ok_or_else(|| "Binary database refers to an unknown key".to_owned())?;
+					obj.insert(key.clone(), self.value(keys)?);
+				}
+				JSONValue::Object(obj)
+			}
+			tag => return Err(format!("Binary database has an unknown value tag {}", tag)),
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn db(value: JSONValue) -> parsing::DatabaseIndex {
+		serde_json::from_value(value).unwrap()
+	}
+
+	#[test]
+	fn values_round_trip() {
+		let data = db(json!({
+			"Post": [
+				{"id": -1, "big": 18_446_744_073_709_551_615u64, "min": i64::MIN, "max": i64::MAX, "zero": 0},
+				{"id": 2, "score": 2.5, "tiny": -0.001, "draft": true, "done": false, "note": null},
+				{"id": 3, "tags": ["a", 1, [2, [3]], {"nested": {"deep": [null, {"id": 4}]}}], "text": "añb 🦀", "empty": ""},
+				{"id": 4, "score": 1.0, "draft": false, "meta": {}, "list": []}
+			],
+			"Empty": [],
+			"Scalars": [null, 1, "x", [true]]
+		}));
+		let encoded = encode(&data);
+		assert!(is_binary(&encoded));
+		assert_eq!(decode(&encoded), Ok(data.clone()));
+		// Keys repeated across records are written once
+		let id_count = encoded.windows(2).filter(|w| w == b"id").count();
+		assert_eq!(id_count, 1);
+		// The same data gives the same file
+		assert_eq!(encode(&data.clone()), encoded);
+	}
+
+	#[test]
+	fn collections_are_read_alone() {
+		let data = db(json!({"Author": [{"id": 1}], "Post": [{"id": 1, "author": 1}, {"id": 2}], "Empty": []}));
+		let encoded = encode(&data);
+		assert_eq!(decode_collection(&encoded, "Post"), Ok(Some(data["Post"].clone())));
+		assert_eq!(decode_collection(&encoded, "Empty"), Ok(Some(Vec::new())));
+		assert_eq!(decode_collection(&encoded, "Tag"), Ok(None));
+		let mut names = collections(&encoded).unwrap();
+		names.sort();
+		assert_eq!(names, vec![("Author".to_owned(), 1), ("Empty".to_owned(), 0), ("Post".to_owned(), 2)]);
+		assert_eq!(decode(&encode(&db(json!({})))), Ok(db(json!({}))));
+	}
+
+	#[test]
+	fn broken_files_are_errors() {
+		let encoded = encode(&db(json!({"Post": [{"id": 1, "title": "a"}, null]})));
+		for len in 0..encoded.len() {
+			assert!(decode(&encoded[..len]).is_err(), "cut at {}", len);
+		}
+
+		let mut wrong = encoded.clone();
+		wrong[0] = b'X';
+		assert_eq!(decode(&wrong), Err("Not a binary database (no GQDB header)".to_owned()));
+		assert!(!is_binary(b"{\"Post\": []}"));
+
+		let mut wrong = encoded.clone();
+		wrong[4] = 9;
+		assert_eq!(decode(&wrong), Err("Binary database version 9 is not supported".to_owned()));
+
+		// The last byte is the tag of the null record
+		let mut wrong = encoded.clone();
+		*wrong.last_mut().unwrap() = 99;
+		assert_eq!(decode(&wrong), Err("Binary database has an unknown value tag 99".to_owned()));
+
+		// Offset of the only collection, after its name and record count
+		let offset = 9 + 2 + "Post".len() + 4;
+		let mut wrong = encoded.clone();
+		wrong[offset..offset + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+		assert_eq!(decode(&wrong), Err("Collection Post is cut off".to_owned()));
+		let mut wrong = encoded.clone();
+		wrong[offset..offset + 8].copy_from_slice(&(encoded.len() as u64).to_le_bytes());
+		assert_eq!(decode(&wrong), Err("Collection Post is cut off".to_owned()));
+
+		// More records than written
+		let mut wrong = encoded.clone();
+		wrong[offset - 4..offset].copy_from_slice(&3u32.to_le_bytes());
+		assert_eq!(decode(&wrong), Err("Binary database is cut off".to_owned()));
+	}
+}
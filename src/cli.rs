@@ -0,0 +1,203 @@
+#[cfg(feature = "bench")]
+use super::bench;
+use super::{exchange, inference, sdl, structure, utility};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+const USAGE: &str = "Usage:
+  gqldbms                                Start the server
+  gqldbms migrate <db> [--dry-run]       Apply schema.gql changes to the data of <db>
+  gqldbms schema <db>                    Print the schema <db> is served with
+  gqldbms infer <db> [--dry-run|--force] Write a schema.gql for <db> guessed from its records
+  gqldbms export <db> <Type> <file>      Write records of <Type> to a .csv or .ndjson file
+  gqldbms import <db> <Type> <file>      Add records of <Type> from a .csv or .ndjson file,
+                                         or replace them with --replace
+  gqldbms convert <db> <format>          Store <db> as data.json (json), data.bin (binary),
+                                         or a JSON file per type in data/ (split)
+  gqldbms bench <db>                     Compare load time and memory of both formats,
+                                         with a build of --features bench
+";
+
+// Run a command given as arguments, returning the exit code
+pub fn run(args: &[String]) -> i32 {
+	let dry_run = args.iter().any(|a| a == "--dry-run");
+	let force = args.iter().any(|a| a == "--force");
+	let replace = args.iter().any(|a| a == "--replace");
+	let args = args
+		.iter()
+		.filter(|a| !a.starts_with("--"))
+		.map(|a| a.as_str())
+		.collect::<Vec<&str>>();
+	match args.as_slice() {
+		["migrate", name] => migrate(name, dry_run),
+		["schema", name] => schema(name),
+		["infer", name] => infer(name, dry_run, force),
+		["export", name, class, file] => export(name, class, file),
+		["import", name, class, file] => import(name, class, file, replace),
+		["convert", name, format] => convert(name, format),
+		["bench", name] => bench(name),
+		_ => {
+			eprint!("{}", USAGE);
+			2
+		}
+	}
+}
+
+fn migrate(name: &str, dry_run: bool) -> i32 {
+	let db = match utility::read_data(name) {
+		Ok(v) => v,
+		Err(e) => {
+			eprintln!("{}", e);
+			return 1;
+		}
+	};
+	match utility::plan_migration(name, &db) {
+		(_, None) => println!("{} is up to date with schema.gql", name),
+		(sch, Some(m)) => {
+			print!("{}", m.report());
+			if dry_run {
+				println!("Dry run, nothing is changed");
+			} else {
+				utility::apply_migration(name, &sch, &m);
+				println!("{} is migrated", name);
+			}
+		}
+	}
+	0
+}
+
+fn schema(name: &str) -> i32 {
+	let sch = utility::load_schema(name, Arc::new(RwLock::new(HashMap::new())));
+	print!("{}", sdl::print_schema(&sch));
+	0
+}
+
+fn infer(name: &str, dry_run: bool, force: bool) -> i32 {
+	let db = match utility::read_data(name) {
+		Ok(v) => v,
+		Err(e) => {
+			eprintln!("{}", e);
+			return 1;
+		}
+	};
+	let text = sdl::print_schema(&inference::infer_schema(name, &db));
+	let gql_path = format!("database/{}/schema.gql", name);
+	if dry_run {
+		print!("{}", text);
+	} else if std::fs::metadata(&gql_path).is_ok() && !force {
+		eprintln!("`{}` already exists, use --force to replace it", gql_path);
+		return 1;
+	} else {
+		utility::write_file(&gql_path, text.into_bytes());
+		println!("`{}` is written, review it before starting the server", gql_path);
+	}
+	0
+}
+
+fn export(name: &str, class: &str, file: &str) -> i32 {
+	let format = match exchange::format_of(file) {
+		Some(f) => f,
+		None => {
+			eprintln!("{} should end with .csv or .ndjson", file);
+			return 2;
+		}
+	};
+	let directory = Arc::new(RwLock::new(HashMap::new()));
+	// Only the records of the type are read, unless they're to be migrated first
+	let (sch, records) = if utility::is_migrated(name) {
+		let sch = utility::load_schema(name, directory);
+		match utility::read_collection(name, class) {
+			Ok(records) => (sch, records),
+			Err(e) => {
+				eprintln!("{}", e);
+				return 1;
+			}
+		}
+	} else {
+		let mut parser = utility::load_db(name, directory);
+		let records = parser.database.remove(class);
+		(parser.schema, records)
+	};
+	let is_type = matches!(sch.find_object(class), structure::StructureItem::Object(_));
+	let records = match records {
+		_ if class.starts_with("__") || !is_type => {
+			eprintln!("{} is not a type of {}", class, name);
+			return 1;
+		}
+		Some(r) => r,
+		None => Vec::new(),
+	};
+	match exchange::encode(format, &sch, class, &records) {
+		Ok(text) => {
+			utility::write_file(file, text.into_bytes());
+			println!("{} records of {} are written to {}", records.len(), class, file);
+			0
+		}
+		Err(e) => {
+			eprintln!("{}", e);
+			1
+		}
+	}
+}
+
+fn import(name: &str, class: &str, file: &str, replace: bool) -> i32 {
+	let format = match exchange::format_of(file) {
+		Some(f) => f,
+		None => {
+			eprintln!("{} should end with .csv or .ndjson", file);
+			return 2;
+		}
+	};
+	let text = match std::fs::read_to_string(file) {
+		Ok(t) => t,
+		Err(e) => {
+			eprintln!("Unable to read {}: {}", file, e);
+			return 1;
+		}
+	};
+	let mut parser = utility::load_db(name, Arc::new(RwLock::new(HashMap::new())));
+	let imported = parser
+		.load_all()
+		.and_then(|_| exchange::decode(format, &parser.schema, class, &text))
+		.and_then(|records| parser.import_records(class, records, replace));
+	match imported {
+		Ok(count) => {
+			utility::save_db(&mut parser);
+			println!("{} records of {} are imported", count, class);
+			0
+		}
+		Err(e) => {
+			eprintln!("Nothing is imported: {}", e);
+			1
+		}
+	}
+}
+
+fn convert(name: &str, format: &str) -> i32 {
+	match utility::convert_data(name, format) {
+		Ok(true) => {
+			println!("{} is stored as `{}`, the previous file is kept as .bak", name, utility::data_path(name));
+			0
+		}
+		Ok(false) => {
+			println!("{} is already stored as {}", name, format);
+			0
+		}
+		Err(e) => {
+			eprintln!("{}", e);
+			1
+		}
+	}
+}
+
+// Built with `--features bench` only, as counting allocations slows down every other command
+#[cfg(feature = "bench")]
+fn bench(name: &str) -> i32 {
+	bench::run(name)
+}
+
+#[cfg(not(feature = "bench"))]
+fn bench(_name: &str) -> i32 {
+	eprintln!("gqldbms is built without benchmarks, build it with `cargo build --release --features bench`");
+	2
+}
@@ -0,0 +1,359 @@
+use super::structure;
+use serde_json::Value as JSONValue;
+
+// Records of a collection as CSV or NDJSON (one JSON record per line), and back
+
+// Format of a file by its extension
+pub fn format_of(path: &str) -> Option<&'static str> {
+	match path.rsplit('.').next() {
+		Some("csv") => Some("csv"),
+		Some("ndjson") | Some("jsonl") => Some("ndjson"),
+		_ => None,
+	}
+}
+
+pub fn encode(
+	format: &str,
+	schema: &structure::StructureIndex,
+	class: &str,
+	records: &[JSONValue],
+) -> Result<String, String> {
+	let records = records.iter().map(|r| decode_enums(schema, class, r)).collect::<Vec<JSONValue>>();
+	match format {
+		"csv" => Ok(to_csv(schema, class, &records)),
+		"ndjson" => Ok(records.iter().map(|r| format!("{}\n", r)).collect()),
+		f => Err(format!("Unknown format {}, use csv or ndjson", f)),
+	}
+}
+
+// Enum values are exchanged by their names, like in queries and mutations
+fn decode_enums(schema: &structure::StructureIndex, class: &str, record: &JSONValue) -> JSONValue {
+	let mut record = record.clone();
+	for field in stored_fields(schema, class) {
+		if let structure::StructureItem::Enum(e) = schema.find_object(&field.return_type.name) {
+			let name = |v: &JSONValue| e.decode(v).map_or_else(|| v.clone(), |n| json!(n));
+			let value = match &record[&field.name] {
+				JSONValue::Null => continue,
+				JSONValue::Array(arr) => json!(arr.iter().map(name).collect::<Vec<JSONValue>>()),
+				v => name(v),
+			};
+			record[&field.name] = value;
+		}
+	}
+	record
+}
+
+pub fn decode(
+	format: &str,
+	schema: &structure::StructureIndex,
+	class: &str,
+	text: &str,
+) -> Result<Vec<JSONValue>, String> {
+	match format {
+		"csv" => from_csv(schema, class, text),
+		"ndjson" => text
+			.lines()
+			.enumerate()
+			.filter(|(_, line)| !line.trim().is_empty())
+			.map(|(i, line)| match serde_json::from_str(line) {
+				Ok(v @ JSONValue::Object(_)) => Ok(v),
+				Ok(_) => Err(format!("Line {} is not a JSON object", i + 1)),
+				Err(e) => Err(format!("Line {} is not valid JSON: {}", i + 1, e)),
+			})
+			.collect(),
+		f => Err(format!("Unknown format {}, use csv or ndjson", f)),
+	}
+}
+
+// Fields which hold stored values, rather than computed or looked up ones
+fn stored_fields<'a>(schema: &'a structure::StructureIndex, class: &str) -> Vec<&'a structure::StructureField> {
+	match schema.find_object(class) {
+		structure::StructureItem::Object(o) => o
+			.fields
+			.iter()
+			.filter(|f| match &f.data_type.resolver {
+				Some(r) => !["SUBTITUTION", "BACKREFERENCE", "MANY_TO_MANY"].contains(&r.kind.as_str()),
+				None => true,
+			})
+			.collect(),
+		_ => Vec::new(),
+	}
+}
+
+// Columns are the stored fields, then keys found in records only. Lists and objects are
+// written as JSON, null as an empty cell.
+fn to_csv(schema: &structure::StructureIndex, class: &str, records: &[JSONValue]) -> String {
+	let mut columns = stored_fields(schema, class)
+		.iter()
+		.map(|f| f.name.clone())
+		.collect::<Vec<String>>();
+	let mut extra = Vec::new();
+	for record in records {
+		if let JSONValue::Object(obj) = record {
+			for key in obj.keys() {
+				if !columns.contains(key) && !extra.contains(key) {
+					extra.push(key.clone());
+				}
+			}
+		}
+	}
+	extra.sort();
+	columns.extend(extra);
+	let mut out = csv_row(&columns);
+	for record in records {
+		let cells = columns
+			.iter()
+			.map(|c| match &record[c] {
+				JSONValue::Null => String::new(),
+				JSONValue::String(s) => s.clone(),
+				v => v.to_string(),
+			})
+			.collect::<Vec<String>>();
+		out.push_str(&csv_row(&cells));
+	}
+	out
+}
+
+fn csv_row(cells: &[String]) -> String {
+	let cells = cells
+		.iter()
+		.map(|c| {
+			if c.contains(&[',', '"', '\n', '\r'][..]) || c.trim() != c {
+				format!("\"{}\"", c.replace('"', "\"\""))
+			} else {
+				c.clone()
+			}
+		})
+		.collect::<Vec<String>>();
+	format!("{}\r\n", cells.join(","))
+}
+
+// Rows of cells, with quoted cells holding commas, quotes ("") and line breaks
+fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, String> {
+	let mut rows = Vec::new();
+	let mut row = Vec::new();
+	let mut cell = String::new();
+	let mut quoted = false;
+	let mut chars = text.trim_start_matches('\u{feff}').chars().peekable();
+	while let Some(c) = chars.next() {
+		match (quoted, c) {
+			(true, '"') if chars.peek() == Some(&'"') => {
+				chars.next();
+				cell.push('"');
+			}
+			(true, '"') => quoted = false,
+			(true, c) => cell.push(c),
+			(false, '"') if cell.is_empty() => quoted = true,
+			(false, ',') => row.push(std::mem::take(&mut cell)),
+			(false, '\r') if chars.peek() == Some(&'\n') => {}
+			(false, '\n') | (false, '\r') => {
+				row.push(std::mem::take(&mut cell));
+				rows.push(std::mem::take(&mut row));
+			}
+			(false, c) => cell.push(c),
+		}
+	}
+	if quoted {
+		return Err(format!("Row {} has an unclosed quote", rows.len() + 1));
+	}
+	if !cell.is_empty() || !row.is_empty() {
+		row.push(cell);
+		rows.push(row);
+	}
+	Ok(rows.into_iter().filter(|r| r.iter().any(|c| !c.is_empty())).collect())
+}
+
+// The first row names the fields. Cells are converted by the kind their field is stored as,
+// and empty cells are left out, so defaults apply.
+fn from_csv(schema: &structure::StructureIndex, class: &str, text: &str) -> Result<Vec<JSONValue>, String> {
+	let mut rows = parse_csv(text)?.into_iter();
+	let header = match rows.next() {
+		Some(h) => h,
+		None => return Ok(Vec::new()),
+	};
+	let fields = stored_fields(schema, class);
+	let columns = header
+		.iter()
+		.map(|name| fields.iter().find(|f| &f.name == name).cloned())
+		.collect::<Vec<Option<&structure::StructureField>>>();
+	let mut records = Vec::new();
+	for (i, row) in rows.enumerate() {
+		if row.len() > header.len() {
+			return Err(format!("Row {} has {} cells, but there are {} columns", i + 2, row.len(), header.len()));
+		}
+		let mut record = serde_json::Map::new();
+		for ((name, field), cell) in header.iter().zip(&columns).zip(&row) {
+			if cell.is_empty() {
+				continue;
+			}
+			let value = coerce(schema, *field, cell)
+				.map_err(|e| format!("Row {}, {}: {}", i + 2, name, e))?;
+			record.insert(name.clone(), value);
+		}
+		records.push(JSONValue::Object(record));
+	}
+	Ok(records)
+}
+
+fn coerce(
+	schema: &structure::StructureIndex,
+	field: Option<&structure::StructureField>,
+	cell: &str,
+) -> Result<JSONValue, String> {
+	let field = match field {
+		Some(f) => f,
+		None => return Ok(guess(cell)),
+	};
+	if field.return_type.is_array {
+		return match serde_json::from_str(cell) {
+			Ok(v @ JSONValue::Array(_)) => Ok(v),
+			_ => Err(format!("`{}` is not a JSON list", cell)),
+		};
+	}
+	// References are stored as the id of the record they point to
+	let kind = match schema.find_object(&field.return_type.name) {
+		structure::StructureItem::Object(o) => match o.find_field("id") {
+			Some(id) => id.data_type.kind.clone(),
+			None => field.data_type.kind.clone(),
+		},
+		_ => field.data_type.kind.clone(),
+	};
+	match kind.as_ref() {
+		"i32" | "i64" | "u64" => cell
+			.trim()
+			.parse::<i64>()
+			.map(|x| json!(x))
+			.map_err(|_| format!("`{}` is not an integer", cell)),
+		"f64" => cell
+			.trim()
+			.parse::<f64>()
+			.map(|x| json!(x))
+			.map_err(|_| format!("`{}` is not a number", cell)),
+		"bool" => match cell.trim().to_lowercase().as_ref() {
+			"true" | "1" => Ok(json!(true)),
+			"false" | "0" => Ok(json!(false)),
+			_ => Err(format!("`{}` is not a boolean", cell)),
+		},
+		"string" => Ok(json!(cell)),
+		// Enums may be stored as numbers, JSON as anything
+		_ => Ok(guess(cell)),
+	}
+}
+
+// JSON numbers, booleans, lists and objects as such, anything else as text
+fn guess(cell: &str) -> JSONValue {
+	match serde_json::from_str(cell) {
+		Ok(JSONValue::String(_)) | Ok(JSONValue::Null) | Err(_) => json!(cell),
+		Ok(v) => v,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{parsing, schema};
+	use std::collections::HashMap;
+	use std::sync::{Arc, RwLock};
+
+	const SCHEMA: &str = r#"
+type Query { posts: [Post] }
+enum Status { DRAFT @stored(value: 0) PUBLISHED @stored(value: 1) }
+type Post {
+  id: Int
+  title: String
+  score: Float
+  draft: Boolean
+  status: Status
+  tags: [String]
+}
+"#;
+
+	fn parser(data: JSONValue) -> parsing::QueryParser {
+		let sch = schema::traverse_schema("test", &graphql_parser::parse_schema(SCHEMA).unwrap());
+		let intros = schema::traverse_schema("instropection", &graphql_parser::schema::Document { definitions: vec![] });
+		let db = serde_json::from_value(data).unwrap();
+		parsing::QueryParser::new(db, sch, intros, Arc::new(RwLock::new(HashMap::new())))
+	}
+
+	fn cells(row: &[&str]) -> Vec<String> {
+		row.iter().map(|c| c.to_string()).collect()
+	}
+
+	#[test]
+	fn csv_is_parsed() {
+		let cases = vec![
+			("a,b\r\n1,2\r\n", vec![vec!["a", "b"], vec!["1", "2"]]),
+			("a,b\n1,2", vec![vec!["a", "b"], vec!["1", "2"]]),
+			("\u{feff}a,b\n1,2\n", vec![vec!["a", "b"], vec!["1", "2"]]),
+			("a\n\"x,\ny\"\n", vec![vec!["a"], vec!["x,\ny"]]),
+			("a\n\"say \"\"hi\"\"\"\n", vec![vec!["a"], vec!["say \"hi\""]]),
+			("a,b\n,\n\n1,\n", vec![vec!["a", "b"], vec!["1", ""]]),
+			("a,b\n1\n", vec![vec!["a", "b"], vec!["1"]]),
+			("", vec![]),
+		];
+		for (text, expected) in cases {
+			let expected = expected.iter().map(|r| cells(r)).collect::<Vec<Vec<String>>>();
+			assert_eq!(parse_csv(text), Ok(expected), "{:?}", text);
+		}
+		assert_eq!(parse_csv("a\n\"open\n"), Err("Row 2 has an unclosed quote".to_owned()));
+	}
+
+	#[test]
+	fn csv_rows_are_parsed_back() {
+		let rows = vec![
+			cells(&["plain", "", " padded ", "a,b", "say \"hi\"", "two\nlines", "cr\rlf"]),
+			cells(&["\u{feff}"]),
+		];
+		let text = rows.iter().map(|r| csv_row(r)).collect::<String>();
+		assert_eq!(parse_csv(&text), Ok(rows));
+		assert_eq!(csv_row(&cells(&["a", "b c", "\"q\""])), "a,b c,\"\"\"q\"\"\"\r\n");
+	}
+
+	#[test]
+	fn cells_are_coerced_by_field() {
+		let p = parser(json!({}));
+		let field = |name: &str| match p.schema.find_object("Post") {
+			structure::StructureItem::Object(o) => o.find_field(name),
+			_ => None,
+		};
+		let cases = vec![
+			("id", " 7 ", Ok(json!(7))),
+			("id", "7.5", Err("`7.5` is not an integer")),
+			("score", "2.5", Ok(json!(2.5))),
+			("score", "x", Err("`x` is not a number")),
+			("draft", "TRUE", Ok(json!(true))),
+			("draft", "0", Ok(json!(false))),
+			("draft", "no", Err("`no` is not a boolean")),
+			("title", "42", Ok(json!("42"))),
+			("status", "DRAFT", Ok(json!("DRAFT"))),
+			("tags", "[\"a\"]", Ok(json!(["a"]))),
+			("tags", "a", Err("`a` is not a JSON list")),
+			("other", "{\"a\":1}", Ok(json!({"a": 1}))),
+			("other", "text", Ok(json!("text"))),
+		];
+		for (name, cell, expected) in cases {
+			let expected = expected.map_err(|e| e.to_owned());
+			assert_eq!(coerce(&p.schema, field(name), cell), expected, "{} {:?}", name, cell);
+		}
+	}
+
+	#[test]
+	fn enums_are_exchanged_by_name() {
+		let records = vec![
+			json!({"id": 1, "title": "a, \"b\"\nc", "status": 1, "tags": ["x"]}),
+			json!({"id": 2, "title": "d", "status": 0, "draft": true}),
+		];
+		for format in &["csv", "ndjson"] {
+			let mut p = parser(json!({}));
+			let text = encode(format, &p.schema, "Post", &records).unwrap();
+			assert!(text.contains("PUBLISHED") && !text.contains("\"status\":1"), "{}", text);
+			let decoded = decode(format, &p.schema, "Post", &text).unwrap();
+			assert_eq!(decoded[0]["status"], json!("PUBLISHED"));
+			assert_eq!(p.import_records("Post", decoded, false), Ok(2));
+			assert_eq!(p.database["Post"][0]["status"], json!(1), "{}", format);
+			assert_eq!(p.database["Post"][0]["title"], records[0]["title"]);
+			assert_eq!(p.database["Post"][1]["status"], json!(0));
+			assert_eq!(p.database["Post"][1]["draft"], json!(true));
+		}
+	}
+}
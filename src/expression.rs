@@ -0,0 +1,455 @@
+use serde_json::Value as JSONValue;
+
+// Expressions of computed fields, evaluated against the record (sibling fields)
+// e.g. `firstName + " " + lastName`, `price * quantity`, `draft ? "Draft" : title`
+#[derive(Clone, Debug)]
+pub enum Expression {
+	Literal(JSONValue),
+	Field(Vec<String>),
+	Unary(String, Box<Expression>),
+	Binary(String, Box<Expression>, Box<Expression>),
+	Conditional(Box<Expression>, Box<Expression>, Box<Expression>),
+	Call(String, Vec<Expression>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+	Number(f64),
+	Str(String),
+	Ident(String),
+	Symbol(String),
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+	let chars = source.chars().collect::<Vec<char>>();
+	let mut tokens = Vec::new();
+	let mut i = 0;
+	while i < chars.len() {
+		let c = chars[i];
+		if c.is_whitespace() {
+			i += 1;
+		} else if c.is_ascii_digit() {
+			let start = i;
+			while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+				i += 1;
+			}
+			let text = chars[start..i].iter().collect::<String>();
+			tokens.push(Token::Number(
+				text.parse().map_err(|_| format!("Invalid number `{}`", text))?,
+			));
+		} else if c == '"' || c == '\'' {
+			let mut text = String::new();
+			i += 1;
+			while i < chars.len() && chars[i] != c {
+				if chars[i] == '\\' && i + 1 < chars.len() {
+					i += 1;
+				}
+				text.push(chars[i]);
+				i += 1;
+			}
+			if i == chars.len() {
+				return Err("Unterminated string".to_owned());
+			}
+			i += 1;
+			tokens.push(Token::Str(text));
+		} else if c.is_alphabetic() || c == '_' {
+			let start = i;
+			while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+				i += 1;
+			}
+			tokens.push(Token::Ident(chars[start..i].iter().collect()));
+		} else {
+			let two = chars[i..(i + 2).min(chars.len())].iter().collect::<String>();
+			if ["==", "!=", "<=", ">=", "&&", "||"].contains(&two.as_str()) {
+				tokens.push(Token::Symbol(two));
+				i += 2;
+			} else if "+-*/%<>!?:().,".contains(c) {
+				tokens.push(Token::Symbol(c.to_string()));
+				i += 1;
+			} else {
+				return Err(format!("Unexpected `{}`", c));
+			}
+		}
+	}
+	Ok(tokens)
+}
+
+struct Parser {
+	tokens: Vec<Token>,
+	position: usize,
+}
+
+impl Parser {
+	fn peek_symbol(&self, symbols: &[&str]) -> Option<String> {
+		match self.tokens.get(self.position) {
+			Some(Token::Symbol(s)) if symbols.contains(&s.as_str()) => Some(s.clone()),
+			_ => None,
+		}
+	}
+
+	fn expect(&mut self, symbol: &str) -> Result<(), String> {
+		match self.peek_symbol(&[symbol]) {
+			Some(_) => {
+				self.position += 1;
+				Ok(())
+			}
+			None => Err(format!("Expected `{}`", symbol)),
+		}
+	}
+
+	fn binary(
+		&mut self,
+		symbols: &[&str],
+		next: fn(&mut Parser) -> Result<Expression, String>,
+	) -> Result<Expression, String> {
+		let mut left = next(self)?;
+		while let Some(op) = self.peek_symbol(symbols) {
+			self.position += 1;
+			left = Expression::Binary(op, Box::new(left), Box::new(next(self)?));
+		}
+		Ok(left)
+	}
+
+	fn conditional(&mut self) -> Result<Expression, String> {
+		let condition = self.or()?;
+		if self.peek_symbol(&["?"]).is_none() {
+			return Ok(condition);
+		}
+		self.position += 1;
+		let then = self.conditional()?;
+		self.expect(":")?;
+		let otherwise = self.conditional()?;
+		Ok(Expression::Conditional(
+			Box::new(condition),
+			Box::new(then),
+			Box::new(otherwise),
+		))
+	}
+
+	fn or(&mut self) -> Result<Expression, String> {
+		self.binary(&["||"], Parser::and)
+	}
+
+	fn and(&mut self) -> Result<Expression, String> {
+		self.binary(&["&&"], Parser::comparison)
+	}
+
+	fn comparison(&mut self) -> Result<Expression, String> {
+		self.binary(&["==", "!=", "<", "<=", ">", ">="], Parser::additive)
+	}
+
+	fn additive(&mut self) -> Result<Expression, String> {
+		self.binary(&["+", "-"], Parser::multiplicative)
+	}
+
+	fn multiplicative(&mut self) -> Result<Expression, String> {
+		self.binary(&["*", "/", "%"], Parser::unary)
+	}
+
+	fn unary(&mut self) -> Result<Expression, String> {
+		match self.peek_symbol(&["!", "-"]) {
+			Some(op) => {
+				self.position += 1;
+				Ok(Expression::Unary(op, Box::new(self.unary()?)))
+			}
+			None => self.primary(),
+		}
+	}
+
+	fn primary(&mut self) -> Result<Expression, String> {
+		let token = match self.tokens.get(self.position) {
+			Some(v) => v.clone(),
+			None => return Err("Unexpected end of expression".to_owned()),
+		};
+		self.position += 1;
+		match token {
+			Token::Number(n) => Ok(Expression::Literal(number(n))),
+			Token::Str(s) => Ok(Expression::Literal(json!(s))),
+			Token::Symbol(ref s) if s == "(" => {
+				let inner = self.conditional()?;
+				self.expect(")")?;
+				Ok(inner)
+			}
+			Token::Ident(name) => match name.as_ref() {
+				"true" => Ok(Expression::Literal(json!(true))),
+				"false" => Ok(Expression::Literal(json!(false))),
+				"null" => Ok(Expression::Literal(JSONValue::Null)),
+				_ if self.peek_symbol(&["("]).is_some() => {
+					self.position += 1;
+					let mut args = Vec::new();
+					if self.peek_symbol(&[")"]).is_none() {
+						args.push(self.conditional()?);
+						while self.peek_symbol(&[","]).is_some() {
+							self.position += 1;
+							args.push(self.conditional()?);
+						}
+					}
+					self.expect(")")?;
+					if !FUNCTIONS.contains(&name.as_str()) {
+						return Err(format!("Unknown function `{}`", name));
+					}
+					Ok(Expression::Call(name, args))
+				}
+				_ => {
+					let mut path = vec![name];
+					while self.peek_symbol(&["."]).is_some() {
+						self.position += 1;
+						match self.tokens.get(self.position) {
+							Some(Token::Ident(n)) => path.push(n.clone()),
+							_ => return Err("Expected a field name after `.`".to_owned()),
+						}
+						self.position += 1;
+					}
+					Ok(Expression::Field(path))
+				}
+			},
+			Token::Symbol(s) => Err(format!("Unexpected `{}`", s)),
+		}
+	}
+}
+
+const FUNCTIONS: &[&str] = &["len", "words", "upper", "lower", "trim", "concat", "coalesce", "round"];
+
+pub fn parse(source: &str) -> Result<Expression, String> {
+	let mut parser = Parser {
+		tokens: tokenize(source)?,
+		position: 0,
+	};
+	let expression = parser.conditional()?;
+	match parser.tokens.get(parser.position) {
+		Some(t) => Err(format!("Unexpected {:?}", t)),
+		None => Ok(expression),
+	}
+}
+
+// Keep integers as integers, so `1 + 2` is `3` and not `3.0`
+fn number(n: f64) -> JSONValue {
+	if n.fract() == 0.0 && n.abs() < 9007199254740992.0 {
+		json!(n as i64)
+	} else {
+		json!(n)
+	}
+}
+
+pub fn is_truthy(value: &JSONValue) -> bool {
+	match value {
+		JSONValue::Null => false,
+		JSONValue::Bool(b) => *b,
+		JSONValue::Number(n) => n.as_f64() != Some(0.0),
+		JSONValue::String(s) => !s.is_empty(),
+		JSONValue::Array(a) => !a.is_empty(),
+		JSONValue::Object(_) => true,
+	}
+}
+
+// Text representation used by concatenation and templates
+pub fn to_text(value: &JSONValue) -> String {
+	match value {
+		JSONValue::Null => String::new(),
+		JSONValue::String(s) => s.clone(),
+		v => v.to_string(),
+	}
+}
+
+fn compare(left: &JSONValue, right: &JSONValue) -> Option<std::cmp::Ordering> {
+	match (left, right) {
+		(JSONValue::Number(a), JSONValue::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+		(JSONValue::String(a), JSONValue::String(b)) => Some(a.cmp(b)),
+		(JSONValue::Bool(a), JSONValue::Bool(b)) => Some(a.cmp(b)),
+		_ => None,
+	}
+}
+
+fn call(name: &str, args: Vec<JSONValue>) -> Result<JSONValue, String> {
+	let first = args.first().cloned().unwrap_or(JSONValue::Null);
+	match name {
+		"len" => Ok(match &first {
+			JSONValue::String(s) => json!(s.chars().count()),
+			JSONValue::Array(a) => json!(a.len()),
+			_ => json!(0),
+		}),
+		"words" => Ok(json!(to_text(&first).split_whitespace().count())),
+		"upper" => Ok(json!(to_text(&first).to_uppercase())),
+		"lower" => Ok(json!(to_text(&first).to_lowercase())),
+		"trim" => Ok(json!(to_text(&first).trim())),
+		"concat" => Ok(json!(args.iter().map(to_text).collect::<String>())),
+		"coalesce" => Ok(args
+			.into_iter()
+			.find(|x| !x.is_null())
+			.unwrap_or(JSONValue::Null)),
+		"round" => Ok(first.as_f64().map(|x| number(x.round())).unwrap_or(JSONValue::Null)),
+		_ => Err(format!("Unknown function `{}`", name)),
+	}
+}
+
+pub fn evaluate(expression: &Expression, record: &JSONValue) -> Result<JSONValue, String> {
+	match expression {
+		Expression::Literal(v) => Ok(v.clone()),
+		Expression::Field(path) => Ok(path.iter().fold(record.clone(), |v, f| v[f].clone())),
+		Expression::Unary(op, inner) => {
+			let value = evaluate(inner, record)?;
+			match op.as_ref() {
+				"!" => Ok(json!(!is_truthy(&value))),
+				_ => match value.as_f64() {
+					Some(n) => Ok(number(-n)),
+					None => Ok(JSONValue::Null),
+				},
+			}
+		}
+		Expression::Conditional(condition, then, otherwise) => {
+			if is_truthy(&evaluate(condition, record)?) {
+				evaluate(then, record)
+			} else {
+				evaluate(otherwise, record)
+			}
+		}
+		Expression::Call(name, args) => call(
+			name,
+			args.iter()
+				.map(|x| evaluate(x, record))
+				.collect::<Result<Vec<JSONValue>, String>>()?,
+		),
+		Expression::Binary(op, left, right) => {
+			let left = evaluate(left, record)?;
+			// Short circuit
+			match op.as_ref() {
+				"&&" if !is_truthy(&left) => return Ok(left),
+				"||" if is_truthy(&left) => return Ok(left),
+				"&&" | "||" => return evaluate(right, record),
+				_ => {}
+			}
+			let right = evaluate(right, record)?;
+			match op.as_ref() {
+				"==" => return Ok(json!(left == right)),
+				"!=" => return Ok(json!(left != right)),
+				"<" | "<=" | ">" | ">=" => {
+					return Ok(json!(match compare(&left, &right) {
+						Some(o) => match op.as_ref() {
+							"<" => o.is_lt(),
+							"<=" => o.is_le(),
+							">" => o.is_gt(),
+							_ => o.is_ge(),
+						},
+						None => false,
+					}))
+				}
+				_ => {}
+			}
+			match (op.as_ref(), left.as_f64(), right.as_f64()) {
+				("+", Some(a), Some(b)) => Ok(number(a + b)),
+				("-", Some(a), Some(b)) => Ok(number(a - b)),
+				("*", Some(a), Some(b)) => Ok(number(a * b)),
+				// Dividing by zero gives null
+				("/", Some(_), Some(b)) | ("%", Some(_), Some(b)) if b == 0.0 => {
+					Ok(JSONValue::Null)
+				}
+				("/", Some(a), Some(b)) => Ok(number(a / b)),
+				("%", Some(a), Some(b)) => Ok(number(a % b)),
+				// Anything added to a string is concatenated
+				("+", _, _) if left.is_string() || right.is_string() => {
+					Ok(json!(to_text(&left) + &to_text(&right)))
+				}
+				_ => Ok(JSONValue::Null),
+			}
+		}
+	}
+}
+
+// Compile a template, replacing every `{expression}` e.g. "{firstName} {lastName}",
+// into the concatenation of its parts
+pub fn parse_template(template: &str) -> Result<Expression, String> {
+	let mut parts = Vec::new();
+	let mut rest = template;
+	while let Some(start) = rest.find('{') {
+		parts.push(Expression::Literal(json!(&rest[..start])));
+		let end = match rest[start..].find('}') {
+			Some(v) => start + v,
+			None => return Err("Unterminated `{` in template".to_owned()),
+		};
+		parts.push(parse(&rest[start + 1..end])?);
+		rest = &rest[end + 1..];
+	}
+	parts.push(Expression::Literal(json!(rest)));
+	Ok(Expression::Call("concat".to_owned(), parts))
+}
+
+// Expression of a `@computed` field by its mode ("expr" or "template") and source
+pub fn parse_computed(mode: &str, source: &str) -> Result<Expression, String> {
+	match mode {
+		"template" => parse_template(source),
+		_ => parse(source),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn eval(source: &str, record: JSONValue) -> JSONValue {
+		evaluate(&parse(source).unwrap(), &record).unwrap()
+	}
+
+	#[test]
+	fn operators_follow_precedence() {
+		let cases = [
+			("1 + 2 * 3", json!(7)),
+			("(1 + 2) * 3", json!(9)),
+			("10 - 4 - 3", json!(3)),
+			("7 % 4 * 2", json!(6)),
+			("1 + 2 == 3 && 2 < 1 || true", json!(true)),
+			("1 < 2 ? 3 < 4 ? \"a\" : \"b\" : \"c\"", json!("a")),
+			("!false && !0", json!(true)),
+			("1 / 4", json!(0.25)),
+		];
+		for (source, expected) in &cases {
+			assert_eq!(&eval(source, json!({})), expected, "{}", source);
+		}
+	}
+
+	#[test]
+	fn unary_minus_binds_tighter_than_binary() {
+		assert_eq!(eval("-2 * 3", json!({})), json!(-6));
+		assert_eq!(eval("2 - -3", json!({})), json!(5));
+		assert_eq!(eval("--x", json!({"x": 4})), json!(4));
+		assert_eq!(eval("-(1 + 2) * 2", json!({})), json!(-6));
+		assert_eq!(eval("-name", json!({"name": "a"})), JSONValue::Null);
+	}
+
+	#[test]
+	fn missing_values_give_null() {
+		let record = json!({"price": 2, "author": {"name": "Neo"}});
+		assert_eq!(eval("price * quantity", record.clone()), JSONValue::Null);
+		assert_eq!(eval("-quantity", record.clone()), JSONValue::Null);
+		assert_eq!(eval("price / 0", record.clone()), JSONValue::Null);
+		assert_eq!(eval("price % 0", record.clone()), JSONValue::Null);
+		assert_eq!(eval("author.name + \"!\"", record.clone()), json!("Neo!"));
+		assert_eq!(eval("author.age + \"!\"", record.clone()), json!("!"));
+		assert_eq!(eval("coalesce(quantity, price)", record.clone()), json!(2));
+		assert_eq!(eval("quantity > 1", record), json!(false));
+	}
+
+	#[test]
+	fn templates_interpolate_expressions() {
+		let template = parse_template("{first} {last}: {len(tags)} tags").unwrap();
+		let record = json!({"first": "John", "tags": ["a", "b"]});
+		assert_eq!(evaluate(&template, &record).unwrap(), json!("John : 2 tags"));
+	}
+
+	#[test]
+	fn invalid_expressions_are_errors() {
+		let cases = [
+			("1 +", "Unexpected end of expression"),
+			("(1 + 2", "Expected `)`"),
+			("a ? b", "Expected `:`"),
+			("\"open", "Unterminated string"),
+			("1 # 2", "Unexpected `#`"),
+			("1 2", "Unexpected Number(2.0)"),
+			("a.", "Expected a field name after `.`"),
+			("sum(a)", "Unknown function `sum`"),
+		];
+		for (source, expected) in &cases {
+			assert_eq!(parse(source).unwrap_err(), *expected, "{}", source);
+		}
+		assert_eq!(parse_template("{a").unwrap_err(), "Unterminated `{` in template");
+	}
+}
@@ -1,3 +1,4 @@
+use super::integrity;
 use super::parsing;
 use super::structure;
 use serde_json::Value;
@@ -8,10 +9,191 @@ use std::convert::TryInto;
 pub enum FieldHashmaps {
 	String(HashMap<String, Vec<usize>>),
 	I32(HashMap<i32, Vec<usize>>),
+	I64(HashMap<i64, Vec<usize>>),
 	U64(HashMap<u64, Vec<usize>>),
+	Bool(HashMap<bool, Vec<usize>>),
+	// Enums and custom scalars, serialized as JSON
+	Json(HashMap<String, Vec<usize>>),
+	// Multiple fields, each value serialized as JSON
+	Composite(HashMap<Vec<String>, Vec<usize>>),
+	// Referenced id (serialized as JSON) to records that reference it.
+	// A record referencing several ids is listed under each of them.
+	Reverse(HashMap<String, Vec<usize>>),
 	Null,
 }
 
+fn push_position<T: std::hash::Hash + Eq>(h: &mut HashMap<T, Vec<usize>>, key: T, position: usize) {
+	h.entry(key).or_default().push(position);
+}
+
+fn take_position<T: std::hash::Hash + Eq>(h: &mut HashMap<T, Vec<usize>>, key: T, position: usize) {
+	if let Some(v) = h.get_mut(&key) {
+		v.retain(|x| *x != position);
+		if v.is_empty() {
+			h.remove(&key);
+		}
+	}
+}
+
+fn as_i32(value: &Value) -> Option<i32> {
+	value.as_i64().and_then(|v| v.try_into().ok())
+}
+
+// Custom scalars and enums can be anything but null
+fn as_json(value: &Value) -> Option<String> {
+	match value {
+		Value::Null => None,
+		v => Some(v.to_string()),
+	}
+}
+
+impl FieldHashmaps {
+	// Empty hashmaps for a field of this data kind, None if it can't be indexed
+	pub fn from_kind(kind: &str, schema: &structure::StructureIndex) -> Option<FieldHashmaps> {
+		match kind {
+			"string" => Some(FieldHashmaps::String(HashMap::new())),
+			"i32" => Some(FieldHashmaps::I32(HashMap::new())),
+			"i64" => Some(FieldHashmaps::I64(HashMap::new())),
+			"u64" => Some(FieldHashmaps::U64(HashMap::new())),
+			"bool" => Some(FieldHashmaps::Bool(HashMap::new())),
+			n => match schema.find_object(n) {
+				structure::StructureItem::Enum(_) | structure::StructureItem::Scalar(_) => {
+					Some(FieldHashmaps::Json(HashMap::new()))
+				}
+				_ => None,
+			},
+		}
+	}
+
+	// Look up positions of records that has exactly these values (one per indexed field)
+	pub fn find(&self, values: &[&Value]) -> Option<&Vec<usize>> {
+		match self {
+			FieldHashmaps::String(h) => values[0].as_str().and_then(|v| h.get(v)),
+			FieldHashmaps::I32(h) => as_i32(values[0]).and_then(|v| h.get(&v)),
+			FieldHashmaps::I64(h) => values[0].as_i64().and_then(|v| h.get(&v)),
+			FieldHashmaps::U64(h) => values[0].as_u64().and_then(|v| h.get(&v)),
+			FieldHashmaps::Bool(h) => values[0].as_bool().and_then(|v| h.get(&v)),
+			FieldHashmaps::Json(h) => as_json(values[0]).and_then(|v| h.get(&v)),
+			FieldHashmaps::Composite(h) => h.get(&composite_values(values)),
+			FieldHashmaps::Reverse(h) => as_json(values[0]).and_then(|v| h.get(&v)),
+			FieldHashmaps::Null => None,
+		}
+	}
+
+	// Whether these values has the right type to be indexed here
+	pub fn accepts(&self, values: &[&Value]) -> bool {
+		match self {
+			FieldHashmaps::String(_) => values[0].is_string(),
+			FieldHashmaps::I32(_) => as_i32(values[0]).is_some(),
+			FieldHashmaps::I64(_) => values[0].is_i64(),
+			FieldHashmaps::U64(_) => values[0].is_u64(),
+			FieldHashmaps::Bool(_) => values[0].is_boolean(),
+			FieldHashmaps::Json(_) => !values[0].is_null(),
+			FieldHashmaps::Composite(_) | FieldHashmaps::Reverse(_) => true,
+			FieldHashmaps::Null => false,
+		}
+	}
+
+	// Returns false (and index nothing) when values are not accepted
+	pub fn insert(&mut self, values: &[&Value], position: usize) -> bool {
+		if !self.accepts(values) {
+			return false;
+		}
+		match self {
+			FieldHashmaps::String(h) => push_position(h, values[0].as_str().unwrap().to_owned(), position),
+			FieldHashmaps::I32(h) => push_position(h, as_i32(values[0]).unwrap(), position),
+			FieldHashmaps::I64(h) => push_position(h, values[0].as_i64().unwrap(), position),
+			FieldHashmaps::U64(h) => push_position(h, values[0].as_u64().unwrap(), position),
+			FieldHashmaps::Bool(h) => push_position(h, values[0].as_bool().unwrap(), position),
+			FieldHashmaps::Json(h) => push_position(h, as_json(values[0]).unwrap(), position),
+			FieldHashmaps::Composite(h) => push_position(h, composite_values(values), position),
+			FieldHashmaps::Reverse(h) => {
+				for id in integrity::referenced_ids(values[0]) {
+					push_position(h, id.to_string(), position);
+				}
+			}
+			FieldHashmaps::Null => {}
+		}
+		true
+	}
+
+	pub fn remove(&mut self, values: &[&Value], position: usize) {
+		if !self.accepts(values) {
+			return;
+		}
+		match self {
+			FieldHashmaps::String(h) => take_position(h, values[0].as_str().unwrap().to_owned(), position),
+			FieldHashmaps::I32(h) => take_position(h, as_i32(values[0]).unwrap(), position),
+			FieldHashmaps::I64(h) => take_position(h, values[0].as_i64().unwrap(), position),
+			FieldHashmaps::U64(h) => take_position(h, values[0].as_u64().unwrap(), position),
+			FieldHashmaps::Bool(h) => take_position(h, values[0].as_bool().unwrap(), position),
+			FieldHashmaps::Json(h) => take_position(h, as_json(values[0]).unwrap(), position),
+			FieldHashmaps::Composite(h) => take_position(h, composite_values(values), position),
+			FieldHashmaps::Reverse(h) => {
+				for id in integrity::referenced_ids(values[0]) {
+					take_position(h, id.to_string(), position);
+				}
+			}
+			FieldHashmaps::Null => {}
+		}
+	}
+
+	// Same keys pointing to the same positions, regardless of order
+	pub fn is_equivalent(&self, other: &FieldHashmaps) -> bool {
+		fn same<T: std::hash::Hash + Eq>(
+			a: &HashMap<T, Vec<usize>>,
+			b: &HashMap<T, Vec<usize>>,
+		) -> bool {
+			a.len() == b.len()
+				&& a.iter().all(|(k, v)| match b.get(k) {
+					Some(w) => {
+						let (mut v, mut w) = (v.clone(), w.clone());
+						v.sort();
+						w.sort();
+						v == w
+					}
+					None => false,
+				})
+		}
+		match (self, other) {
+			(FieldHashmaps::String(a), FieldHashmaps::String(b)) => same(a, b),
+			(FieldHashmaps::I32(a), FieldHashmaps::I32(b)) => same(a, b),
+			(FieldHashmaps::I64(a), FieldHashmaps::I64(b)) => same(a, b),
+			(FieldHashmaps::U64(a), FieldHashmaps::U64(b)) => same(a, b),
+			(FieldHashmaps::Bool(a), FieldHashmaps::Bool(b)) => same(a, b),
+			(FieldHashmaps::Json(a), FieldHashmaps::Json(b)) => same(a, b),
+			(FieldHashmaps::Composite(a), FieldHashmaps::Composite(b)) => same(a, b),
+			(FieldHashmaps::Reverse(a), FieldHashmaps::Reverse(b)) => same(a, b),
+			(FieldHashmaps::Null, FieldHashmaps::Null) => true,
+			_ => false,
+		}
+	}
+
+	// Groups of positions that share the same key
+	pub fn find_duplicates(&self) -> Vec<&Vec<usize>> {
+		let positions: Vec<&Vec<usize>> = match self {
+			FieldHashmaps::String(h) => h.values().collect(),
+			FieldHashmaps::I32(h) => h.values().collect(),
+			FieldHashmaps::I64(h) => h.values().collect(),
+			FieldHashmaps::U64(h) => h.values().collect(),
+			FieldHashmaps::Bool(h) => h.values().collect(),
+			FieldHashmaps::Json(h) => h.values().collect(),
+			// Null never equals to another null
+			FieldHashmaps::Composite(h) => h
+				.iter()
+				.filter(|(k, _)| !k.iter().any(|x| x == "null"))
+				.map(|(_, v)| v)
+				.collect(),
+			FieldHashmaps::Reverse(_) | FieldHashmaps::Null => Vec::new(),
+		};
+		positions.into_iter().filter(|v| v.len() > 1).collect()
+	}
+}
+
+pub fn composite_values(values: &[&Value]) -> Vec<String> {
+	values.iter().map(|v| v.to_string()).collect()
+}
+
 // Type, PropertyName (usually "id"), ValueOfProperty. returns index in database
 // Valid for database lifetimes
 pub type DatabaseHashmaps = HashMap<String, Option<HashMap<String, FieldHashmaps>>>;
@@ -63,31 +245,368 @@ pub fn build_hashmaps(
 				continue;
 			}
 		};
-		let (field_name, hash) = (
-			"id".to_owned(),
-			match obj.find_field("id") {
-				Option::Some(field) => match field.data_type.kind.as_ref() {
-					"string" => FieldHashmaps::String(subindex_hashmaps(arr_classes, |value| {
-						value["id"].as_str().unwrap().to_string()
-					})),
-					"i32" => FieldHashmaps::I32(subindex_hashmaps(arr_classes, |value| {
-						value["id"].as_i64().unwrap().try_into().unwrap()
-					})),
-					"u64" => FieldHashmaps::U64(subindex_hashmaps(arr_classes, |value| {
-						value["id"].as_u64().unwrap()
-					})),
-					_ => panic!("id is not indexable!"),
-				},
-				_ => {
-					hashes.insert(obj.name.clone(), Option::None);
-					continue;
-				}
-			},
-		);
-
+		let backrefs = backref_fields(schema, &obj.name);
 		let mut type_hash = HashMap::new();
-		type_hash.insert(field_name, hash);
+		match obj
+			.find_field("id")
+			.and_then(|field| FieldHashmaps::from_kind(&field.data_type.kind, schema))
+		{
+			Option::Some(mut hash) => {
+				for (position, value) in arr_classes.iter().enumerate() {
+					hash.insert(&[&value["id"]], position);
+				}
+				type_hash.insert("id".to_owned(), hash);
+			}
+			// Join records have no id, but are still looked up by their sides
+			_ if !backrefs.is_empty() => {}
+			_ => {
+				hashes.insert(obj.name.clone(), Option::None);
+				continue;
+			}
+		}
+		for index in &obj.indexes {
+			let hash = FieldHashmaps::Composite(subindex_hashmaps(arr_classes, |value| {
+				composite_values(
+					&index
+						.fields
+						.iter()
+						.map(|f| &value[f])
+						.collect::<Vec<&Value>>(),
+				)
+			}));
+			type_hash.insert(index.key(), hash);
+		}
+		for field in backrefs {
+			let mut hash = FieldHashmaps::Reverse(HashMap::new());
+			for (position, value) in arr_classes.iter().enumerate() {
+				hash.insert(&[&value[&field]], position);
+			}
+			type_hash.insert(reverse_key(&field), hash);
+		}
 		hashes.insert(obj.name.clone(), Some(type_hash));
 	}
 	hashes
 }
+
+// Print records which `id` is missing or has a type that can't be indexed.
+// Those records can't be referenced, but they won't stop the database to load.
+pub fn report_invalid_ids(db: &parsing::DatabaseIndex, schema: &structure::StructureIndex) {
+	for obj in &schema.objects {
+		let field = match obj.find_field("id") {
+			Some(v) => v,
+			_ => continue,
+		};
+		let hash = match FieldHashmaps::from_kind(&field.data_type.kind, schema) {
+			Some(v) => v,
+			_ => {
+				println!(
+					"Warning: {}.id of type {} can't be indexed",
+					obj.name, field.return_type.name
+				);
+				continue;
+			}
+		};
+		for (position, value) in db.get(&obj.name).into_iter().flatten().enumerate() {
+			if !hash.accepts(&[&value["id"]]) {
+				println!(
+					"Warning: {} at {} has {} id: {}",
+					obj.name,
+					position,
+					if value["id"].is_null() { "missing" } else { "mistyped" },
+					value["id"]
+				);
+			}
+		}
+	}
+}
+
+// Print records which violates `unique` indexes
+pub fn report_duplicates(hashes: &DatabaseHashmaps, schema: &structure::StructureIndex) {
+	for obj in &schema.objects {
+		let type_hash = match hashes.get(&obj.name) {
+			Some(Some(v)) => v,
+			_ => continue,
+		};
+		for key in obj.unique_keys() {
+			if let Some(hash) = type_hash.get(&key) {
+				for dup in hash.find_duplicates() {
+					println!("Warning: {} has duplicated ({}) at {:?}", obj.name, key, dup);
+				}
+			}
+		}
+	}
+}
+
+// Values of a record which are indexed under `key` (e.g. "id", "authorId,status" or "author[]")
+pub fn index_values<'a>(key: &str, record: &'a Value) -> Vec<&'a Value> {
+	key.split(',')
+		.map(|f| &record[f.trim_end_matches("[]")])
+		.collect()
+}
+
+// Key of the reverse index of a reference field
+pub fn reverse_key(field: &str) -> String {
+	format!("{}[]", field)
+}
+
+// Fields of `class` with a reverse index: its references, found by deletes to apply
+// `@relation(onDelete:)`, and those which `@backref` or `@manyToMany` fields look up
+pub fn backref_fields(schema: &structure::StructureIndex, class: &str) -> Vec<String> {
+	let mut fields = match schema.find_object(class) {
+		structure::StructureItem::Object(o) => integrity::reference_fields(o, schema)
+			.into_iter()
+			.map(|f| f.name.clone())
+			.collect(),
+		_ => Vec::new(),
+	};
+	for obj in &schema.objects {
+		for field in &obj.fields {
+			match &field.data_type.resolver {
+				Some(r)
+					if r.kind == "BACKREFERENCE"
+						&& field.return_type.name == class
+						&& !fields.contains(&r.flags[0]) =>
+				{
+					fields.push(r.flags[0].clone());
+				}
+				// Join records are looked up by the side they're read from
+				Some(r)
+					if r.kind == "MANY_TO_MANY"
+						&& r.flags[0] == class
+						&& !fields.contains(&r.flags[1]) =>
+				{
+					fields.push(r.flags[1].clone());
+				}
+				_ => {}
+			}
+		}
+	}
+	fields
+}
+
+// Incremental maintenance: index a record that just placed at `position`
+pub fn insert_position(hashes: &mut DatabaseHashmaps, class: &str, position: usize, record: &Value) {
+	if let Some(Some(type_hash)) = hashes.get_mut(class) {
+		for (key, hash) in type_hash.iter_mut() {
+			hash.insert(&index_values(key, record), position);
+		}
+	}
+}
+
+// Incremental maintenance: forget a record that (still) sits at `position`
+pub fn remove_position(hashes: &mut DatabaseHashmaps, class: &str, position: usize, record: &Value) {
+	if let Some(Some(type_hash)) = hashes.get_mut(class) {
+		for (key, hash) in type_hash.iter_mut() {
+			hash.remove(&index_values(key, record), position);
+		}
+	}
+}
+
+pub fn update_position(
+	hashes: &mut DatabaseHashmaps,
+	class: &str,
+	position: usize,
+	old: &Value,
+	new: &Value,
+) {
+	remove_position(hashes, class, position, old);
+	insert_position(hashes, class, position, new);
+}
+
+// Compare incrementally maintained hashmaps against a full rebuild
+pub fn verify_hashmaps(
+	hashes: &DatabaseHashmaps,
+	db: &parsing::DatabaseIndex,
+	schema: &structure::StructureIndex,
+) -> bool {
+	let rebuilt = build_hashmaps(db, schema);
+	rebuilt.iter().all(|(class, type_hash)| {
+		match (type_hash, hashes.get(class)) {
+			(Some(a), Some(Some(b))) => {
+				a.len() == b.len()
+					&& a.iter().all(|(key, hash)| match b.get(key) {
+						Some(other) => hash.is_equivalent(other),
+						None => false,
+					})
+			}
+			(None, Some(None)) | (None, None) => true,
+			_ => false,
+		}
+	})
+}
+
+// Positions of records in `class` whose fields equal to all `filters`.
+// Picks the index that covers most of the filtered fields, then scan the rest.
+pub fn find_equals(
+	hashes: &DatabaseHashmaps,
+	records: &[Value],
+	class: &str,
+	filters: &[(String, Value)],
+) -> Vec<usize> {
+	let mut best: Option<(Vec<&str>, &FieldHashmaps)> = None;
+	if let Some(Some(type_hash)) = hashes.get(class) {
+		for (key, hash) in type_hash {
+			let fields = key.split(',').collect::<Vec<&str>>();
+			let covered = fields
+				.iter()
+				.all(|f| filters.iter().any(|(name, _)| name == f));
+			let better = match &best {
+				Some((b, _)) => fields.len() > b.len(),
+				None => true,
+			};
+			if covered && better {
+				best = Some((fields, hash));
+			}
+		}
+	}
+	let candidates = match best {
+		Some((fields, hash)) => {
+			let values = fields
+				.iter()
+				.map(|f| &filters.iter().find(|(name, _)| name == f).unwrap().1)
+				.collect::<Vec<&Value>>();
+			match hash.find(&values) {
+				Some(v) => v.clone(),
+				None => return Vec::new(),
+			}
+		}
+		None => (0..records.len()).collect(),
+	};
+	candidates
+		.into_iter()
+		.filter(|i| filters.iter().all(|(name, value)| &records[*i][name] == value))
+		.collect()
+}
+
+// Inverted index over searchable fields of a type, ranked with BM25
+#[derive(Clone, Default, PartialEq)]
+pub struct SearchIndex {
+	pub fields: Vec<String>,
+	// Term, position in database, and how many times the term appears there
+	pub postings: HashMap<String, HashMap<usize, usize>>,
+	// Count of terms for each position in database
+	pub lengths: HashMap<usize, usize>,
+	pub total_length: usize,
+}
+
+// Type name. Only types with searchable fields are present
+pub type DatabaseSearchIndexes = HashMap<String, SearchIndex>;
+
+// Split by anything that's not alphanumeric, then case-fold
+pub fn tokenize(text: &str) -> Vec<String> {
+	text.split(|c: char| !c.is_alphanumeric())
+		.filter(|x| !x.is_empty())
+		.map(|x| x.to_lowercase())
+		.collect()
+}
+
+impl SearchIndex {
+	pub fn new(fields: Vec<String>) -> SearchIndex {
+		SearchIndex {
+			fields,
+			..SearchIndex::default()
+		}
+	}
+
+	fn record_terms(&self, record: &Value) -> Vec<String> {
+		let mut terms = Vec::new();
+		for field in &self.fields {
+			match &record[field] {
+				Value::String(s) => terms.extend(tokenize(s)),
+				Value::Array(arr) => {
+					for s in arr.iter().filter_map(|x| x.as_str()) {
+						terms.extend(tokenize(s));
+					}
+				}
+				_ => {}
+			}
+		}
+		terms
+	}
+
+	pub fn insert(&mut self, position: usize, record: &Value) {
+		let terms = self.record_terms(record);
+		self.total_length += terms.len();
+		self.lengths.insert(position, terms.len());
+		for term in terms {
+			*self
+				.postings
+				.entry(term)
+				.or_default()
+				.entry(position)
+				.or_insert(0) += 1;
+		}
+	}
+
+	pub fn remove(&mut self, position: usize, record: &Value) {
+		if let Some(length) = self.lengths.remove(&position) {
+			self.total_length -= length;
+		}
+		for term in self.record_terms(record) {
+			if let Some(docs) = self.postings.get_mut(&term) {
+				docs.remove(&position);
+				if docs.is_empty() {
+					self.postings.remove(&term);
+				}
+			}
+		}
+	}
+
+	// Positions that match any term of the query, best match first
+	pub fn search(&self, query: &str) -> Vec<(usize, f64)> {
+		let (k1, b) = (1.2, 0.75);
+		let count = self.lengths.len() as f64;
+		let average = if self.lengths.is_empty() {
+			1.0
+		} else {
+			self.total_length as f64 / count
+		};
+		let mut scores: HashMap<usize, f64> = HashMap::new();
+		let mut terms = tokenize(query);
+		terms.sort();
+		terms.dedup();
+		for term in terms {
+			let docs = match self.postings.get(&term) {
+				Some(v) => v,
+				None => continue,
+			};
+			let df = docs.len() as f64;
+			let idf = ((count - df + 0.5) / (df + 0.5) + 1.0).ln();
+			for (position, tf) in docs {
+				let tf = *tf as f64;
+				let length = self.lengths[position] as f64;
+				*scores.entry(*position).or_insert(0.0) +=
+					idf * tf * (k1 + 1.0) / (tf + k1 * (1.0 - b + b * length / average));
+			}
+		}
+		let mut ranked = scores.into_iter().collect::<Vec<(usize, f64)>>();
+		ranked.sort_by(|x, y| y.1.partial_cmp(&x.1).unwrap().then(x.0.cmp(&y.0)));
+		ranked
+	}
+}
+
+pub fn build_search_indexes(
+	db: &parsing::DatabaseIndex,
+	schema: &structure::StructureIndex,
+) -> DatabaseSearchIndexes {
+	let mut indexes = HashMap::new();
+	for obj in &schema.objects {
+		let fields = obj
+			.fields
+			.iter()
+			.filter(|f| f.is_searchable)
+			.map(|f| f.name.clone())
+			.collect::<Vec<String>>();
+		if fields.is_empty() {
+			continue;
+		}
+		let mut index = SearchIndex::new(fields);
+		if let Some(arr_classes) = db.get(&obj.name) {
+			for (position, record) in arr_classes.iter().enumerate() {
+				index.insert(position, record);
+			}
+		}
+		indexes.insert(obj.name.clone(), index);
+	}
+	indexes
+}
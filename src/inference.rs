@@ -0,0 +1,397 @@
+use super::{parsing, scalar, schema, structure};
+use serde_json::Value as JSONValue;
+use std::collections::{HashMap, HashSet};
+
+// Guess a schema from the records of a database, as a start for writing schema.gql.
+// Every collection becomes a type, listed by `Query` and written by `Mutation`.
+pub fn infer_schema(name: &str, db: &parsing::DatabaseIndex) -> structure::StructureIndex {
+	let mut classes = db
+		.keys()
+		.filter(|k| !k.starts_with("__") && !["Query", "Mutation", "Subscription"].contains(&k.as_str()))
+		.cloned()
+		.collect::<Vec<String>>();
+	classes.sort();
+	// Ids of each collection, as text so 1 and "1" don't meet
+	let ids = classes
+		.iter()
+		.map(|c| (c.clone(), db[c].iter().map(|r| r["id"].to_string()).collect::<HashSet<String>>()))
+		.collect::<HashMap<String, HashSet<String>>>();
+
+	let mut objects = Vec::new();
+	let mut scalars = Vec::new();
+	let mut query = empty_type("Query");
+	let mut mutation = empty_type("Mutation");
+	for class in &classes {
+		let records = &db[class];
+		let mut obj = empty_type(class);
+		for field in field_names(records) {
+			let values = records.iter().map(|r| &r[&field]).collect::<Vec<&JSONValue>>();
+			let mut inferred = infer_field(class, &field, &values, &ids);
+			// ids are generated like those already there
+			if field == "id" {
+				inferred.data_type.default = match inferred.data_type.kind.as_ref() {
+					"i32" | "i64" => Some("INCREMENT"),
+					"string" => Some("UUID"),
+					_ => None,
+				}
+				.map(|kind| structure::StructureDataDefault {
+					kind: kind.to_owned(),
+					reference: JSONValue::Null,
+				});
+			}
+			let kind = inferred.return_type.name.clone();
+			if scalar::is_builtin(&kind) && !scalars.iter().any(|s: &structure::StructureScalar| s.name == kind) {
+				scalars.push(structure::StructureScalar {
+					name: kind,
+					description: String::new(),
+				});
+			}
+			obj.add_field(inferred);
+		}
+		let mut list = structure::StructureField::from(
+			plural(&schema::join_key(class)),
+			String::new(),
+			class.clone(),
+			true,
+			None,
+		);
+		list.return_type.is_nullable = true;
+		query.add_field(list);
+		for prefix in &["create", "update", "delete"] {
+			let mut field = structure::StructureField::from(
+				format!("{}{}", prefix, class),
+				String::new(),
+				class.clone(),
+				false,
+				None,
+			);
+			field.return_type.is_nullable = true;
+			mutation.add_field(field);
+		}
+		objects.push(obj);
+	}
+	objects.insert(0, mutation);
+	objects.insert(0, query);
+	let mut sch = (structure::StructureIndex {
+		name: name.to_owned(),
+		objects,
+		enums: Vec::new(),
+		scalars,
+		inputs: Vec::new(),
+		hash: String::new(),
+		hashed_objects: HashMap::new(),
+	})
+	.into_perform_indexing();
+	add_mutation_args(&mut sch, &classes);
+	sch
+}
+
+// `createX(data: XData!)`, `updateX(where: XWhere!, data: XData!)` and `deleteX(where: XWhere!)`,
+// with the input types the server generates for bulk mutations
+fn add_mutation_args(sch: &mut structure::StructureIndex, classes: &[String]) {
+	let inputs = classes
+		.iter()
+		.filter_map(|class| match sch.find_object(class) {
+			structure::StructureItem::Object(obj) => Some((class.clone(), schema::mutation_inputs(sch, obj)?)),
+			_ => None,
+		})
+		.collect::<Vec<(String, (structure::StructureInput, structure::StructureInput))>>();
+	for (class, (filters, data)) in inputs {
+		if let structure::StructureItemMut::Object(m) = sch.find_object_mut("Mutation") {
+			let (f, d) = (filters.name.as_str(), data.name.as_str());
+			for (prefix, args) in [
+				("create", vec![schema::argument("data", d, false, false)]),
+				("update", vec![schema::argument("where", f, false, false), schema::argument("data", d, false, false)]),
+				("delete", vec![schema::argument("where", f, false, false)]),
+			] {
+				if let Some(field) = m.find_field_mut(&format!("{}{}", prefix, class)) {
+					field.args = args;
+				}
+			}
+		}
+		sch.inputs.push(filters);
+		sch.inputs.push(data);
+	}
+}
+
+fn empty_type(name: &str) -> structure::StructureType {
+	structure::StructureType {
+		name: name.to_owned(),
+		description: String::new(),
+		fields: Vec::new(),
+		indexes: Vec::new(),
+		renamed_from: None,
+		is_hidden: false,
+		hashed_fields: HashMap::new(),
+	}
+}
+
+// Keys of all records in the order first seen, "id" first
+fn field_names(records: &[JSONValue]) -> Vec<String> {
+	let mut names = vec!["id".to_owned()];
+	for record in records {
+		if let JSONValue::Object(obj) = record {
+			for key in obj.keys() {
+				if !names.contains(key) {
+					names.push(key.clone());
+				}
+			}
+		}
+	}
+	names
+}
+
+fn infer_field(
+	class: &str,
+	name: &str,
+	values: &[&JSONValue],
+	ids: &HashMap<String, HashSet<String>>,
+) -> structure::StructureField {
+	let is_array = values.iter().any(|v| v.is_array());
+	// Lists and single values together fit no GraphQL type but JSON
+	if is_array && values.iter().any(|v| !v.is_null() && !v.is_array()) {
+		println!("Warning: {}.{} holds both lists and single values, typed as JSON", class, name);
+		let mut field = structure::StructureField::from(name.to_owned(), String::new(), "JSON".to_owned(), false, None);
+		field.return_type.is_nullable = values.iter().any(|v| v.is_null());
+		return field;
+	}
+	let items = values
+		.iter()
+		.flat_map(|v| match v {
+			JSONValue::Array(arr) => arr.iter().collect::<Vec<&JSONValue>>(),
+			v => vec![*v],
+		})
+		.filter(|v| !v.is_null())
+		.collect::<Vec<&JSONValue>>();
+	let reference = if name == "id" { None } else { infer_reference(name, &items, ids) };
+	let (kind, data_kind) = match reference {
+		Some(target) => (target, None),
+		None if name == "id" && items.iter().all(|v| v.is_string()) => ("ID".to_owned(), None),
+		None => infer_scalar(&items),
+	};
+	let mut field = structure::StructureField::from(name.to_owned(), String::new(), kind, is_array, None);
+	// e.g. `@type as i64;` for integers beyond 32 bits
+	if let Some(k) = data_kind {
+		field.description = format!("@type as {};", k);
+		field.data_type.kind = k.to_owned();
+	}
+	// Required when every record has it, unless there are none to tell
+	field.return_type.is_nullable = values.is_empty() || values.iter().any(|v| v.is_null());
+	field.return_type.is_item_required = is_array
+		&& values.iter().all(|v| match v {
+			JSONValue::Array(arr) => arr.iter().all(|x| !x.is_null()),
+			_ => true,
+		});
+	field
+}
+
+// Type whose ids hold all the values. Among several, the one named like the field,
+// e.g. Author for `author`, `authorId` or `authors`. Numbers must be named like one.
+fn infer_reference(
+	name: &str,
+	items: &[&JSONValue],
+	ids: &HashMap<String, HashSet<String>>,
+) -> Option<String> {
+	if items.is_empty() || !items.iter().all(|v| v.is_string() || v.is_i64() || v.is_u64()) {
+		return None;
+	}
+	let mut candidates = ids
+		.iter()
+		.filter(|(_, set)| items.iter().all(|v| set.contains(&v.to_string())))
+		.map(|(class, _)| class.clone())
+		.collect::<Vec<String>>();
+	candidates.sort();
+	let base = name
+		.trim_end_matches("Ids")
+		.trim_end_matches("Id")
+		.trim_end_matches("_ids")
+		.trim_end_matches("_id")
+		.to_lowercase();
+	let named = candidates.iter().find(|c| {
+		let c = c.to_lowercase();
+		base == c || base == plural(&c)
+	});
+	match named {
+		Some(c) => Some(c.clone()),
+		None if candidates.len() == 1 && items.iter().all(|v| v.is_string()) => Some(candidates[0].clone()),
+		None => None,
+	}
+}
+
+// GraphQL type of the values, and how they're stored when not as usual
+fn infer_scalar(items: &[&JSONValue]) -> (String, Option<&'static str>) {
+	if items.is_empty() {
+		return ("String".to_owned(), None);
+	}
+	if items.iter().all(|v| v.is_boolean()) {
+		return ("Boolean".to_owned(), None);
+	}
+	if items.iter().all(|v| v.is_i64() || v.is_u64()) {
+		let small = items.iter().all(|v| v.as_i64().is_some_and(|x| x as i32 as i64 == x));
+		return ("Int".to_owned(), if small { None } else { Some("i64") });
+	}
+	if items.iter().all(|v| v.is_number()) {
+		return ("Float".to_owned(), None);
+	}
+	if items.iter().all(|v| v.is_string()) {
+		let texts = items.iter().filter_map(|v| v.as_str()).collect::<Vec<&str>>();
+		// Hints keep e.g. dates from passing as DateTime
+		let fits = |name: &str, text: &str| {
+			let hint = match name {
+				"DateTime" => text.contains('T'),
+				"Date" => text.len() == 10,
+				"URL" => text.contains("://"),
+				_ => true,
+			};
+			hint && scalar::normalize(name, &json!(text)).is_ok()
+		};
+		for name in &["UUID", "DateTime", "Date", "Email", "URL"] {
+			if texts.iter().all(|t| fits(name, t)) {
+				return (name.to_string(), None);
+			}
+		}
+		return ("String".to_owned(), None);
+	}
+	("JSON".to_owned(), None)
+}
+
+// "post" to "posts", "category" to "categories"
+fn plural(word: &str) -> String {
+	let consonant_y = word.ends_with('y') && !word.ends_with("ay") && !word.ends_with("ey") && !word.ends_with("oy");
+	if consonant_y {
+		format!("{}ies", &word[..word.len() - 1])
+	} else if ["s", "x", "z", "ch", "sh"].iter().any(|s| word.ends_with(s)) {
+		format!("{}es", word)
+	} else {
+		format!("{}s", word)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::sdl;
+	use std::sync::{Arc, RwLock};
+
+	fn db() -> parsing::DatabaseIndex {
+		serde_json::from_value(json!({
+			"Author": [{"id": 1, "name": "Ann"}, {"id": 2, "name": "Bob"}],
+			"Post": [
+				{"id": 1, "title": "a", "authorId": 1, "tags": ["x"], "meta": [1, 2]},
+				{"id": 2, "title": "b", "authorId": 2, "tags": [], "meta": "none"}
+			],
+			"Category": [{"id": "c1"}, {"id": "c2"}]
+		}))
+		.unwrap()
+	}
+
+	fn ids(db: &parsing::DatabaseIndex) -> HashMap<String, HashSet<String>> {
+		db.iter()
+			.map(|(c, r)| (c.clone(), r.iter().map(|r| r["id"].to_string()).collect()))
+			.collect()
+	}
+
+	#[test]
+	fn scalars_are_inferred() {
+		let cases = vec![
+			(json!([]), "String", None),
+			(json!([true, false]), "Boolean", None),
+			(json!([1, -2]), "Int", None),
+			(json!([1, 5_000_000_000i64]), "Int", Some("i64")),
+			(json!([1, 2.5]), "Float", None),
+			(json!(["a", "b"]), "String", None),
+			(json!(["0b9e9c3c-0f1e-4c59-9d7e-3a3b9a4e5f10"]), "UUID", None),
+			(json!(["2024-01-02T03:04:05Z"]), "DateTime", None),
+			(json!(["2024-01-02"]), "Date", None),
+			(json!(["ann@example.com"]), "Email", None),
+			(json!(["https://example.com"]), "URL", None),
+			(json!(["2024-01-02", "soon"]), "String", None),
+			(json!([1, "a"]), "JSON", None),
+			(json!([{"a": 1}]), "JSON", None),
+		];
+		for (items, name, kind) in cases {
+			let items = items.as_array().unwrap().iter().collect::<Vec<&JSONValue>>();
+			assert_eq!(infer_scalar(&items), (name.to_owned(), kind), "{:?}", items);
+		}
+	}
+
+	#[test]
+	fn references_are_inferred() {
+		let db = db();
+		let ids = ids(&db);
+		let cases = vec![
+			("authorId", json!([1, 2]), Some("Author")),
+			("author", json!([1]), Some("Author")),
+			("authors", json!([2]), Some("Author")),
+			("author_id", json!([1]), Some("Author")),
+			// Numbers named like no type are just numbers, strings with one match are references
+			("count", json!([1, 2]), None),
+			("main", json!(["c1"]), Some("Category")),
+			("categoryIds", json!(["c1", "c2"]), Some("Category")),
+			("authorId", json!([3]), None),
+			("authorId", json!([]), None),
+			("authorId", json!([1.5]), None),
+		];
+		for (name, items, expected) in cases {
+			let items = items.as_array().unwrap().iter().collect::<Vec<&JSONValue>>();
+			assert_eq!(infer_reference(name, &items, &ids).as_deref(), expected, "{} {:?}", name, items);
+		}
+	}
+
+	#[test]
+	fn words_are_pluralized() {
+		let cases = vec![
+			("post", "posts"),
+			("category", "categories"),
+			("day", "days"),
+			("key", "keys"),
+			("toy", "toys"),
+			("address", "addresses"),
+			("box", "boxes"),
+			("quiz", "quizes"),
+			("match", "matches"),
+			("dish", "dishes"),
+		];
+		for (word, expected) in cases {
+			assert_eq!(plural(word), expected);
+		}
+	}
+
+	#[test]
+	fn inferred_schema_is_served() {
+		let db = db();
+		let sch = infer_schema("test", &db);
+		let post = match sch.find_object("Post") {
+			structure::StructureItem::Object(o) => o,
+			_ => panic!("Post is inferred"),
+		};
+		let meta = post.find_field("meta").unwrap();
+		assert_eq!((meta.return_type.name.as_str(), meta.return_type.is_array), ("JSON", false));
+		assert_eq!(post.find_field("authorId").unwrap().return_type.name, "Author");
+		assert!(post.find_field("tags").unwrap().return_type.is_array);
+
+		// Read back as written, generated mutations work with their arguments
+		let text = sdl::print_schema(&sch);
+		let read = schema::traverse_schema("test", &graphql_parser::parse_schema(&text).unwrap());
+		let intros = schema::traverse_schema("instropection", &graphql_parser::schema::Document { definitions: vec![] });
+		let mut p = parsing::QueryParser::new(db, read, intros, Arc::new(RwLock::new(HashMap::new())));
+		let mutation = match p.schema.find_object("Mutation") {
+			structure::StructureItem::Object(o) => o,
+			_ => panic!("Mutation is inferred"),
+		};
+		let args = |name: &str| {
+			let field = mutation.find_field(name).unwrap();
+			field.args.iter().map(|a| format!("{}: {}", a.name, a.return_type.name)).collect::<Vec<String>>()
+		};
+		assert_eq!(args("createPost"), vec!["data: PostData"]);
+		assert_eq!(args("updatePost"), vec!["where: PostWhere", "data: PostData"]);
+		assert_eq!(args("deletePost"), vec!["where: PostWhere"]);
+		let text = "mutation { createPost(data: {title: \"c\", authorId: 1, tags: [], meta: {}}) { id } \
+			updatePost(where: {id: 1}, data: {title: \"d\"}) { title } deleteCategory(where: {id: \"c2\"}) { id } }";
+		let (values, errors) = p.traverse_mutation(&graphql_parser::parse_query(text).unwrap(), &serde_json::Map::new());
+		assert!(errors.is_empty(), "{:?}", errors);
+		assert_eq!(values["createPost"], json!({"id": 3}));
+		assert_eq!(values["updatePost"], json!({"title": "d"}));
+		assert_eq!(values["deleteCategory"], json!({"id": "c2"}));
+	}
+}
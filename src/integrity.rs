@@ -0,0 +1,274 @@
+use super::{indexing, parsing, structure};
+use serde_json::Value as JSONValue;
+
+// Fields of `obj` that store ids of another object
+pub fn reference_fields<'a>(
+	obj: &'a structure::StructureType,
+	schema: &structure::StructureIndex,
+) -> Vec<&'a structure::StructureField> {
+	if obj.name.starts_with("__") {
+		return Vec::new();
+	}
+	obj.fields
+		.iter()
+		.filter(|f| match &f.data_type.resolver {
+			Some(r) => r.kind == "DATA",
+			None => true,
+		})
+		.filter(|f| match schema.find_object(&f.return_type.name) {
+			structure::StructureItem::Object(o) => o.find_field("id").is_some(),
+			_ => false,
+		})
+		.collect()
+}
+
+// Every id stored in a reference field value (single id or array of them)
+pub fn referenced_ids(value: &JSONValue) -> Vec<&JSONValue> {
+	match value {
+		JSONValue::Array(arr) => arr.iter().filter(|x| !x.is_null()).collect(),
+		JSONValue::Null => Vec::new(),
+		v => vec![v],
+	}
+}
+
+fn exists(hashes: &indexing::DatabaseHashmaps, class: &str, id: &JSONValue) -> bool {
+	match hashes.get(class) {
+		Some(Some(h)) => match h.get("id") {
+			Some(hash) => hash.find(&[id]).is_some(),
+			None => false,
+		},
+		_ => false,
+	}
+}
+
+// Print references which points to nothing
+pub fn report_references(
+	db: &parsing::DatabaseIndex,
+	hashes: &indexing::DatabaseHashmaps,
+	schema: &structure::StructureIndex,
+) {
+	for obj in &schema.objects {
+		let fields = reference_fields(obj, schema);
+		if fields.is_empty() {
+			continue;
+		}
+		for (position, record) in db.get(&obj.name).into_iter().flatten().enumerate() {
+			for field in &fields {
+				for id in referenced_ids(&record[&field.name]) {
+					if !exists(hashes, &field.return_type.name, id) {
+						println!(
+							"Warning: {} at {} references missing {} {} in {}",
+							obj.name, position, field.return_type.name, id, field.name
+						);
+					}
+				}
+			}
+		}
+	}
+}
+
+// Error when a record about to be written references ids that don't exist
+pub fn check_references(
+	parser: &parsing::QueryParser,
+	class: &str,
+	record: &JSONValue,
+) -> Result<(), String> {
+	let obj = match parser.schema.find_object(class) {
+		structure::StructureItem::Object(o) => o,
+		_ => return Ok(()),
+	};
+	for field in reference_fields(obj, &parser.schema) {
+		for id in referenced_ids(&record[&field.name]) {
+			if !exists(&parser.hashmaps, &field.return_type.name, id) {
+				return Err(format!(
+					"{}.{} references missing {} {}",
+					class, field.name, field.return_type.name, id
+				));
+			}
+		}
+	}
+	Ok(())
+}
+
+// Error when a record of any type references a missing record of `class`,
+// e.g. after the records of `class` are replaced
+pub fn check_referenced(parser: &parsing::QueryParser, class: &str) -> Result<(), String> {
+	for obj in &parser.schema.objects {
+		for field in reference_fields(obj, &parser.schema) {
+			if field.return_type.name != class {
+				continue;
+			}
+			for record in parser.database.get(&obj.name).into_iter().flatten() {
+				for id in referenced_ids(&record[&field.name]) {
+					if !exists(&parser.hashmaps, class, id) {
+						return Err(format!(
+							"{} {} references missing {} {} in {}",
+							obj.name, record["id"], class, id, field.name
+						));
+					}
+				}
+			}
+		}
+	}
+	Ok(())
+}
+
+#[derive(Default)]
+struct DeletePlan {
+	// (type, id) to remove
+	deletes: Vec<(String, JSONValue)>,
+	// (type, record) to remove, for records without id such as join records
+	links: Vec<(String, JSONValue)>,
+	// (type, id, field) to unset the deleted id from
+	unsets: Vec<(String, JSONValue, String, JSONValue)>,
+	// (type, id, message) blocking the delete, unless that record is deleted too
+	restricts: Vec<(String, JSONValue, String)>,
+}
+
+impl DeletePlan {
+	fn is_deleted(&self, class: &str, id: &JSONValue) -> bool {
+		self.deletes.iter().any(|(c, i)| c == class && i == id)
+	}
+}
+
+fn plan_delete(
+	parser: &parsing::QueryParser,
+	class: &str,
+	id: &JSONValue,
+	plan: &mut DeletePlan,
+) {
+	if plan.is_deleted(class, id) {
+		return;
+	}
+	plan.deletes.push((class.to_owned(), id.clone()));
+	for (obj, field, position) in referencing(parser, class, id) {
+		let record = &parser.database[&obj.name][position];
+		match field.on_delete.as_deref() {
+			Some("CASCADE") if record["id"].is_null() => plan.links.push((obj.name.clone(), record.clone())),
+			Some("CASCADE") => plan_delete(parser, &obj.name, &record["id"], plan),
+			Some("SET_NULL") => plan.unsets.push((
+				obj.name.clone(),
+				record["id"].clone(),
+				field.name.clone(),
+				id.clone(),
+			)),
+			_ => plan.restricts.push((
+				obj.name.clone(),
+				record["id"].clone(),
+				format!(
+					"{} {} is still referenced by {} {} in {}",
+					class, id, obj.name, record["id"], field.name
+				),
+			)),
+		}
+	}
+}
+
+// Records referencing `id` of `class`: their type, the field referencing it and their position
+fn referencing<'a>(
+	parser: &'a parsing::QueryParser,
+	class: &str,
+	id: &JSONValue,
+) -> Vec<(&'a structure::StructureType, &'a structure::StructureField, usize)> {
+	let mut found = Vec::new();
+	for obj in &parser.schema.objects {
+		for field in reference_fields(obj, &parser.schema) {
+			if field.return_type.name != class {
+				continue;
+			}
+			// Every reference field has a reverse index, see indexing::backref_fields
+			let mut positions = match parser.hashmaps.get(&obj.name) {
+				Some(Some(h)) => match h.get(&indexing::reverse_key(&field.name)) {
+					Some(hash) => hash.find(&[id]).cloned().unwrap_or_default(),
+					None => Vec::new(),
+				},
+				_ => Vec::new(),
+			};
+			// A record listing the id twice is indexed twice
+			positions.sort();
+			positions.dedup();
+			found.extend(positions.into_iter().map(|position| (obj, field, position)));
+		}
+	}
+	found
+}
+
+// Error when an update changes the id of a record that others still reference,
+// as their references would point to nothing
+pub fn check_id_change(
+	parser: &parsing::QueryParser,
+	class: &str,
+	old: &JSONValue,
+	new: &JSONValue,
+) -> Result<(), String> {
+	if old.is_null() || old == new {
+		return Ok(());
+	}
+	match referencing(parser, class, old).first() {
+		Some((obj, field, position)) => Err(format!(
+			"{} {} can't change its id to {}, as {} {} references it in {}",
+			class, old, new, obj.name, parser.database[&obj.name][*position]["id"], field.name
+		)),
+		None => Ok(()),
+	}
+}
+
+fn find_by_id(parser: &parsing::QueryParser, class: &str, id: &JSONValue) -> Option<usize> {
+	parser
+		.find_positions(class, &[("id".to_owned(), id.clone())])
+		.ok()?
+		.first()
+		.cloned()
+}
+
+// Delete a record and apply `@relation(onDelete:)` of every field referencing it.
+// Nothing is changed when a RESTRICT reference is found.
+pub fn delete_record(
+	parser: &mut parsing::QueryParser,
+	class: &str,
+	position: usize,
+) -> Result<JSONValue, String> {
+	let id = parser.database[class][position]["id"].clone();
+	if id.is_null() {
+		return Ok(parser.remove_record(class, position));
+	}
+	let mut plan = DeletePlan::default();
+	plan_delete(parser, class, &id, &mut plan);
+	if let Some((_, _, message)) = plan
+		.restricts
+		.iter()
+		.find(|(c, i, _)| !plan.is_deleted(c, i))
+	{
+		return Err(message.clone());
+	}
+	for (c, i, field, target) in &plan.unsets {
+		if plan.is_deleted(c, i) {
+			continue;
+		}
+		if let Some(p) = find_by_id(parser, c, i) {
+			let mut record = parser.database[c][p].clone();
+			record[field] = match &record[field] {
+				JSONValue::Array(arr) => {
+					JSONValue::Array(arr.iter().filter(|x| *x != target).cloned().collect())
+				}
+				_ => JSONValue::Null,
+			};
+			parser.update_record(c, p, record);
+		}
+	}
+	for (c, link) in &plan.links {
+		if let Some(p) = parser.database[c].iter().position(|r| r == link) {
+			parser.remove_record(c, p);
+		}
+	}
+	let mut removed = JSONValue::Null;
+	for (c, i) in &plan.deletes {
+		if let Some(p) = find_by_id(parser, c, i) {
+			let record = parser.remove_record(c, p);
+			if c == class && i == &id {
+				removed = record;
+			}
+		}
+	}
+	Ok(removed)
+}
@@ -3,11 +3,22 @@ extern crate serde_json;
 extern crate hyper;
 extern crate serde;
 
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod binary;
 pub mod canonical;
+pub mod cli;
+pub mod exchange;
+pub mod expression;
 pub mod indexing;
+pub mod inference;
+pub mod integrity;
+pub mod migration;
 pub mod parsing;
 pub mod resolver;
+pub mod scalar;
 pub mod schema;
+pub mod sdl;
 pub mod structure;
 pub mod utility;
 
@@ -39,6 +50,8 @@ pub struct ConfigConfig {
 #[derive(Deserialize)]
 pub struct ConfigDatabase {
     pub name: String,
+    // "json" (data.json), "binary" (data.bin) or "split" (data/<Type>.json), converted on start
+    pub format: Option<String>,
 }
 
 #[derive(Clone)]
@@ -61,6 +74,13 @@ impl App {
         for db in &config.database {
             let dbss = dbs.clone();
             let dddd = &mut *dbs.write().unwrap();
+            if let Some(format) = &db.format {
+                match utility::convert_data(&db.name, format) {
+                    Ok(true) => println!("Converted {} to {}", db.name, format),
+                    Ok(false) => {}
+                    Err(e) => panic!("{}", e),
+                }
+            }
             println!("Loading {}", db.name);
             dddd.insert(db.name.clone(), utility::load_db(&db.name[..], dbss));
         }
@@ -111,19 +131,22 @@ impl App {
                 .from_err()
                 .and_then(move |entire_body| {
                     // TODO: Replace all unwraps with proper error handling
-                    let parser2 = &*parser.read().unwrap_or_else(|e| e.into_inner());
-                    let parser3 = match parser2.get(&dbb[..]) {
-                        Some(v) => v,
-                        _ => {
-                            return Ok(Response::builder()
-                                .status(StatusCode::OK)
-                                .body(Body::from(
-                                    json!({"data":null, "error":"Database entry not found"})
-                                        .to_string(),
-                                ))
-                                .unwrap())
-                        }
+                    let not_found = || {
+                        Ok(Response::builder()
+                            .status(StatusCode::OK)
+                            .body(Body::from(
+                                json!({"data":null, "error":"Database entry not found"})
+                                    .to_string(),
+                            ))
+                            .unwrap())
                     };
+                    if !parser
+                        .read()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .contains_key(&dbb[..])
+                    {
+                        return not_found();
+                    }
                     let str = String::from_utf8(entire_body.to_vec())?;
                     let data: serde_json::Value = serde_json::from_str(&str)?;
                     let query = match &data["query"] {
@@ -138,8 +161,51 @@ impl App {
 
                     match parse_query(&query) {
                         Ok(v) => {
-                            let values = parser3.traverse_query(&v, &vars);
-                            let data = json!({ "data": values });
+                            let (values, errors) = if parsing::is_mutation(&v) {
+                                // Writes are exclusive, then saved
+                                let parser2 = &mut *parser.write().unwrap_or_else(|e| e.into_inner());
+                                let parser3 = match parser2.get_mut(&dbb[..]) {
+                                    Some(v) => v,
+                                    _ => return not_found(),
+                                };
+                                let results = match parser3.load_for(&v) {
+                                    Ok(()) => parser3.traverse_mutation(&v, vars),
+                                    Err(e) => (Value::Null, vec![json!({ "message": e })]),
+                                };
+                                if !parser3.is_canonical {
+                                    utility::save_db(parser3);
+                                }
+                                results
+                            } else {
+                                // Collections are read when first queried, which takes the write lock once
+                                let is_loaded = parser
+                                    .read()
+                                    .unwrap_or_else(|e| e.into_inner())
+                                    .get(&dbb[..])
+                                    .is_none_or(|p| p.unloaded_collections(&v).is_empty());
+                                let loaded = if is_loaded {
+                                    Ok(())
+                                } else {
+                                    match parser.write().unwrap_or_else(|e| e.into_inner()).get_mut(&dbb[..]) {
+                                        Some(p) => p.load_for(&v),
+                                        _ => return not_found(),
+                                    }
+                                };
+                                let parser2 = &*parser.read().unwrap_or_else(|e| e.into_inner());
+                                let parser3 = match parser2.get(&dbb[..]) {
+                                    Some(v) => v,
+                                    _ => return not_found(),
+                                };
+                                match loaded {
+                                    Ok(()) => parser3.traverse_query(&v, vars),
+                                    Err(e) => (Value::Null, vec![json!({ "message": e })]),
+                                }
+                            };
+                            let data = if errors.is_empty() {
+                                json!({ "data": values })
+                            } else {
+                                json!({ "data": values, "errors": errors })
+                            };
                             Ok(Response::builder()
                                 .status(StatusCode::OK)
                                 .header(header::CONTENT_TYPE, "application/json")
@@ -158,6 +224,108 @@ impl App {
         )
     }
 
+    // The schema a database is served with, as SDL
+    fn schema_sdl(&self, req: Request<Body>) -> ResponseFuture {
+        let dbb = query_param(&req, "db");
+        let parser = &*self.parser.read().unwrap_or_else(|e| e.into_inner());
+        let (status, body) = match parser.get(&dbb[..]) {
+            Some(v) => (StatusCode::OK, sdl::print_schema(&v.schema)),
+            _ => (StatusCode::NOT_FOUND, "Database entry not found\n".to_owned()),
+        };
+        Box::new(future::ok(
+            Response::builder()
+                .status(status)
+                .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+                .body(Body::from(body))
+                .unwrap(),
+        ))
+    }
+
+    // Records of a type as CSV or NDJSON, e.g. `/export?db=blog&type=Post&format=csv`
+    fn export(&self, req: Request<Body>) -> ResponseFuture {
+        let (dbb, class, format) = (
+            query_param(&req, "db"),
+            query_param(&req, "type"),
+            query_param(&req, "format"),
+        );
+        let is_loaded = self
+            .parser
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&dbb[..])
+            .is_none_or(|p| !p.unloaded.contains(&class));
+        let loaded = if is_loaded {
+            Ok(())
+        } else {
+            match self.parser.write().unwrap_or_else(|e| e.into_inner()).get_mut(&dbb[..]) {
+                Some(p) => p.load_collections(std::slice::from_ref(&class)),
+                _ => Ok(()),
+            }
+        };
+        let parser = &*self.parser.read().unwrap_or_else(|e| e.into_inner());
+        let encoded = match (loaded, parser.get(&dbb[..])) {
+            (Err(e), _) => Err(e),
+            (_, Some(p)) => match p.database.get(&class) {
+                Some(records) if !class.starts_with("__") => {
+                    exchange::encode(&format, &p.schema, &class, records)
+                }
+                _ => Err(format!("{} is not a type of {}", class, dbb)),
+            },
+            _ => Err("Database entry not found".to_owned()),
+        };
+        let (status, content_type, body) = match encoded {
+            Ok(text) if format == "csv" => (StatusCode::OK, "text/csv; charset=utf-8", text),
+            Ok(text) => (StatusCode::OK, "application/x-ndjson", text),
+            Err(e) => (StatusCode::BAD_REQUEST, "application/json", json!({ "error": e }).to_string()),
+        };
+        Box::new(future::ok(
+            Response::builder()
+                .status(status)
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from(body))
+                .unwrap(),
+        ))
+    }
+
+    // Records of a type from a CSV or NDJSON body, added to the present ones
+    // unless `replace=true`, e.g. `/import?db=blog&type=Post&format=ndjson`
+    fn import(&self, req: Request<Body>) -> ResponseFuture {
+        let (dbb, class, format) = (
+            query_param(&req, "db"),
+            query_param(&req, "type"),
+            query_param(&req, "format"),
+        );
+        let replace = query_param(&req, "replace") == "true";
+        let parser = self.parser.clone();
+        Box::new(req.into_body().concat2().from_err().and_then(move |entire_body| {
+            let text = String::from_utf8(entire_body.to_vec())?;
+            let parser2 = &mut *parser.write().unwrap_or_else(|e| e.into_inner());
+            let imported = match parser2.get_mut(&dbb[..]) {
+                Some(p) => {
+                    // References are checked both ways, so every collection is read
+                    let result = p
+                        .load_all()
+                        .and_then(|_| exchange::decode(&format, &p.schema, &class, &text))
+                        .and_then(|records| p.import_records(&class, records, replace));
+                    if result.is_ok() && !p.is_canonical {
+                        utility::save_db(p);
+                    }
+                    result
+                }
+                _ => Err("Database entry not found".to_owned()),
+            };
+            let (status, body) = match imported {
+                Ok(count) => (StatusCode::OK, json!({ "imported": count })),
+                Err(e) => (StatusCode::BAD_REQUEST, json!({ "error": e })),
+            };
+            Ok(Response::builder()
+                .status(status)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap())
+        }))
+    }
+
     fn serve_static(&self, path: &str, content_type: &str) -> ResponseFuture {
         Box::new(future::ok(
             Response::builder()
@@ -171,6 +339,9 @@ impl App {
     pub fn app_worker(&self, req: Request<Body>) -> ResponseFuture {
         match (req.method(), req.uri().path()) {
             (&Method::POST, "/graphql") => self.graphql_api(req),
+            (&Method::GET, "/schema.gql") => self.schema_sdl(req),
+            (&Method::GET, "/export") => self.export(req),
+            (&Method::POST, "/import") => self.import(req),
             (&Method::GET, "/graphiql") => self.serve_static("graphiql.html", "text/html"),
             (&Method::GET, "/") => self.serve_static("index.html", "text/html"),
             _ => Box::new(future::ok(
@@ -184,7 +355,24 @@ impl App {
     }
 }
 
+// Value of a parameter in the query string of a request, or empty
+fn query_param(req: &Request<Body>, name: &str) -> String {
+    let urlpa = url::Url::parse("https://example.com")
+        .unwrap()
+        .join(&req.uri().to_string())
+        .unwrap();
+    let value = urlpa.query_pairs().find(|(k, _)| k == name);
+    match value {
+        Some(v) => v.1.to_string(),
+        _ => "".to_owned(),
+    }
+}
+
 fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args));
+    }
     let addr = ([127, 0, 0, 1], 3000).into();
     let app = App::new();
     let server = Server::bind(&addr)
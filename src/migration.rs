@@ -0,0 +1,591 @@
+use super::{parsing, scalar, structure, utility};
+use serde_json::Value as JSONValue;
+use std::collections::HashMap;
+
+// A difference between the previous and the edited schema. Breaking changes lose or
+// can't convert stored data, so those are only applied when asked to.
+pub struct SchemaChange {
+	pub breaking: bool,
+	pub message: String,
+}
+
+pub struct Migration {
+	pub changes: Vec<SchemaChange>,
+	// Stored data converted to the edited schema
+	pub database: parsing::DatabaseIndex,
+	// Collections no longer stored under their name, as their type is renamed or removed
+	pub removed: Vec<String>,
+}
+
+impl Migration {
+	pub fn is_breaking(&self) -> bool {
+		self.changes.iter().any(|c| c.breaking)
+	}
+
+	pub fn report(&self) -> String {
+		if self.changes.is_empty() {
+			return "No changes\n".to_owned();
+		}
+		self.changes
+			.iter()
+			.map(|c| {
+				format!(
+					"{} {}\n",
+					if c.breaking {
+						"[breaking]"
+					} else {
+						"[safe]    "
+					},
+					c.message
+				)
+			})
+			.collect()
+	}
+
+	fn safe(&mut self, message: String) {
+		self.changes.push(SchemaChange {
+			breaking: false,
+			message,
+		});
+	}
+
+	fn breaking(&mut self, message: String) {
+		self.changes.push(SchemaChange {
+			breaking: true,
+			message,
+		});
+	}
+}
+
+const ROOT_TYPES: [&str; 3] = ["Query", "Mutation", "Subscription"];
+
+// Compare schemas and convert `database` from the `old` one to the `new` one
+pub fn plan(
+	old: &structure::StructureIndex,
+	new: &structure::StructureIndex,
+	database: &parsing::DatabaseIndex,
+) -> Migration {
+	let mut migration = Migration {
+		changes: Vec::new(),
+		database: database.clone(),
+		removed: Vec::new(),
+	};
+	for obj in &new.objects {
+		let previous = match find_previous(old, &obj.name, &obj.renamed_from) {
+			Some(v) => v,
+			None => {
+				migration.safe(format!("{} is added", obj.name));
+				continue;
+			}
+		};
+		if previous.name != obj.name {
+			migration.safe(format!("{} is renamed from {}", obj.name, previous.name));
+			if let Some(records) = migration.database.remove(&previous.name) {
+				migration.database.insert(obj.name.clone(), records);
+			}
+			migration.removed.push(previous.name.clone());
+		}
+		// Root types have no stored data, their fields are just the API
+		if ROOT_TYPES.contains(&obj.name.as_str()) {
+			diff_root_fields(&mut migration, previous, obj);
+		} else {
+			migrate_fields(&mut migration, old, new, previous, obj);
+		}
+	}
+	for obj in &old.objects {
+		let kept = new
+			.objects
+			.iter()
+			.any(|o| o.name == obj.name || o.renamed_from.as_ref() == Some(&obj.name));
+		if kept {
+			continue;
+		}
+		migration.removed.push(obj.name.clone());
+		match migration.database.remove(&obj.name) {
+			Some(records) if !records.is_empty() => migration.breaking(format!(
+				"{} is removed with its {} records",
+				obj.name,
+				records.len()
+			)),
+			_ => migration.safe(format!("{} is removed", obj.name)),
+		}
+	}
+	for e in &new.enums {
+		if let structure::StructureItem::Enum(previous) = old.find_object(&e.name) {
+			migrate_enum(&mut migration, new, previous, e);
+		}
+	}
+	migration
+}
+
+fn find_previous<'a>(
+	old: &'a structure::StructureIndex,
+	name: &str,
+	renamed_from: &Option<String>,
+) -> Option<&'a structure::StructureType> {
+	let by_name = old.objects.iter().find(|o| o.name == name);
+	by_name.or_else(|| {
+		let from = renamed_from.as_ref()?;
+		old.objects.iter().find(|o| &o.name == from)
+	})
+}
+
+fn diff_root_fields(
+	migration: &mut Migration,
+	previous: &structure::StructureType,
+	obj: &structure::StructureType,
+) {
+	for field in &obj.fields {
+		if previous.find_field(&field.name).is_none() {
+			migration.safe(format!("{}.{} is added", obj.name, field.name));
+		}
+	}
+	for field in &previous.fields {
+		if obj.find_field(&field.name).is_none() {
+			migration.safe(format!("{}.{} is removed", obj.name, field.name));
+		}
+	}
+}
+
+// Fields that are stored in records, rather than resolved
+fn is_stored(field: &structure::StructureField) -> bool {
+	match &field.data_type.resolver {
+		Some(r) => r.kind == "DATA",
+		None => true,
+	}
+}
+
+fn migrate_fields(
+	migration: &mut Migration,
+	old: &structure::StructureIndex,
+	new: &structure::StructureIndex,
+	previous: &structure::StructureType,
+	obj: &structure::StructureType,
+) {
+	let mut records = migration.database.remove(&obj.name).unwrap_or_default();
+	for field in obj.fields.iter().filter(|f| is_stored(f)) {
+		let from = previous.fields.iter().filter(|f| is_stored(f)).find(|f| {
+			f.name == field.name
+				|| (field.renamed_from.as_ref() == Some(&f.name)
+					&& obj.find_field(&f.name).is_none())
+		});
+		let from = match from {
+			Some(v) => v,
+			None => {
+				add_field(migration, new, obj, field, &mut records);
+				continue;
+			}
+		};
+		if from.name != field.name {
+			migration.safe(format!(
+				"{}.{} is renamed from {}",
+				obj.name, field.name, from.name
+			));
+			for record in records.iter_mut() {
+				if let Some(map) = record.as_object_mut() {
+					if let Some(value) = map.remove(&from.name) {
+						map.insert(field.name.clone(), value);
+					}
+				}
+			}
+		}
+		convert_field(migration, old, new, obj, from, field, &mut records);
+	}
+	for field in previous.fields.iter().filter(|f| is_stored(f)) {
+		let kept = obj
+			.fields
+			.iter()
+			.filter(|f| is_stored(f))
+			.any(|f| f.name == field.name || f.renamed_from.as_ref() == Some(&field.name));
+		if kept {
+			continue;
+		}
+		let mut dropped = 0;
+		for record in records.iter_mut() {
+			if let Some(map) = record.as_object_mut() {
+				if let Some(value) = map.remove(&field.name) {
+					if !value.is_null() {
+						dropped += 1;
+					}
+				}
+			}
+		}
+		match dropped {
+			0 => migration.safe(format!("{}.{} is removed", obj.name, field.name)),
+			n => migration.breaking(format!(
+				"{}.{} is removed with its values in {} records",
+				obj.name, field.name, n
+			)),
+		}
+	}
+	migration.database.insert(obj.name.clone(), records);
+}
+
+// New fields get their default. Required fields without one are breaking.
+fn add_field(
+	migration: &mut Migration,
+	new: &structure::StructureIndex,
+	obj: &structure::StructureType,
+	field: &structure::StructureField,
+	records: &mut [JSONValue],
+) {
+	let mut sequence = 0;
+	let mut missing = 0;
+	for record in records.iter_mut() {
+		if !record[&field.name].is_null() || !record.is_object() {
+			continue;
+		}
+		let value = match &field.data_type.default {
+			Some(d) => match d.kind.as_ref() {
+				"INCREMENT" | "SEQUENCE" => {
+					sequence += 1;
+					json!(sequence)
+				}
+				"UUID" => json!(utility::uuid_v4()),
+				"ULID" => json!(utility::ulid()),
+				"NOW" | "UPDATED_AT" => parsing::now_value(field),
+				_ => match new.find_object(&field.return_type.name) {
+					structure::StructureItem::Enum(e) => {
+						e.encode(&d.reference).unwrap_or(JSONValue::Null)
+					}
+					_ => d.reference.clone(),
+				},
+			},
+			None => JSONValue::Null,
+		};
+		if value.is_null() {
+			missing += 1;
+		} else {
+			record[&field.name] = value;
+		}
+	}
+	if missing > 0 && !field.return_type.is_nullable {
+		migration.breaking(format!(
+			"{}.{} is added as required, without a default for {} records",
+			obj.name, field.name, missing
+		));
+	} else if field.data_type.default.is_some() && !records.is_empty() {
+		migration.safe(format!(
+			"{}.{} is added, with defaults in {} records",
+			obj.name,
+			field.name,
+			records.len() - missing
+		));
+	} else {
+		migration.safe(format!("{}.{} is added", obj.name, field.name));
+	}
+}
+
+// Convert values when the field type changes. Values that can't be converted are removed.
+fn convert_field(
+	migration: &mut Migration,
+	old: &structure::StructureIndex,
+	new: &structure::StructureIndex,
+	obj: &structure::StructureType,
+	from: &structure::StructureField,
+	field: &structure::StructureField,
+	records: &mut [JSONValue],
+) {
+	let (was, is) = (&from.return_type, &field.return_type);
+	let retyped = was.name != is.name || was.is_array != is.is_array;
+	let mut failed = 0;
+	let mut nulls = 0;
+	for record in records.iter_mut() {
+		let value = &record[&field.name];
+		if value.is_null() {
+			nulls += 1;
+			continue;
+		}
+		if !retyped {
+			continue;
+		}
+		// Decode enums by the old schema, encode by the new one
+		let value = match old.find_object(&was.name) {
+			structure::StructureItem::Enum(e) => match value {
+				JSONValue::Array(arr) => json!(arr
+					.iter()
+					.map(|x| e.decode(x).map(|n| json!(n)).unwrap_or(JSONValue::Null))
+					.collect::<Vec<JSONValue>>()),
+				v => e.decode(v).map(|n| json!(n)).unwrap_or(JSONValue::Null),
+			},
+			_ => value.clone(),
+		};
+		let items = match (value, was.is_array, is.is_array) {
+			(JSONValue::Array(arr), true, false) => {
+				if arr.len() > 1 {
+					failed += 1;
+				}
+				arr.into_iter().take(1).collect::<Vec<JSONValue>>()
+			}
+			(JSONValue::Array(arr), _, _) => arr,
+			(v, _, _) => vec![v],
+		};
+		let converted = items
+			.iter()
+			.map(|x| convert_value(new, &is.name, x))
+			.collect::<Option<Vec<JSONValue>>>();
+		record[&field.name] = match converted {
+			Some(values) if is.is_array => json!(values),
+			Some(values) => values.into_iter().next().unwrap_or(JSONValue::Null),
+			None => {
+				failed += 1;
+				JSONValue::Null
+			}
+		};
+	}
+	if retyped {
+		let describe = |t: &structure::StructureReturnType| {
+			if t.is_array {
+				format!("[{}]", t.name)
+			} else {
+				t.name.clone()
+			}
+		};
+		let message = format!(
+			"{}.{} changes from {} to {}",
+			obj.name,
+			field.name,
+			describe(was),
+			describe(is)
+		);
+		match failed {
+			0 => migration.safe(message),
+			n => migration.breaking(format!(
+				"{}, {} values can't be converted and are removed",
+				message, n
+			)),
+		}
+	}
+	if was.is_nullable && !is.is_nullable && nulls > 0 {
+		migration.breaking(format!(
+			"{}.{} becomes required, but is missing in {} records",
+			obj.name, field.name, nulls
+		));
+	}
+}
+
+// A value converted to type `name`, or None when it can't be
+fn convert_value(
+	new: &structure::StructureIndex,
+	name: &str,
+	value: &JSONValue,
+) -> Option<JSONValue> {
+	if value.is_null() {
+		return Some(JSONValue::Null);
+	}
+	match name {
+		"Int" => match value {
+			JSONValue::Number(n) => n
+				.as_i64()
+				.or_else(|| n.as_f64().filter(|f| f.fract() == 0.0).map(|f| f as i64))
+				.map(|x| json!(x)),
+			JSONValue::String(s) => s.trim().parse::<i64>().ok().map(|x| json!(x)),
+			JSONValue::Bool(b) => Some(json!(*b as i64)),
+			_ => None,
+		},
+		"Float" => match value {
+			JSONValue::Number(n) => n.as_f64().map(|x| json!(x)),
+			JSONValue::String(s) => s.trim().parse::<f64>().ok().map(|x| json!(x)),
+			_ => None,
+		},
+		"String" | "ID" => match value {
+			JSONValue::String(_) => Some(value.clone()),
+			JSONValue::Number(_) | JSONValue::Bool(_) => Some(json!(value.to_string())),
+			_ => None,
+		},
+		"Boolean" => match value {
+			JSONValue::Bool(_) => Some(value.clone()),
+			JSONValue::String(s) if s == "true" || s == "false" => Some(json!(s == "true")),
+			JSONValue::Number(n) => n
+				.as_i64()
+				.filter(|x| *x == 0 || *x == 1)
+				.map(|x| json!(x == 1)),
+			_ => None,
+		},
+		n => match new.find_object(n) {
+			structure::StructureItem::Enum(e) => e.encode(value),
+			structure::StructureItem::Scalar(_) if scalar::is_builtin(n) => {
+				scalar::normalize(n, value).ok()
+			}
+			_ => Some(value.clone()),
+		},
+	}
+}
+
+// Values kept by name, but with another stored value, are translated
+fn migrate_enum(
+	migration: &mut Migration,
+	new: &structure::StructureIndex,
+	previous: &structure::StructureEnum,
+	e: &structure::StructureEnum,
+) {
+	let mut translate = Vec::new();
+	for (name, stored) in &previous.values {
+		match e.values.get(name) {
+			Some(v) if v != stored => {
+				migration.safe(format!(
+					"{}.{} is stored as {} instead of {}",
+					e.name, name, v, stored
+				));
+				translate.push((stored.clone(), v.clone()));
+			}
+			Some(_) => {}
+			None => translate.push((stored.clone(), JSONValue::Null)),
+		}
+	}
+	for name in e
+		.values
+		.keys()
+		.filter(|n| !previous.values.contains_key(*n))
+	{
+		migration.safe(format!("{}.{} is added", e.name, name));
+	}
+	if translate.is_empty() {
+		return;
+	}
+	let mut removed = HashMap::new();
+	for obj in &new.objects {
+		let fields = obj
+			.fields
+			.iter()
+			.filter(|f| f.return_type.name == e.name && is_stored(f))
+			.map(|f| f.name.clone())
+			.collect::<Vec<String>>();
+		for record in migration.database.get_mut(&obj.name).into_iter().flatten() {
+			for field in &fields {
+				let value = record[field].clone();
+				let translated = match &value {
+					JSONValue::Array(arr) => json!(arr
+						.iter()
+						.map(|x| translate_value(&translate, x, &mut removed))
+						.collect::<Vec<JSONValue>>()),
+					v => translate_value(&translate, v, &mut removed),
+				};
+				if translated != value {
+					record[field] = translated;
+				}
+			}
+		}
+	}
+	for (name, _) in previous
+		.values
+		.iter()
+		.filter(|(n, _)| !e.values.contains_key(*n))
+	{
+		let count = previous
+			.values
+			.get(name)
+			.and_then(|v| removed.get(&v.to_string()))
+			.cloned()
+			.unwrap_or(0);
+		match count {
+			0 => migration.safe(format!("{}.{} is removed", e.name, name)),
+			n => migration.breaking(format!(
+				"{}.{} is removed, but used by {} values which are removed",
+				e.name, name, n
+			)),
+		}
+	}
+}
+
+fn translate_value(
+	translate: &[(JSONValue, JSONValue)],
+	value: &JSONValue,
+	removed: &mut HashMap<String, usize>,
+) -> JSONValue {
+	match translate.iter().find(|(from, _)| from == value) {
+		Some((from, JSONValue::Null)) => {
+			*removed.entry(from.to_string()).or_insert(0) += 1;
+			JSONValue::Null
+		}
+		Some((_, to)) => to.clone(),
+		None => value.clone(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::schema;
+
+	fn structure(sdl: &str) -> structure::StructureIndex {
+		schema::traverse_schema("test", &graphql_parser::parse_schema(sdl).unwrap())
+	}
+
+	fn migrate(old: &str, new: &str, data: JSONValue) -> Migration {
+		plan(&structure(old), &structure(new), &serde_json::from_value(data).unwrap())
+	}
+
+	fn messages(m: &Migration) -> Vec<String> {
+		m.changes
+			.iter()
+			.map(|c| format!("{}{}", if c.breaking { "! " } else { "" }, c.message))
+			.collect()
+	}
+
+	#[test]
+	fn renamed_fields_and_types_keep_their_data() {
+		let m = migrate(
+			"type Post { id: Int\n name: String }",
+			"type Article @renamed(from: \"Post\") { id: Int\n title: String @renamed(from: \"name\") }",
+			json!({"Post": [{"id": 1, "name": "a"}]}),
+		);
+		assert_eq!(
+			messages(&m),
+			vec!["Article is renamed from Post", "Article.title is renamed from name"]
+		);
+		assert!(!m.is_breaking());
+		assert!(!m.database.contains_key("Post"));
+		assert_eq!(m.database["Article"], vec![json!({"id": 1, "title": "a"})]);
+		assert_eq!(m.removed, vec!["Post"]);
+	}
+
+	#[test]
+	fn changed_types_convert_values() {
+		let m = migrate(
+			"type Post { id: Int\n views: String\n tags: String }",
+			"type Post { id: Int\n views: Int\n tags: [String] }",
+			json!({"Post": [{"id": 1, "views": " 12 ", "tags": "a"}, {"id": 2}]}),
+		);
+		assert_eq!(
+			messages(&m),
+			vec!["Post.views changes from String to Int", "Post.tags changes from String to [String]"]
+		);
+		assert_eq!(m.database["Post"][0], json!({"id": 1, "views": 12, "tags": ["a"]}));
+	}
+
+	#[test]
+	fn lost_data_is_breaking() {
+		let m = migrate(
+			"type Post { id: Int\n views: String\n body: String\n tags: [String] }",
+			"type Post { id: Int\n views: Int\n tags: String\n likes: Int! }",
+			json!({"Post": [{"id": 1, "views": "many", "body": "x", "tags": ["a", "b"]}]}),
+		);
+		assert_eq!(
+			messages(&m),
+			vec![
+				"! Post.views changes from String to Int, 1 values can't be converted and are removed",
+				"! Post.tags changes from [String] to String, 1 values can't be converted and are removed",
+				"! Post.likes is added as required, without a default for 1 records",
+				"! Post.body is removed with its values in 1 records",
+			]
+		);
+		assert!(m.is_breaking());
+	}
+
+	#[test]
+	fn safe_changes_are_not_breaking() {
+		let m = migrate(
+			"type Post { id: Int\n body: String }\ntype Tag { id: Int }",
+			"type Post { id: Int\n views: Int! @default(value: 0) }",
+			json!({"Post": [{"id": 1}, {"id": 2, "body": null}]}),
+		);
+		assert_eq!(
+			messages(&m),
+			vec!["Post.views is added, with defaults in 2 records", "Post.body is removed", "Tag is removed"]
+		);
+		assert!(!m.is_breaking());
+		assert_eq!(m.database["Post"][1], json!({"id": 2, "views": 0}));
+		assert_eq!(m.removed, vec!["Tag"]);
+	}
+}
@@ -1,21 +1,46 @@
 use graphql_parser::query::*;
 use serde_json::Value as JSONValue;
 
-use super::{indexing, resolver, schema, structure};
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use super::{indexing, integrity, resolver, scalar, schema, structure, utility};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
 pub type DatabaseIndex = HashMap<String, Vec<JSONValue>>;
 
 pub type DatabaseDirectory = Arc<RwLock<HashMap<String, QueryParser>>>;
 
+// Reads the stored records of a collection, None when there are none
+pub type CollectionLoader = Arc<dyn Fn(&str) -> Result<Option<Vec<JSONValue>>, String> + Send + Sync>;
+
+// Undo log entry of a record operation, kept while a transaction is open
+#[derive(Clone)]
+pub enum JournalEntry {
+	Insert(String, usize),
+	Update(String, usize, JSONValue),
+	Remove(String, usize, JSONValue),
+}
+
 #[derive(Clone)]
 pub struct QueryParser {
 	pub schema: structure::StructureIndex,
 	pub database: DatabaseIndex,
 	pub hashmaps: indexing::DatabaseHashmaps,
+	pub searches: indexing::DatabaseSearchIndexes,
 	pub is_canonical: bool,
 	pub directory: DatabaseDirectory,
+	pub journal: Vec<JournalEntry>,
+	pub transactions: usize,
+	// Last value given by sequences, keyed by sequence_key
+	pub sequences: HashMap<String, i64>,
+	// Times each deprecated field is read, keyed by "Type.field"
+	pub deprecated_uses: Arc<Mutex<HashMap<String, u64>>>,
+	// Collections changed since they were last saved
+	pub changed: HashSet<String>,
+	// Stored collections not read yet, empty until `loader` reads them
+	pub unloaded: HashSet<String>,
+	pub loader: Option<CollectionLoader>,
 }
 
 impl QueryParser {
@@ -27,26 +52,54 @@ impl QueryParser {
 	) -> QueryParser {
 		// Load necessary files (should be done before server starts, actually)
 		let mut schema = schema;
-		let instropection = schema::build_schema_instropection(&schema, instropection);
 		let mut db = database;
-		db.extend(instropection.database);
 		// Query schema injects
 		{
 			let qhash = match schema.find_object_mut("Query") {
 				structure::StructureItemMut::Object(o) => o,
 				_ => panic!(),
 			};
-			qhash.add_field(structure::StructureField::from(
-				"__schema".to_owned(),
-				"".to_owned(),
-				"__Schema".to_owned(),
-				false,
-				Some(structure::StructureDataResolver {
-					args: Vec::default(),
-					flags: Vec::default(),
-					kind: "ALL_REFERENCES".to_owned(),
-				}),
-			));
+			// Fields below are only injected when not declared, like in a printed schema
+			if qhash.find_field("__schema").is_none() {
+				qhash.add_field(structure::StructureField::from(
+					"__schema".to_owned(),
+					"".to_owned(),
+					"__Schema".to_owned(),
+					false,
+					Some(structure::StructureDataResolver {
+						args: Vec::default(),
+						flags: Vec::default(),
+						kind: "ALL_REFERENCES".to_owned(),
+					}),
+				));
+			}
+			// `__type(name: "Post")` finds the type by the name argument
+			if qhash.find_field("__type").is_none() {
+				let mut field = structure::StructureField::from(
+					"__type".to_owned(),
+					"".to_owned(),
+					"__Type".to_owned(),
+					false,
+					Some(structure::StructureDataResolver {
+						args: Vec::default(),
+						flags: Vec::default(),
+						kind: "ALL_REFERENCES".to_owned(),
+					}),
+				);
+				field.return_type.is_nullable = true;
+				field.args.push(structure::StructureArgument {
+					name: "name".to_owned(),
+					description: "".to_owned(),
+					return_type: structure::StructureReturnType {
+						name: "String".to_owned(),
+						is_array: false,
+						is_nullable: false,
+						is_item_required: false,
+					},
+					default: None,
+				});
+				qhash.add_field(field);
+			}
 		}
 		// let mut qdata = HashMap::new();
 		let mut qhash = Vec::new();
@@ -56,7 +109,7 @@ impl QueryParser {
 				structure::StructureItem::Object(_) => {
 					let fmtr = format!("values__of_{}", v.name);
 					// qdata.insert(fmtr.clone(), hashes);
-					qhash.push(structure::StructureField::from(
+					let mut values = structure::StructureField::from(
 						fmtr.clone(),
 						"".to_owned(),
 						v.name.clone(),
@@ -66,11 +119,91 @@ impl QueryParser {
 							flags: Vec::default(),
 							kind: "ALL_REFERENCES".to_owned(),
 						}),
-					));
+					);
+					// Arguments of stored fields are equality filters
+					values.args = schema::filter_arguments(&schema, v);
+					qhash.push(values);
+					if v.fields.iter().any(|f| f.is_searchable) {
+						let mut search = structure::StructureField::from(
+							format!("search__of_{}", v.name),
+							"".to_owned(),
+							v.name.clone(),
+							true,
+							Some(structure::StructureDataResolver {
+								args: Vec::default(),
+								flags: Vec::default(),
+								kind: "SEARCH".to_owned(),
+							}),
+						);
+						search.args.push(schema::argument("query", "String", false, false));
+						qhash.push(search);
+					}
 				}
 				_ => {}
 			}
 		}
+		// Bulk mutations of every type, unless the schema declares them itself
+		let mut mhash = Vec::new();
+		let mut minputs = Vec::new();
+		if let structure::StructureItem::Object(m) = schema.find_object("Mutation") {
+			for v in &schema.objects {
+				if ["Query", "Mutation", "Subscription"].contains(&v.name.as_str()) {
+					continue;
+				}
+				let inputs = schema::mutation_inputs(&schema, v);
+				for prefix in &["createMany", "updateMany", "deleteMany", "upsert"] {
+					let name = format!("{}{}", prefix, v.name);
+					if m.find_field(&name).is_some() {
+						continue;
+					}
+					let return_type = structure::StructureReturnType {
+						name: if *prefix == "upsert" { v.name.clone() } else { "Int".to_owned() },
+						is_array: false,
+						is_nullable: true,
+						is_item_required: false,
+					};
+					let data_type = schema::get_data_type(&return_type, "", "Mutation", &name);
+					// Nullable, as rolled back mutations give null
+					let mut field = structure::StructureField::from(
+						name,
+						"".to_owned(),
+						return_type.name.clone(),
+						false,
+						data_type.resolver,
+					);
+					field.return_type = return_type;
+					if let Some((filters, data)) = &inputs {
+						let (filters, data) = (filters.name.as_str(), data.name.as_str());
+						field.args = match *prefix {
+							"createMany" => vec![schema::argument("data", data, true, false)],
+							"updateMany" => vec![
+								schema::argument("where", filters, false, false),
+								schema::argument("data", data, false, false),
+							],
+							"deleteMany" => vec![schema::argument("where", filters, false, false)],
+							_ => vec![
+								schema::argument("where", filters, false, false),
+								schema::argument("create", data, false, false),
+								schema::argument("update", data, false, false),
+							],
+						};
+					}
+					mhash.push(field);
+				}
+				// Input types declared by the schema are kept as they are
+				for input in inputs.into_iter().flat_map(|(filters, data)| vec![filters, data]) {
+					if !schema.inputs.iter().any(|i| i.name == input.name) {
+						minputs.push(input);
+					}
+				}
+			}
+		}
+		schema.inputs.extend(minputs);
+		if let structure::StructureItemMut::Object(m) = schema.find_object_mut("Mutation") {
+			for mmm in mhash {
+				m.add_field(mmm);
+			}
+		}
 		// we require all of these has in DB, altough has no members at all
 		for class in vec!["Query", "Mutation", "Subscription"] {
 			if !db.contains_key(class) {
@@ -81,38 +214,457 @@ impl QueryParser {
 		match schema.find_object_mut("Query") {
 			structure::StructureItemMut::Object(o) => {
 				for qqq in qhash {
-					o.add_field(qqq);
+					if o.find_field(&qqq.name).is_none() {
+						o.add_field(qqq);
+					}
 				}
 			}
 			_ => panic!(),
 		};
+		// Instropection of the schema with everything injected above
+		let instropection = schema::build_schema_instropection(&schema, instropection);
+		db.extend(instropection.database);
 		for ooo in instropection.schema.objects {
 			schema.add_object(ooo);
 		}
 		for ooo in instropection.schema.enums {
 			schema.add_enum(ooo);
 		}
+		// hidden join collections of many-to-many fields
+		for join in schema::join_types(&schema) {
+			schema.add_object(join);
+		}
+		// empty types still need a collection, so records can be created later
+		for obj in &schema.objects {
+			if !db.contains_key(&obj.name) {
+				db.insert(obj.name.clone(), Vec::new());
+			}
+		}
 		// println!("{}", json!(db));
+		scalar::normalize_database(&mut db, &schema);
+		indexing::report_invalid_ids(&db, &schema);
 		let hashmap = indexing::build_hashmaps(&db, &schema);
+		indexing::report_duplicates(&hashmap, &schema);
+		integrity::report_references(&db, &hashmap, &schema);
+		let searches = indexing::build_search_indexes(&db, &schema);
+		let sequences = last_sequences(&db, &schema);
 		let is_canonical = schema.name == "canonical";
 		QueryParser {
 			schema: schema,
 			database: db,
 			hashmaps: hashmap,
+			searches: searches,
 			is_canonical: is_canonical,
 			directory: directory,
+			journal: Vec::new(),
+			transactions: 0,
+			sequences: sequences,
+			deprecated_uses: Arc::new(Mutex::new(HashMap::new())),
+			changed: HashSet::new(),
+			unloaded: HashSet::new(),
+			loader: None,
+		}
+	}
+
+	// Read collections not loaded yet, checked and indexed like those given to QueryParser::new.
+	// The types they reference are read with them, so their references can be checked.
+	pub fn load_collections(&mut self, classes: &[String]) -> Result<(), String> {
+		let loader = match &self.loader {
+			Some(l) => l.clone(),
+			None => return Ok(()),
+		};
+		let mut pending = classes.to_vec();
+		let mut batch = DatabaseIndex::new();
+		while let Some(class) = pending.pop() {
+			if !self.unloaded.contains(&class) || batch.contains_key(&class) {
+				continue;
+			}
+			if let structure::StructureItem::Object(obj) = self.schema.find_object(&class) {
+				pending.extend(integrity::reference_fields(obj, &self.schema).iter().map(|f| f.return_type.name.clone()));
+			}
+			batch.insert(class.clone(), loader(&class)?.unwrap_or_default());
+		}
+		scalar::normalize_database(&mut batch, &self.schema);
+		indexing::report_invalid_ids(&batch, &self.schema);
+		let hashmaps = indexing::build_hashmaps(&batch, &self.schema)
+			.into_iter()
+			.filter(|(class, _)| batch.contains_key(class))
+			.collect::<indexing::DatabaseHashmaps>();
+		indexing::report_duplicates(&hashmaps, &self.schema);
+		self.hashmaps.extend(hashmaps);
+		integrity::report_references(&batch, &self.hashmaps, &self.schema);
+		for (class, index) in indexing::build_search_indexes(&batch, &self.schema) {
+			if batch.contains_key(&class) {
+				self.searches.insert(class, index);
+			}
+		}
+		for (key, last) in last_sequences(&batch, &self.schema) {
+			let value = self.sequences.entry(key).or_insert(0);
+			*value = std::cmp::max(*value, last);
+		}
+		for (class, records) in batch {
+			self.unloaded.remove(&class);
+			self.database.insert(class, records);
+		}
+		Ok(())
+	}
+
+	pub fn load_all(&mut self) -> Result<(), String> {
+		let classes = self.unloaded.iter().cloned().collect::<Vec<String>>();
+		self.load_collections(&classes)
+	}
+
+	// Load the collections `ast` reads: the types of the fields it selects, with the join types
+	// of many-to-many fields. Mutations load every collection, as writes check references both ways.
+	pub fn load_for(&mut self, ast: &Document) -> Result<(), String> {
+		let classes = self.unloaded_collections(ast);
+		self.load_collections(&classes)
+	}
+
+	pub fn unloaded_collections(&self, ast: &Document) -> Vec<String> {
+		if self.unloaded.is_empty() {
+			return Vec::new();
+		}
+		let mut classes = HashSet::new();
+		if is_mutation(ast) {
+			classes = self.unloaded.clone();
+		} else if let Ok(fragments) = collect_fragments(ast) {
+			for def in &ast.definitions {
+				match def {
+					Definition::Operation(OperationDefinition::Query(q)) => {
+						self.selected_types(&q.selection_set, "Query", &fragments, &mut classes)
+					}
+					Definition::Operation(OperationDefinition::SelectionSet(s)) => {
+						self.selected_types(s, "Query", &fragments, &mut classes)
+					}
+					Definition::Operation(OperationDefinition::Subscription(s)) => {
+						self.selected_types(&s.selection_set, "Subscription", &fragments, &mut classes)
+					}
+					_ => {}
+				}
+			}
+		}
+		let mut classes = classes.into_iter().filter(|c| self.unloaded.contains(c)).collect::<Vec<String>>();
+		classes.sort();
+		classes
+	}
+
+	fn selected_types(
+		&self,
+		selection_set: &SelectionSet,
+		class: &str,
+		fragments: &HashMap<String, &FragmentDefinition>,
+		classes: &mut HashSet<String>,
+	) {
+		let obj = match self.schema.find_object(class) {
+			structure::StructureItem::Object(o) => o,
+			_ => return,
+		};
+		for field in collect_fields(selection_set, fragments) {
+			let info = match obj.find_field(&field.name) {
+				Some(v) => v,
+				None => continue,
+			};
+			match &info.data_type.resolver {
+				Some(r) if r.kind == "MANY_TO_MANY" => {
+					classes.insert(r.flags[0].clone());
+				}
+				_ => {}
+			}
+			if let structure::StructureItem::Object(_) = self.schema.find_object(&info.return_type.name) {
+				classes.insert(info.return_type.name.clone());
+				self.selected_types(&field.selection_set, &info.return_type.name, fragments, classes);
+			}
+		}
+	}
+
+	// Positions of records in `class` which fields equal to `filters`,
+	// or an error when a filter isn't a valid value of its field
+	pub fn find_positions(
+		&self,
+		class: &str,
+		filters: &[(String, JSONValue)],
+	) -> Result<Vec<usize>, String> {
+		let mut filters = filters.to_vec();
+		scalar::normalize_filters(&self.schema, class, &mut filters)?;
+		Ok(match self.database.get(class) {
+			Some(records) => indexing::find_equals(&self.hashmaps, records, class, &filters),
+			_ => Vec::new(),
+		})
+	}
+
+	// Append a record and index it at the new position
+	pub fn insert_record(&mut self, class: &str, record: JSONValue) -> usize {
+		let records = self.database.entry(class.to_owned()).or_default();
+		let position = records.len();
+		indexing::insert_position(&mut self.hashmaps, class, position, &record);
+		if let Some(index) = self.searches.get_mut(class) {
+			index.insert(position, &record);
+		}
+		// Explicit values move the sequences past them
+		for (key, last) in self.sequences.iter_mut() {
+			let field = match key.strip_prefix(class) {
+				Some("") => "id",
+				Some(rest) => match rest.strip_prefix('.') {
+					Some(field) => field,
+					None => continue,
+				},
+				None => continue,
+			};
+			if let Some(value) = record[field].as_i64() {
+				*last = std::cmp::max(*last, value);
+			}
+		}
+		records.push(record);
+		self.changed.insert(class.to_owned());
+		self.record_journal(JournalEntry::Insert(class.to_owned(), position));
+		position
+	}
+
+	// Add records of `class` read from a file, checked like created ones. With `replace`,
+	// present records are removed first. Either all are added or none, returning how many.
+	pub fn import_records(&mut self, class: &str, records: Vec<JSONValue>, replace: bool) -> Result<usize, String> {
+		if !matches!(self.schema.find_object(class), structure::StructureItem::Object(_)) || class.starts_with("__") {
+			return Err(format!("{} is not a type of {}", class, self.schema.name));
+		}
+		let savepoint = self.begin();
+		let result = self.import_into(class, records, replace);
+		match result {
+			Ok(_) => self.commit(),
+			Err(_) => self.rollback(savepoint),
+		}
+		result
+	}
+
+	fn import_into(&mut self, class: &str, records: Vec<JSONValue>, replace: bool) -> Result<usize, String> {
+		if replace {
+			while let Some(last) = self.database.get(class).map_or(0, Vec::len).checked_sub(1) {
+				self.remove_record(class, last);
+			}
+		}
+		let count = records.len();
+		let first = self.database.get(class).map_or(0, Vec::len);
+		for (i, mut record) in records.into_iter().enumerate() {
+			let at = |e: String| format!("Record {}: {}", i + 1, e);
+			self.apply_defaults(class, &mut record);
+			scalar::normalize_record(&self.schema, class, &mut record).map_err(at)?;
+			self.check_unique(class, &record, None).map_err(at)?;
+			self.insert_record(class, record);
+		}
+		// References are checked once all records are in, so records may point to later ones
+		for i in 0..count {
+			integrity::check_references(self, class, &self.database[class][first + i])
+				.map_err(|e| format!("Record {}: {}", i + 1, e))?;
+		}
+		// Other records may still point to removed ones
+		if replace {
+			integrity::check_referenced(self, class)?;
+		}
+		Ok(count)
+	}
+
+	// Fields of `class` with a default, and @updatedAt fields
+	fn field_defaults(&self, class: &str) -> Vec<(structure::StructureField, structure::StructureDataDefault)> {
+		match self.schema.find_object(class) {
+			structure::StructureItem::Object(o) => o
+				.fields
+				.iter()
+				.filter_map(|f| f.data_type.default.clone().map(|d| (f.clone(), d)))
+				.collect(),
+			_ => Vec::new(),
+		}
+	}
+
+	// Fill fields missing in a new record with their default, ids included.
+	// Sequences aren't rolled back, so rolled back creates leave gaps.
+	pub fn apply_defaults(&mut self, class: &str, record: &mut JSONValue) {
+		for (field, default) in self.field_defaults(class) {
+			if !record[&field.name].is_null() {
+				continue;
+			}
+			let value = match default.kind.as_ref() {
+				"INCREMENT" | "SEQUENCE" => {
+					let last = self.sequences.entry(sequence_key(class, &field.name)).or_insert(0);
+					*last += 1;
+					json!(*last)
+				}
+				"UUID" => json!(utility::uuid_v4()),
+				"ULID" => json!(utility::ulid()),
+				"NOW" | "UPDATED_AT" => now_value(&field),
+				_ => default.reference.clone(),
+			};
+			if !value.is_null() {
+				record[&field.name] = value;
+			}
+		}
+	}
+
+	// Move sequences past the values they gave before, as saved in `sequences.json`.
+	// Values of sequences the schema no longer has are dropped.
+	pub fn restore_sequences(&mut self, saved: HashMap<String, i64>) {
+		for (key, last) in saved {
+			if let Some(v) = self.sequences.get_mut(&key) {
+				*v = std::cmp::max(*v, last);
+			}
+		}
+	}
+
+	// Refresh @updatedAt fields of a record being updated
+	pub fn apply_updated_at(&self, class: &str, record: &mut JSONValue) {
+		for (field, default) in self.field_defaults(class) {
+			if default.kind == "UPDATED_AT" {
+				record[&field.name] = now_value(&field);
+			}
+		}
+	}
+
+	// Replace a record in place, returning the old one
+	pub fn update_record(&mut self, class: &str, position: usize, record: JSONValue) -> JSONValue {
+		let records = self.database.get_mut(class).unwrap();
+		let old = std::mem::replace(&mut records[position], record);
+		let new = &records[position];
+		indexing::update_position(&mut self.hashmaps, class, position, &old, new);
+		if let Some(index) = self.searches.get_mut(class) {
+			index.remove(position, &old);
+			index.insert(position, new);
+		}
+		self.changed.insert(class.to_owned());
+		self.record_journal(JournalEntry::Update(class.to_owned(), position, old.clone()));
+		old
+	}
+
+	// Remove a record. The last record is moved into its position,
+	// so only those two positions need to be reindexed.
+	pub fn remove_record(&mut self, class: &str, position: usize) -> JSONValue {
+		let records = self.database.get_mut(class).unwrap();
+		let last = records.len() - 1;
+		indexing::remove_position(&mut self.hashmaps, class, position, &records[position]);
+		if let Some(index) = self.searches.get_mut(class) {
+			index.remove(position, &records[position]);
+		}
+		if position != last {
+			indexing::remove_position(&mut self.hashmaps, class, last, &records[last]);
+			if let Some(index) = self.searches.get_mut(class) {
+				index.remove(last, &records[last]);
+			}
+		}
+		let removed = records.swap_remove(position);
+		if position != last {
+			indexing::insert_position(&mut self.hashmaps, class, position, &records[position]);
+			if let Some(index) = self.searches.get_mut(class) {
+				index.insert(position, &records[position]);
+			}
+		}
+		self.changed.insert(class.to_owned());
+		self.record_journal(JournalEntry::Remove(class.to_owned(), position, removed.clone()));
+		removed
+	}
+
+	fn record_journal(&mut self, entry: JournalEntry) {
+		if self.transactions > 0 {
+			self.journal.push(entry);
+		}
+	}
+
+	// Open a (nested) transaction. Returns the savepoint to commit or roll back to.
+	pub fn begin(&mut self) -> usize {
+		self.transactions += 1;
+		self.journal.len()
+	}
+
+	pub fn commit(&mut self) {
+		self.transactions -= 1;
+		if self.transactions == 0 {
+			self.journal.clear();
+		}
+	}
+
+	// Undo every record operation since `savepoint`, newest first
+	pub fn rollback(&mut self, savepoint: usize) {
+		let entries = self.journal.split_off(savepoint);
+		let transactions = std::mem::replace(&mut self.transactions, 0);
+		for entry in entries.into_iter().rev() {
+			match entry {
+				JournalEntry::Insert(class, position) => {
+					self.remove_record(&class, position);
+				}
+				JournalEntry::Update(class, position, old) => {
+					self.update_record(&class, position, old);
+				}
+				// Reverse the swap: the moved record goes back to the end
+				JournalEntry::Remove(class, position, record) => {
+					if position == self.database[&class].len() {
+						self.insert_record(&class, record);
+					} else {
+						let moved = self.update_record(&class, position, record);
+						self.insert_record(&class, moved);
+					}
+				}
+			}
+		}
+		self.transactions = transactions - 1;
+		if self.transactions == 0 {
+			self.journal.clear();
 		}
 	}
 
+	// Whether incremental indexes are still the same as a full rebuild
+	pub fn verify_indexes(&self) -> bool {
+		indexing::verify_hashmaps(&self.hashmaps, &self.database, &self.schema)
+			&& indexing::build_search_indexes(&self.database, &self.schema) == self.searches
+	}
+
+	// Error when `record` shares an id or unique key with another record than `position`
+	pub fn check_unique(
+		&self,
+		class: &str,
+		record: &JSONValue,
+		position: Option<usize>,
+	) -> Result<(), String> {
+		let (obj, type_hash) = match (self.schema.find_object(class), self.hashmaps.get(class)) {
+			(structure::StructureItem::Object(o), Some(Some(h))) => (o, h),
+			_ => return Ok(()),
+		};
+		for key in obj.unique_keys() {
+			let hash = match type_hash.get(&key) {
+				Some(v) => v,
+				_ => continue,
+			};
+			let values = indexing::index_values(&key, record);
+			if key == "id" && !hash.accepts(&values) {
+				return Err(format!("{} requires a valid id, got {}", class, values[0]));
+			}
+			if values.iter().any(|v| v.is_null()) {
+				continue;
+			}
+			if let Some(positions) = hash.find(&values) {
+				if positions.iter().any(|p| Some(*p) != position) {
+					return Err(format!(
+						"{} with ({}) = ({}) already exists",
+						class,
+						key,
+						indexing::composite_values(&values).join(",")
+					));
+				}
+			}
+		}
+		Ok(())
+	}
+
 	// Resolve/Expand JSON database to object representation (by looking their Schema Type)
-	fn resolve_id_to_object(&self, id: &JSONValue, class_name: &String) -> JSONValue {
+	fn resolve_id_to_object(
+		&self,
+		id: &JSONValue,
+		class_name: &String,
+		errors: &RefCell<Vec<JSONValue>>,
+	) -> JSONValue {
 		match id {
 			// Unpack array and resolve individually
 			JSONValue::Array(arr) => json!(arr
 				.iter()
 				.filter_map(|x| match x {
 					JSONValue::Null => None,
-					_ => match self.resolve_id_to_object(x, &class_name) {
+					_ => match self.resolve_id_to_object(x, &class_name, errors) {
 						JSONValue::Null => None,
 						y @ _ => Some(y),
 					},
@@ -120,6 +672,8 @@ impl QueryParser {
 				.collect::<Vec<JSONValue>>()),
 			// Null is null
 			JSONValue::Null => JSONValue::Null,
+			// Already expanded (e.g. a deleted record)
+			JSONValue::Object(_) => id.clone(),
 			x => {
 				match class_name.as_ref() {
 					// A primitive
@@ -133,29 +687,20 @@ impl QueryParser {
 							} // Could be an enum
 						};
 						// Unpack object
-						let idkey = match &self.hashmaps[n] {
-							Some(v) => &v["id"],
-							_ => {
-								panic!();
-							}
+						// Types without an indexed id can't be referenced
+						let idkey = match self.hashmaps.get(n) {
+							Some(Some(v)) => v.get("id"),
+							_ => None,
 						};
-						let keyy = match &idkey {
-							indexing::FieldHashmaps::String(h) => match x.as_str() {
-								Some(v) => match h.get(v) {
-									Some(v) => Some(v),
-									_ => None,
-								},
-								_ => None,
-							},
-							indexing::FieldHashmaps::I32(h) => {
-								Some(&h[&(x.as_i64().unwrap() as i32)])
+						match idkey.map(|k| k.find(&[x])) {
+							Some(Some(v)) => arr[v[0]].clone(),
+							Some(None) => JSONValue::Null,
+							None => {
+								errors.borrow_mut().push(json!({
+									"message": format!("{} has no indexed id to look up {}", n, x)
+								}));
+								JSONValue::Null
 							}
-							indexing::FieldHashmaps::U64(h) => Some(&h[&(x.as_u64().unwrap())]),
-							_ => panic!(),
-						};
-						match keyy {
-							Some(v) => arr[v[0]].clone(),
-							_ => JSONValue::Null,
 						}
 					}
 				}
@@ -163,6 +708,26 @@ impl QueryParser {
 		}
 	}
 
+	// Uses of deprecated fields are counted, to know when nothing relies on them anymore.
+	// Logged on the 1st, 10th, 100th.. use.
+	fn log_deprecated(&self, class: &str, info: &structure::StructureField) {
+		if let Some(reason) = &info.deprecated {
+			let key = format!("{}.{}", class, info.name);
+			let mut uses = self.deprecated_uses.lock().unwrap_or_else(|e| e.into_inner());
+			let count = uses.entry(key.clone()).or_insert(0);
+			*count += 1;
+			let mut mark = 1;
+			while mark < *count {
+				mark *= 10;
+			}
+			match *count {
+				1 => println!("Deprecated field {} is used: {}", key, reason),
+				n if n == mark => println!("Deprecated field {} is used {} times: {}", key, n, reason),
+				_ => {}
+			}
+		}
+	}
+
 	fn resolve_field(
 		&self,
 		parent: &JSONValue,
@@ -171,21 +736,17 @@ impl QueryParser {
 		context: &resolver::GenericResolverContext,
 		info: &structure::StructureField,
 	) -> JSONValue {
-		println!("I am dead");
-		let written = &mut (*self.directory.write().unwrap());
-		println!("I am alive");
 		match resolver::resolve(parent, args, &resolver::ResolverContext {
 			fragments: context.fragments,
 			variables: context.variables,
-			parser: written.get_mut(&self.schema.name[..]).unwrap(),
+			errors: context.errors,
+			parser: self,
 		}, info) {
 			JSONValue::Null => JSONValue::Null,
-			results @ _ => self.traverse_selection(
-				&self.resolve_id_to_object(&results, &info.return_type.name),
-				&selector.selection_set,
-				context,
-				&info.return_type.name,
-			),
+			results @ _ => match self.resolve_id_to_object(&results, &info.return_type.name, context.errors) {
+				JSONValue::Null => JSONValue::Null,
+				object => self.traverse_selection(&object, &selector.selection_set, context, &info.return_type.name),
+			},
 		}
 	}
 
@@ -203,36 +764,64 @@ impl QueryParser {
 				.collect::<Vec<JSONValue>>()),
 			_ => {
 				match info {
-					"String" | "ID" | "Number" | "Float" | "Int" => parent.clone(),
+					"String" | "ID" | "Number" | "Float" | "Int" | "Boolean" => parent.clone(),
 					nn @ _ => {
 						match &self.schema.find_object(nn) {
-							structure::StructureItem::Enum(_) => parent.clone(),
+							structure::StructureItem::Scalar(_) => parent.clone(),
+							// Stored values are translated back to their names
+							structure::StructureItem::Enum(e) => match (parent, e.decode(parent)) {
+								(JSONValue::Null, _) => JSONValue::Null,
+								(_, Some(name)) => json!(name),
+								(_, None) => {
+									context.errors.borrow_mut().push(json!({
+										"message": format!("{} has no value stored as {}", e.name, parent)
+									}));
+									JSONValue::Null
+								}
+							},
 							structure::StructureItem::Object(fields) => {
 								let mut values = HashMap::new();
 								for sel in &selector.items {
 									match sel {
+										Selection::Field(field) if field.name == "__typename" => {
+											values.insert(
+												field.alias.as_ref().unwrap_or(&field.name).clone(),
+												json!(nn),
+											);
+										}
 										// Field for parent
 										Selection::Field(field) => {
 											values.insert(
-												field.name.clone(),
+												field.alias.as_ref().unwrap_or(&field.name).clone(),
 												match &fields.find_field(&field.name) {
-													Some(ff) => self.resolve_field(
-														&parent,
-														&field.arguments,
-														&field,
-														context,
-														&ff,
-													),
+													Some(ff) => {
+														self.log_deprecated(nn, ff);
+														self.resolve_field(
+															&parent,
+															&field.arguments,
+															&field,
+															context,
+															&ff,
+														)
+													}
 													None => JSONValue::Null,
 												},
 											);
 										}
 										Selection::FragmentSpread(spread) => {
 											// traverse again, then unpack
+											let fragment = match context.fragments.get(&spread.fragment_name) {
+												Some(v) => v,
+												None => {
+													context.errors.borrow_mut().push(json!({
+														"message": format!("Unknown fragment {}", spread.fragment_name)
+													}));
+													continue;
+												}
+											};
 											let frag_values = self.traverse_selection(
 												parent,
-												&context.fragments[&spread.fragment_name]
-													.selection_set,
+												&fragment.selection_set,
 												context,
 												info,
 											);
@@ -258,46 +847,856 @@ impl QueryParser {
 		}
 	}
 
+	// Mutation fields are resolved one by one with write access to the database,
+	// then their result is traversed like a query
+	pub fn traverse_mutation(
+		&mut self,
+		ast: &Document,
+		variables: &serde_json::Map<String, JSONValue>,
+	) -> (JSONValue, Vec<JSONValue>) {
+		let fragments = match collect_fragments(ast) {
+			Ok(v) => v,
+			Err(e) => return (JSONValue::Null, vec![json!({ "message": e })]),
+		};
+		let errors = RefCell::new(Vec::new());
+		let mutation = match ast.definitions.iter().find_map(|def| match &def {
+			Definition::Operation(OperationDefinition::Mutation(m)) => Some(m),
+			_ => None,
+		}) {
+			Some(v) => v,
+			_ => return (JSONValue::Null, errors.into_inner()),
+		};
+		// Every field applies or none does, unless opted out by `mutation @bestEffort { .. }`
+		let transactional = !mutation.directives.iter().any(|d| d.name == "bestEffort");
+		let savepoint = self.begin();
+		let mut failed = false;
+		let parent = self.database["Mutation"][0].clone();
+		let mut values = HashMap::new();
+		// Root fields execute serially in document order, each one seeing every change before it
+		for field in collect_fields(&mutation.selection_set, &fragments) {
+			if failed {
+				values.insert(field.alias.as_ref().unwrap_or(&field.name).clone(), JSONValue::Null);
+				continue;
+			}
+			let info = match self.schema.find_object("Mutation") {
+				structure::StructureItem::Object(o) => o.find_field(&field.name).cloned(),
+				_ => None,
+			};
+			let key = field.alias.as_ref().unwrap_or(&field.name).clone();
+			let info = match info {
+				Some(v) => v,
+				None => {
+					values.insert(key, JSONValue::Null);
+					continue;
+				}
+			};
+			self.log_deprecated("Mutation", &info);
+			let results = resolver::resolve_mutation(
+				&parent,
+				&field.arguments,
+				&mut resolver::MutationResolverContext {
+					fragments: &fragments,
+					variables,
+					errors: &errors,
+					parser: self,
+				},
+				&info,
+			);
+			let value = match results {
+				Ok(JSONValue::Null) => JSONValue::Null,
+				Ok(results) => self.traverse_selection(
+					&self.resolve_id_to_object(&results, &info.return_type.name, &errors),
+					&field.selection_set,
+					&resolver::GenericResolverContext {
+						fragments: &fragments,
+						variables,
+						errors: &errors,
+					},
+					&info.return_type.name,
+				),
+				Err(message) => {
+					errors
+						.borrow_mut()
+						.push(json!({ "message": message, "path": [key] }));
+					failed = transactional;
+					JSONValue::Null
+				}
+			};
+			values.insert(key, value);
+		}
+		if failed {
+			self.rollback(savepoint);
+			for value in values.values_mut() {
+				*value = JSONValue::Null;
+			}
+			errors
+				.borrow_mut()
+				.push(json!({ "message": "Mutation is rolled back, nothing has been applied" }));
+		} else {
+			self.commit();
+		}
+		debug_assert!(self.verify_indexes(), "indexes differ from a full rebuild");
+		(json!(values), errors.into_inner())
+	}
+
 	pub fn traverse_query(
 		&self,
 		ast: &Document,
 		variables: &serde_json::Map<String, JSONValue>,
-	) -> JSONValue {
+	) -> (JSONValue, Vec<JSONValue>) {
 		// Look for fragments before doing actual operation
-		let fragments = ast
-			.definitions
-			.iter()
-			.filter_map(|def| match &def {
-				Definition::Fragment(fragdef) => Some((fragdef.name.clone(), fragdef)),
-				_ => None,
-			})
-			.collect::<HashMap<String, &FragmentDefinition>>();
-
-		// Start action
-		let subset = ast
-			.definitions
-			.iter()
-			.filter_map(|def| match &def {
-				Definition::Operation(opdef) => match opdef {
-					OperationDefinition::Query(q) => Some((&q.selection_set, "Query")),
-					OperationDefinition::SelectionSet(s) => Some((s, "Query")),
-					OperationDefinition::Mutation(m) => Some((&m.selection_set, "Mutation")),
-					OperationDefinition::Subscription(s) => {
-						Some((&s.selection_set, "Subscription"))
+		let fragments = match collect_fragments(ast) {
+			Ok(v) => v,
+			Err(e) => return (JSONValue::Null, vec![json!({ "message": e })]),
+		};
+		let errors = RefCell::new(Vec::new());
+
+		// Mutations are executed by traverse_mutation
+		let (selection_set, root) = match ast.definitions.iter().find_map(|def| match &def {
+			Definition::Operation(OperationDefinition::Query(q)) => Some((&q.selection_set, "Query")),
+			Definition::Operation(OperationDefinition::SelectionSet(s)) => Some((s, "Query")),
+			Definition::Operation(OperationDefinition::Subscription(s)) => {
+				Some((&s.selection_set, "Subscription"))
+			}
+			_ => None,
+		}) {
+			Some(v) => v,
+			_ => return (JSONValue::Null, errors.into_inner()),
+		};
+		let fields = match self.schema.find_object(root) {
+			structure::StructureItem::Object(o) => o,
+			_ => return (JSONValue::Null, errors.into_inner()),
+		};
+		let parent = &self.database[root][0];
+
+		// Query fields can't change anything, so root fields are resolved in parallel
+		// by a few threads taking the next field in turn, or on this thread when only one is asked.
+		// Each one collects its own errors, merged in document order.
+		let resolve = |field: &Field| {
+			let errors = RefCell::new(Vec::new());
+			let key = field.alias.as_ref().unwrap_or(&field.name).clone();
+			// A bug in a resolver fails its field rather than the whole response
+			let value = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+				match fields.find_field(&field.name) {
+					Some(info) => {
+						self.log_deprecated(root, info);
+						self.resolve_field(
+							parent,
+							&field.arguments,
+							field,
+							&resolver::GenericResolverContext {
+								fragments: &fragments,
+								variables,
+								errors: &errors,
+							},
+							info,
+						)
 					}
-				},
-				_ => None,
-			})
-			.collect::<Vec<(&SelectionSet, &str)>>()[0];
-
-		self.traverse_selection(
-			&self.database[subset.1][0],
-			&subset.0,
-			&resolver::GenericResolverContext {
-				fragments: &fragments,
-				variables: &variables,
+					None => JSONValue::Null,
+				}
+			}));
+			let mut errors = errors.into_inner();
+			let value = value.unwrap_or_else(|cause| {
+				let cause = cause
+					.downcast_ref::<&str>()
+					.map(|x| x.to_string())
+					.or_else(|| cause.downcast_ref::<String>().cloned())
+					.unwrap_or_default();
+				errors.push(json!({ "message": format!("Unable to resolve {}: {}", key, cause) }));
+				JSONValue::Null
+			});
+			(key, value, errors)
+		};
+		let root_fields = collect_fields(selection_set, &fragments);
+		let workers = std::thread::available_parallelism()
+			.map_or(1, |n| n.get())
+			.min(root_fields.len());
+		let results = if workers <= 1 {
+			root_fields.into_iter().map(resolve).collect::<Vec<_>>()
+		} else {
+			let next = AtomicUsize::new(0);
+			let mut results = std::thread::scope(|scope| {
+				(0..workers)
+					.map(|_| {
+						scope.spawn(|| {
+							let mut done = Vec::new();
+							loop {
+								let i = next.fetch_add(1, Ordering::Relaxed);
+								match root_fields.get(i) {
+									Some(field) => done.push((i, resolve(field))),
+									None => return done,
+								}
+							}
+						})
+					})
+					.collect::<Vec<_>>()
+					.into_iter()
+					// Panics are caught by `resolve`, so workers always finish
+					.flat_map(|handle| handle.join().unwrap_or_default())
+					.collect::<Vec<_>>()
+			});
+			results.sort_by_key(|(i, _)| *i);
+			results.into_iter().map(|(_, result)| result).collect()
+		};
+		let mut values = HashMap::new();
+		for (key, value, field_errors) in results {
+			errors.borrow_mut().extend(field_errors);
+			values.insert(key, value);
+		}
+		(json!(values), errors.into_inner())
+	}
+}
+
+// Fields of a selection set in document order, with fragments at this level unpacked
+fn collect_fields<'a>(
+	selection_set: &'a SelectionSet,
+	fragments: &HashMap<String, &'a FragmentDefinition>,
+) -> Vec<&'a Field> {
+	let mut fields = Vec::new();
+	for sel in &selection_set.items {
+		match sel {
+			Selection::Field(field) => fields.push(field),
+			Selection::FragmentSpread(spread) => {
+				if let Some(fragment) = fragments.get(&spread.fragment_name) {
+					fields.extend(collect_fields(&fragment.selection_set, fragments));
+				}
+			}
+			Selection::InlineFragment(inline) => {
+				fields.extend(collect_fields(&inline.selection_set, fragments));
+			}
+		}
+	}
+	fields
+}
+
+// Largest value of each sequence in the collections of `db`
+fn last_sequences(db: &DatabaseIndex, schema: &structure::StructureIndex) -> HashMap<String, i64> {
+	let mut sequences = HashMap::new();
+	for obj in &schema.objects {
+		let records = match db.get(&obj.name) {
+			Some(v) => v,
+			None => continue,
+		};
+		for field in &obj.fields {
+			match &field.data_type.default {
+				Some(d) if d.kind == "INCREMENT" || d.kind == "SEQUENCE" => {
+					let last = records.iter().filter_map(|r| r[&field.name].as_i64()).max();
+					sequences.insert(sequence_key(&obj.name, &field.name), last.unwrap_or(0));
+				}
+				_ => {}
+			}
+		}
+	}
+	sequences
+}
+
+// Sequence of ids is kept by type name, other sequences by "Type.field"
+pub fn sequence_key(class: &str, field: &str) -> String {
+	match field {
+		"id" => class.to_owned(),
+		_ => format!("{}.{}", class, field),
+	}
+}
+
+// Current time as milliseconds since epoch for numbers, otherwise as ISO 8601 text
+pub fn now_value(field: &structure::StructureField) -> JSONValue {
+	match field.return_type.name.as_ref() {
+		"Int" | "Float" => json!(utility::millis_since_epoch() as i64),
+		"Date" => json!(utility::format_datetime(utility::millis_since_epoch() as i64)[0..10]),
+		_ => json!(utility::format_datetime(utility::millis_since_epoch() as i64)),
+	}
+}
+
+// Fragments by name, or an error when one of them spreads itself, directly or not
+fn collect_fragments(ast: &Document) -> Result<HashMap<String, &FragmentDefinition>, String> {
+	let fragments = ast
+		.definitions
+		.iter()
+		.filter_map(|def| match &def {
+			Definition::Fragment(fragdef) => Some((fragdef.name.clone(), fragdef)),
+			_ => None,
+		})
+		.collect::<HashMap<String, &FragmentDefinition>>();
+	for fragment in fragments.values() {
+		let mut visiting = vec![fragment.name.as_str()];
+		if let Some(name) = find_cycle(&fragment.selection_set, &fragments, &mut visiting) {
+			return Err(format!("Fragment {} spreads itself", name));
+		}
+	}
+	Ok(fragments)
+}
+
+// Name of a fragment spread again inside itself, following spreads from `visiting`
+fn find_cycle<'a>(
+	selection_set: &'a SelectionSet,
+	fragments: &HashMap<String, &'a FragmentDefinition>,
+	visiting: &mut Vec<&'a str>,
+) -> Option<String> {
+	for sel in &selection_set.items {
+		let found = match sel {
+			Selection::Field(field) => find_cycle(&field.selection_set, fragments, visiting),
+			Selection::InlineFragment(inline) => find_cycle(&inline.selection_set, fragments, visiting),
+			Selection::FragmentSpread(spread) if visiting.contains(&spread.fragment_name.as_str()) => {
+				Some(spread.fragment_name.clone())
+			}
+			Selection::FragmentSpread(spread) => match fragments.get(&spread.fragment_name) {
+				Some(fragment) => {
+					visiting.push(&fragment.name);
+					let found = find_cycle(&fragment.selection_set, fragments, visiting);
+					visiting.pop();
+					found
+				}
+				None => None,
 			},
-			subset.1,
-		)
+		};
+		if found.is_some() {
+			return found;
+		}
+	}
+	None
+}
+
+pub fn is_mutation(ast: &Document) -> bool {
+	ast.definitions
+		.iter()
+		.any(|def| matches!(def, Definition::Operation(OperationDefinition::Mutation(_))))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SCHEMA: &str = r#"
+type Query {
+  posts: [Post]
+}
+type Mutation {
+  createPost(data: String): Post
+  updatePost(data: String): Post
+}
+type Author {
+  id: Int
+  name: String
+}
+type Post @index(fields: ["authorId", "status"]) {
+  id: Int
+  title: String @searchable
+  authorId: Int
+  status: String
+}
+"#;
+
+	pub fn parser(sdl: &str, data: JSONValue) -> QueryParser {
+		let schema = schema::traverse_schema("test", &graphql_parser::parse_schema(sdl).unwrap());
+		let intros = schema::traverse_schema("instropection", &graphql_parser::schema::Document { definitions: vec![] });
+		let db = serde_json::from_value(data).unwrap();
+		QueryParser::new(db, schema, intros, Arc::new(RwLock::new(HashMap::new())))
+	}
+
+	// Small deterministic generator, so failures can be replayed
+	struct Random(u64);
+
+	impl Random {
+		fn below(&mut self, n: usize) -> usize {
+			self.0 = self.0.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+			((self.0 >> 33) % n as u64) as usize
+		}
+	}
+
+	fn random_post(rng: &mut Random, id: usize) -> JSONValue {
+		let words = ["red", "green", "blue", "apple", "road", "science"];
+		let status = ["DRAFT", "PUBLISHED"][rng.below(2)];
+		json!({
+			"id": id,
+			"title": format!("{} {}", words[rng.below(words.len())], words[rng.below(words.len())]),
+			"authorId": rng.below(3),
+			"status": status,
+		})
+	}
+
+	fn assert_rebuilt(p: &QueryParser) {
+		assert!(indexing::verify_hashmaps(&p.hashmaps, &p.database, &p.schema));
+		assert!(indexing::build_search_indexes(&p.database, &p.schema) == p.searches);
+	}
+
+	#[test]
+	fn incremental_indexes_match_a_rebuild() {
+		let mut p = parser(SCHEMA, json!({}));
+		let mut rng = Random(7);
+		let mut next_id = 0;
+		for step in 0..2000 {
+			let len = p.database["Post"].len();
+			match rng.below(3) {
+				0 => {
+					next_id += 1;
+					let post = random_post(&mut rng, next_id);
+					p.insert_record("Post", post);
+				}
+				1 if len > 0 => {
+					let position = rng.below(len);
+					let mut post = random_post(&mut rng, 0);
+					post["id"] = p.database["Post"][position]["id"].clone();
+					p.update_record("Post", position, post);
+				}
+				_ if len > 0 => {
+					p.remove_record("Post", rng.below(len));
+				}
+				_ => {}
+			}
+			if step % 50 == 0 {
+				assert_rebuilt(&p);
+			}
+		}
+		assert_rebuilt(&p);
+	}
+
+	#[test]
+	fn rollback_restores_records_and_indexes() {
+		let mut p = parser(SCHEMA, json!({}));
+		let mut rng = Random(11);
+		for id in 1..=20 {
+			let post = random_post(&mut rng, id);
+			p.insert_record("Post", post);
+		}
+		let before = p.database["Post"].clone();
+		let savepoint = p.begin();
+		for id in 21..=60 {
+			let len = p.database["Post"].len();
+			match rng.below(3) {
+				0 => {
+					let post = random_post(&mut rng, id);
+					p.insert_record("Post", post);
+				}
+				1 => {
+					let position = rng.below(len);
+					let mut post = random_post(&mut rng, 0);
+					post["id"] = p.database["Post"][position]["id"].clone();
+					p.update_record("Post", position, post);
+				}
+				_ => {
+					p.remove_record("Post", rng.below(len));
+				}
+			}
+		}
+		p.rollback(savepoint);
+		assert_eq!(p.database["Post"], before);
+		assert_rebuilt(&p);
+	}
+
+	#[test]
+	fn reference_to_a_type_without_id_is_an_error() {
+		let sdl = "type Query { posts: [Post] }\ntype Note { text: String }\ntype Post { id: Int\n note: Note }";
+		let p = parser(sdl, json!({"Post": [{"id": 1, "note": "x"}], "Note": [{"text": "x"}]}));
+		let query = graphql_parser::parse_query("{ posts { id note { text } } }").unwrap();
+		let (values, errors) = p.traverse_query(&query, &serde_json::Map::new());
+		assert_eq!(values, json!({"posts": [{"id": 1, "note": null}]}));
+		assert_eq!(errors.len(), 1);
+	}
+
+	#[test]
+	fn removing_moves_the_last_record_into_the_gap() {
+		let mut p = parser(SCHEMA, json!({"Post": [{"id": 1}, {"id": 2}, {"id": 3}, {"id": 4}]}));
+		p.remove_record("Post", 1);
+		let ids = p.database["Post"].iter().map(|r| r["id"].clone()).collect::<Vec<JSONValue>>();
+		assert_eq!(ids, vec![json!(1), json!(4), json!(3)]);
+		p.remove_record("Post", 2);
+		let ids = p.database["Post"].iter().map(|r| r["id"].clone()).collect::<Vec<JSONValue>>();
+		assert_eq!(ids, vec![json!(1), json!(4)]);
+		assert_rebuilt(&p);
+	}
+
+	const RELATIONS: &str = r#"
+type Query { authors: [Author] }
+type Author { id: Int }
+type Post {
+  id: Int
+  author: Author @relation(onDelete: CASCADE)
+}
+type Comment {
+  id: Int
+  post: Post @relation(onDelete: SET_NULL)
+  authors: [Author]
+}
+"#;
+
+	#[test]
+	fn deletes_follow_references_through_reverse_indexes() {
+		let mut p = parser(
+			RELATIONS,
+			json!({
+				"Author": [{"id": 1}, {"id": 2}],
+				"Post": [{"id": 10, "author": 1}, {"id": 11, "author": 2}, {"id": 12, "author": 1}],
+				"Comment": [{"id": 20, "post": 10, "authors": [2]}, {"id": 21, "post": 11, "authors": [2, 2]}],
+			}),
+		);
+		// Comment.authors restricts, even when listing the author twice
+		let error = integrity::delete_record(&mut p, "Author", 1).unwrap_err();
+		assert_eq!(error, "Author 2 is still referenced by Comment 20 in authors");
+		assert_rebuilt(&p);
+		// Posts of author 1 are deleted, and unset from their comments
+		integrity::delete_record(&mut p, "Author", 0).unwrap();
+		assert_eq!(json!(p.database["Post"]), json!([{"id": 11, "author": 2}]));
+		assert_eq!(p.database["Comment"][0], json!({"id": 20, "post": null, "authors": [2]}));
+		assert_rebuilt(&p);
+	}
+
+	#[test]
+	#[should_panic(expected = "Post.author has unknown onDelete DELETE")]
+	fn unknown_on_delete_is_refused() {
+		parser(&RELATIONS.replace("CASCADE", "DELETE"), json!({}));
+	}
+
+	#[test]
+	fn computed_fields_are_parsed_with_the_schema() {
+		let sdl = r#"type Query { items: [Item] }
+type Item { id: Int
+ price: Int
+ quantity: Int
+ total: Int @computed(expr: "price * quantity")
+ label: String @computed(template: "{quantity} x {price}") }"#;
+		let p = parser(sdl, json!({"Item": [{"id": 1, "price": 3, "quantity": 2}]}));
+		let query = graphql_parser::parse_query("{ items { total label } }").unwrap();
+		let (values, _) = p.traverse_query(&query, &serde_json::Map::new());
+		assert_eq!(values, json!({"items": [{"total": 6, "label": "2 x 3"}]}));
+	}
+
+	#[test]
+	#[should_panic(expected = "Item.total has invalid expr: Unknown function `sum`")]
+	fn invalid_computed_expression_is_refused() {
+		parser("type Query { items: [Item] }\ntype Item { total: Int @computed(expr: \"sum(price)\") }", json!({}));
+	}
+
+	#[test]
+	fn type_linked_to_itself_has_two_join_keys() {
+		let sdl = r#"type Query { users: [User] }
+type User { id: Int
+ following: [User] @manyToMany(through: "Follow")
+ followers: [User] @manyToMany(through: "Follow", inverse: true) }"#;
+		let mut p = parser(
+			sdl,
+			json!({
+				"User": [{"id": 1}, {"id": 2}, {"id": 3}],
+				"Follow": [{"userFrom": 1, "userTo": 2}, {"userFrom": 3, "userTo": 1}],
+			}),
+		);
+		let query = graphql_parser::parse_query("{ users { id following { id } followers { id } } }").unwrap();
+		let (values, _) = p.traverse_query(&query, &serde_json::Map::new());
+		assert_eq!(
+			values,
+			json!({"users": [
+				{"id": 1, "following": [{"id": 2}], "followers": [{"id": 3}]},
+				{"id": 2, "following": [], "followers": [{"id": 1}]},
+				{"id": 3, "following": [{"id": 1}], "followers": []},
+			]})
+		);
+		// Links of either side are deleted with the user
+		integrity::delete_record(&mut p, "User", 0).unwrap();
+		assert!(p.database["Follow"].is_empty());
+		assert_rebuilt(&p);
+	}
+
+	#[test]
+	fn many_root_fields_keep_their_values() {
+		let p = parser(SCHEMA, json!({"Post": [{"id": 1, "title": "a"}, {"id": 2, "title": "b"}]}));
+		let text = (0..64).map(|i| format!("f{}: posts {{ id }} ", i)).collect::<String>();
+		let query = graphql_parser::parse_query(&format!("{{ {} }}", text)).unwrap();
+		let (values, errors) = p.traverse_query(&query, &serde_json::Map::new());
+		assert!(errors.is_empty());
+		for i in 0..64 {
+			assert_eq!(values[format!("f{}", i)], json!([{"id": 1}, {"id": 2}]));
+		}
+	}
+
+	#[test]
+	fn fragments_spreading_themselves_are_refused() {
+		let p = parser(SCHEMA, json!({"Post": [{"id": 1}]}));
+		let cyclic = "{ ...A } fragment A on Query { posts { ...B } } fragment B on Post { id ...C } fragment C on Post { ...B }";
+		let (values, errors) = p.traverse_query(&graphql_parser::parse_query(cyclic).unwrap(), &serde_json::Map::new());
+		assert_eq!(values, JSONValue::Null);
+		assert!(errors[0]["message"].as_str().unwrap().ends_with("spreads itself"));
+		// The same fragment twice is fine
+		let repeated = "{ ...A ...A } fragment A on Query { posts { ...B ...B } } fragment B on Post { id }";
+		let (values, errors) = p.traverse_query(&graphql_parser::parse_query(repeated).unwrap(), &serde_json::Map::new());
+		assert_eq!(values, json!({"posts": [{"id": 1}]}));
+		assert!(errors.is_empty());
+	}
+
+	#[test]
+	fn bulk_mutations_declare_their_arguments() {
+		let p = parser(SCHEMA, json!({}));
+		let mutation = match p.schema.find_object("Mutation") {
+			structure::StructureItem::Object(o) => o,
+			_ => unreachable!(),
+		};
+		let args = |name: &str| {
+			mutation
+				.find_field(name)
+				.unwrap()
+				.args
+				.iter()
+				.map(|a| (a.name.clone(), a.return_type.name.clone(), a.return_type.is_array, a.return_type.is_nullable))
+				.collect::<Vec<(String, String, bool, bool)>>()
+		};
+		let arg = |name: &str, kind: &str, is_array| (name.to_owned(), kind.to_owned(), is_array, false);
+		assert_eq!(args("createManyPost"), vec![arg("data", "PostData", true)]);
+		assert_eq!(args("updateManyPost"), vec![arg("where", "PostWhere", false), arg("data", "PostData", false)]);
+		assert_eq!(args("deleteManyPost"), vec![arg("where", "PostWhere", false)]);
+		assert_eq!(
+			args("upsertPost"),
+			vec![arg("where", "PostWhere", false), arg("create", "PostData", false), arg("update", "PostData", false)]
+		);
+		let data = p.schema.inputs.iter().find(|i| i.name == "PostData").unwrap();
+		let fields = data.fields.iter().map(|f| f.name.as_str()).collect::<Vec<&str>>();
+		assert_eq!(fields, vec!["id", "title", "authorId", "status"]);
+		assert!(p.schema.inputs.iter().any(|i| i.name == "AuthorWhere"));
+	}
+
+	#[test]
+	fn ids_are_generated_unless_strategy_is_none() {
+		let mut p = parser(SCHEMA, json!({"Post": [{"id": 1}]}));
+		let create = graphql_parser::parse_query("mutation { createPost(data: {title: \"a\"}) { id } }").unwrap();
+		let (values, errors) = p.traverse_mutation(&create, &serde_json::Map::new());
+		assert!(errors.is_empty());
+		assert_eq!(values, json!({"createPost": {"id": 2}}));
+
+		let mut p = parser(&SCHEMA.replace("id: Int\n  title", "id: Int @id(strategy: NONE)\n  title"), json!({}));
+		let (values, errors) = p.traverse_mutation(&create, &serde_json::Map::new());
+		assert_eq!(values, json!({"createPost": null}));
+		assert_eq!(errors[0]["message"], json!("Post requires a valid id, got null"));
+		let wrong = graphql_parser::parse_query("mutation { createPost(data: {id: \"x\"}) { id } }").unwrap();
+		let (_, errors) = p.traverse_mutation(&wrong, &serde_json::Map::new());
+		assert_eq!(errors[0]["message"], json!("Post requires a valid id, got \"x\""));
+	}
+
+	#[test]
+	fn invalid_filter_values_are_errors() {
+		let sdl = "scalar DateTime\ntype Query { events: [Event] }\ntype Event { id: Int\n at: DateTime }";
+		let p = parser(sdl, json!({"Event": [{"id": 1, "at": "2020-01-31T12:00:00.000Z"}]}));
+		let query = |text: &str| p.traverse_query(&graphql_parser::parse_query(text).unwrap(), &serde_json::Map::new());
+		// Filters are normalized like stored values
+		let (values, errors) = query("{ values__of_Event(at: \"2020-01-31T19:00:00+07:00\") { id } }");
+		assert_eq!(values, json!({"values__of_Event": [{"id": 1}]}));
+		assert!(errors.is_empty());
+		let (values, errors) = query("{ values__of_Event(at: \"2020-02-30T00:00:00Z\") { id } }");
+		assert_eq!(values, json!({"values__of_Event": null}));
+		assert_eq!(errors[0]["message"], json!("Event.at: \"2020-02-30T00:00:00Z\" is not a valid DateTime"));
+	}
+
+	#[test]
+	fn imports_check_references_once_all_records_are_in() {
+		let sdl = "type Query { people: [Person] }\ntype Person { id: Int\n name: String\n manager: Person }";
+		// A missing collection is replaced as an empty one
+		let mut p = parser(sdl, json!({}));
+		let records = vec![json!({"id": 1, "name": "a", "manager": 2}), json!({"id": 2, "name": "b"})];
+		assert_eq!(p.import_records("Person", records.clone(), true), Ok(2));
+		assert_eq!(p.database["Person"].len(), 2);
+
+		let mut p = parser(sdl, json!({}));
+		assert_eq!(p.import_records("Person", records, false), Ok(2));
+		let wrong = vec![json!({"id": 3, "manager": 1}), json!({"id": 4, "manager": 5})];
+		let error = p.import_records("Person", wrong, false).unwrap_err();
+		assert!(error.starts_with("Record 2: "), "{}", error);
+		assert_eq!(p.database["Person"].len(), 2, "nothing is imported");
+	}
+
+	#[test]
+	fn collections_are_loaded_when_first_read() {
+		let sdl = "type Query { posts: [Post] }\ntype Mutation { createPost(data: String): Post }\n\
+			type Author { id: Int\n name: String }\n\
+			type Post { id: Int\n title: String @searchable\n author: Author\n tags: [Tag] @manyToMany(through: \"PostTag\") }\n\
+			type Tag { id: Int\n name: String }\ntype Note { id: Int }";
+		let stored = json!({
+			"Author": [{"id": 1, "name": "Ann"}],
+			"Post": [{"id": 4, "title": "Hello world", "author": 1}],
+			"Tag": [{"id": 1, "name": "news"}],
+			"PostTag": [{"post": 4, "tag": 1}],
+			"Note": [{"id": 9}]
+		});
+		let mut p = parser(sdl, json!({}));
+		p.unloaded = ["Author", "Post", "Tag", "PostTag", "Note"].iter().map(|c| c.to_string()).collect();
+		p.loader = Some(Arc::new(move |class: &str| Ok(stored[class].as_array().cloned())));
+		let query = |text: &str| graphql_parser::parse_query(text).unwrap();
+
+		// Reading a type loads it with the types it references
+		let titles = query("{ search__of_Post(query: \"hello\") { title } }");
+		assert_eq!(p.unloaded_collections(&titles), vec!["Post"]);
+		p.load_for(&titles).unwrap();
+		let mut unloaded = p.unloaded.iter().cloned().collect::<Vec<String>>();
+		unloaded.sort();
+		assert_eq!(unloaded, vec!["Note", "PostTag", "Tag"]);
+		let (values, _) = p.traverse_query(&titles, &serde_json::Map::new());
+		assert_eq!(values, json!({"search__of_Post": [{"title": "Hello world"}]}));
+
+		let tags = query("query { ...F } fragment F on Query { values__of_Post { tags { name } } }");
+		assert_eq!(p.unloaded_collections(&tags), vec!["PostTag", "Tag"]);
+		p.load_for(&tags).unwrap();
+		let (values, _) = p.traverse_query(&tags, &serde_json::Map::new());
+		assert_eq!(values, json!({"values__of_Post": [{"tags": [{"name": "news"}]}]}));
+
+		// Mutations load the rest, and ids go on from the loaded ones
+		let create = query("mutation { createManyNote(data: [{}]) }");
+		assert_eq!(p.unloaded_collections(&create), vec!["Note"]);
+		p.load_for(&create).unwrap();
+		assert!(p.unloaded.is_empty());
+		p.traverse_mutation(&create, &serde_json::Map::new());
+		assert_eq!(p.database["Note"][1]["id"], json!(10));
+		assert!(p.verify_indexes());
+	}
+
+	#[test]
+	fn failed_mutations_roll_back_earlier_fields() {
+		let mut p = parser(SCHEMA, json!({"Post": [{"id": 1, "title": "a"}]}));
+		let before = p.database.clone();
+		let text = "mutation { a: createPost(data: {id: 2, title: \"b\"}) { id } \
+			b: updatePost(where: {id: 1}, data: {title: \"c\"}) { title } c: createPost(data: {id: 2}) { id } \
+			d: createPost(data: {id: 3}) { id } }";
+		let (values, errors) = p.traverse_mutation(&graphql_parser::parse_query(text).unwrap(), &serde_json::Map::new());
+		assert_eq!(values, json!({"a": null, "b": null, "c": null, "d": null}));
+		let messages = errors.iter().map(|e| e["message"].as_str().unwrap()).collect::<Vec<&str>>();
+		assert_eq!(messages.len(), 2, "{:?}", messages);
+		assert_eq!(errors[0]["path"], json!(["c"]));
+		assert_eq!(messages[1], "Mutation is rolled back, nothing has been applied");
+		assert_eq!(p.database, before);
+		assert!(p.verify_indexes());
+
+		// Opted out, the fields before and after the failed one stay applied
+		let best_effort = text.replacen("mutation {", "mutation @bestEffort {", 1);
+		let (values, errors) = p.traverse_mutation(&graphql_parser::parse_query(&best_effort).unwrap(), &serde_json::Map::new());
+		assert_eq!(values, json!({"a": {"id": 2}, "b": {"title": "c"}, "c": null, "d": {"id": 3}}));
+		assert_eq!(errors.len(), 1);
+		assert_eq!(errors[0]["path"], json!(["c"]));
+		let ids = p.database["Post"].iter().map(|r| r["id"].clone()).collect::<Vec<JSONValue>>();
+		assert_eq!(ids, vec![json!(1), json!(2), json!(3)]);
+		assert_eq!(p.database["Post"][0]["title"], json!("c"));
+		assert!(p.verify_indexes());
+	}
+
+	#[test]
+	fn defaults_fill_missing_fields() {
+		let sdl = "type Query { posts: [Post] }\n\
+			type Mutation { createPost(data: String): Post\n updatePost(data: String): Post\n deletePost(data: String): Post }\n\
+			enum Status { DRAFT PUBLISHED }\nscalar Date\n\
+			type Post { id: Int\n title: String @default(value: \"Untitled\")\n views: Int @default(value: 0)\n\
+			status: Status @default(value: DRAFT)\n number: Int @default(sequence: true)\n day: Date @default(now: true)\n\
+			createdAt: String @createdAt\n updatedAt: Int @updatedAt }";
+		let mut p = parser(sdl, json!({"Post": [{"id": 1, "number": 7}]}));
+		let mutate = |p: &mut QueryParser, text: &str| {
+			let (values, errors) = p.traverse_mutation(&graphql_parser::parse_query(text).unwrap(), &serde_json::Map::new());
+			assert!(errors.is_empty(), "{:?}", errors);
+			values
+		};
+		let start = utility::millis_since_epoch() as i64;
+		mutate(&mut p, "mutation { createPost(data: {}) { id } }");
+		let post = p.database["Post"][1].clone();
+		assert_eq!(post["id"], json!(2));
+		assert_eq!(post["title"], json!("Untitled"));
+		assert_eq!(post["views"], json!(0));
+		assert_eq!(post["status"], json!("DRAFT"));
+		assert_eq!(post["number"], json!(8), "sequences go on from stored values");
+		let days = [start, utility::millis_since_epoch() as i64].map(|t| json!(utility::format_datetime(t)[0..10]));
+		assert!(days.contains(&post["day"]), "{}", post["day"]);
+		let created = utility::parse_datetime(post["createdAt"].as_str().unwrap()).unwrap();
+		assert!(created >= start - start % 1000 && created <= utility::millis_since_epoch() as i64);
+		assert!(post["updatedAt"].as_i64().unwrap() >= start);
+
+		// Given values are kept, but updates always refresh @updatedAt
+		mutate(&mut p, "mutation { createPost(data: {title: \"Set\", views: 5, status: PUBLISHED, number: 20}) { id } }");
+		let given = &p.database["Post"][2];
+		assert_eq!((&given["title"], &given["views"], &given["status"], &given["number"]), (&json!("Set"), &json!(5), &json!("PUBLISHED"), &json!(20)));
+		std::thread::sleep(std::time::Duration::from_millis(5));
+		mutate(&mut p, "mutation { updatePost(where: {id: 2}, data: {title: \"New\", updatedAt: 1}) { id } }");
+		let updated = &p.database["Post"][1];
+		assert_eq!(updated["createdAt"], post["createdAt"]);
+		assert!(updated["updatedAt"].as_i64().unwrap() > post["updatedAt"].as_i64().unwrap());
+
+		// Sequences don't reuse the values of deleted records, also after they're saved and loaded again
+		mutate(&mut p, "mutation { deletePost(where: {id: 3}) { id } }");
+		let saved = serde_json::from_value::<HashMap<String, i64>>(json!(p.sequences)).unwrap();
+		let stored = p.database.iter().filter(|(k, _)| !k.starts_with("__")).collect::<HashMap<_, _>>();
+		let mut p = parser(sdl, json!(stored));
+		p.restore_sequences(saved);
+		mutate(&mut p, "mutation { createPost(data: {}) { id } }");
+		let post = p.database["Post"].last().unwrap();
+		assert_eq!((&post["id"], &post["number"]), (&json!(4), &json!(21)));
+	}
+
+	#[test]
+	fn stored_enum_values_are_translated() {
+		let sdl = "type Query { posts: [Post] }\n\
+			type Mutation { createPost(data: String): Post\n updatePost(data: String): Post }\n\
+			enum Status { DRAFT @stored(value: 0)\n PUBLISHED @stored(value: 1)\n OLD @stored(value: \"old\") }\n\
+			type Post { id: Int\n status: Status\n history: [Status] }";
+		let data = json!({"Post": [{"id": 1, "status": 1, "history": [0, 1]}, {"id": 2, "status": 0}]});
+		let mut p = parser(sdl, data);
+		let run = |p: &mut QueryParser, text: &str| {
+			let document = graphql_parser::parse_query(text).unwrap();
+			match text.starts_with("mutation") {
+				true => p.traverse_mutation(&document, &serde_json::Map::new()),
+				false => p.traverse_query(&document, &serde_json::Map::new()),
+			}
+		};
+
+		// Reads give names, filters take them
+		let (values, errors) = run(&mut p, "{ values__of_Post { id status history } }");
+		assert!(errors.is_empty(), "{:?}", errors);
+		let expected = json!([{"id": 1, "status": "PUBLISHED", "history": ["DRAFT", "PUBLISHED"]}, {"id": 2, "status": "DRAFT", "history": null}]);
+		assert_eq!(values["values__of_Post"], expected);
+		let (values, _) = run(&mut p, "{ values__of_Post(status: DRAFT) { id } }");
+		assert_eq!(values, json!({"values__of_Post": [{"id": 2}]}));
+		let (values, errors) = run(&mut p, "{ values__of_Post(status: GONE) { id } }");
+		assert_eq!(values, json!({"values__of_Post": null}));
+		assert_eq!(errors[0]["message"], json!("Post.status: Status has no value \"GONE\""));
+
+		// Writes store values
+		let (values, errors) = run(&mut p, "mutation { createPost(data: {id: 3, status: OLD, history: [PUBLISHED, DRAFT]}) { status history } }");
+		assert!(errors.is_empty(), "{:?}", errors);
+		assert_eq!(values, json!({"createPost": {"status": "OLD", "history": ["PUBLISHED", "DRAFT"]}}));
+		assert_eq!((&p.database["Post"][2]["status"], &p.database["Post"][2]["history"]), (&json!("old"), &json!([1, 0])));
+		run(&mut p, "mutation { updatePost(where: {status: OLD}, data: {status: PUBLISHED}) { id } }");
+		assert_eq!(p.database["Post"][2]["status"], json!(1));
+		let (_, errors) = run(&mut p, "mutation { createPost(data: {id: 4, status: 1}) { id } }");
+		assert_eq!(errors[0]["message"], json!("Post.status: Status has no value 1"));
+		assert_eq!(p.database["Post"].len(), 3);
+
+		// Values stored without a name are errors when read
+		p.database.get_mut("Post").unwrap()[0]["status"] = json!(7);
+		let (values, errors) = run(&mut p, "{ values__of_Post(id: 1) { id status } }");
+		assert_eq!(values, json!({"values__of_Post": [{"id": 1, "status": null}]}));
+		assert_eq!(errors[0]["message"], json!("Status has no value stored as 7"));
+	}
+
+	#[test]
+	fn unique_keys_refuse_duplicates() {
+		let sdl = "type Query { users: [User] }\n\
+			type Mutation { createUser(data: String): User\n updateUser(data: String): User }\n\
+			type User @index(fields: [\"team\", \"number\"], unique: true) { id: Int\n login: String @unique\n team: String\n number: Int }";
+		let data = json!({"User": [{"id": 1, "login": "ann", "team": "a", "number": 1}, {"id": 2, "login": "bob", "team": "a", "number": 2}]});
+		let mut p = parser(sdl, data);
+		let mut mutate = |text: &str| {
+			let (_, errors) = p.traverse_mutation(&graphql_parser::parse_query(text).unwrap(), &serde_json::Map::new());
+			errors.first().map(|e| e["message"].as_str().unwrap().to_owned())
+		};
+
+		// On create
+		let error = mutate("mutation { createUser(data: {login: \"ann\"}) { id } }");
+		assert_eq!(error.as_deref(), Some("User with (login) = (\"ann\") already exists"));
+		let error = mutate("mutation { createUser(data: {login: \"cid\", team: \"a\", number: 2}) { id } }");
+		assert_eq!(error.as_deref(), Some("User with (team,number) = (\"a\",2) already exists"));
+		let error = mutate("mutation { createManyUser(data: [{login: \"cid\"}, {login: \"cid\"}]) }");
+		assert!(error.unwrap().ends_with("User with (login) = (\"cid\") already exists"));
+		// Keys with a null part aren't checked
+		assert_eq!(mutate("mutation { createUser(data: {login: \"cid\", team: \"a\"}) { id } }"), None);
+
+		// On update, where keeping its own values is fine
+		let error = mutate("mutation { updateUser(where: {id: 2}, data: {login: \"ann\"}) { id } }");
+		assert_eq!(error.as_deref(), Some("User with (login) = (\"ann\") already exists"));
+		let error = mutate("mutation { updateUser(where: {id: 2}, data: {number: 1}) { id } }");
+		assert_eq!(error.as_deref(), Some("User with (team,number) = (\"a\",1) already exists"));
+		let kept = mutate("mutation { updateUser(where: {id: 2}, data: {login: \"bob\", team: \"a\", number: 2}) { id } }");
+		assert_eq!(kept, None);
+		assert_eq!(mutate("mutation { updateUser(where: {id: 2}, data: {login: \"ben\", number: 3}) { id } }"), None);
+		let logins = p.database["User"].iter().map(|r| r["login"].clone()).collect::<Vec<JSONValue>>();
+		assert_eq!(logins, vec![json!("ann"), json!("ben"), json!("cid")]);
+		assert!(p.verify_indexes());
 	}
 }
@@ -1,20 +1,33 @@
-use super::{parsing, structure};
+use super::{expression, indexing, integrity, parsing, scalar, schema, structure, utility};
 use serde_json::Value as JSONValue;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use graphql_parser::query::FragmentDefinition;
 
 pub type ResolverArgs = Vec<(String, graphql_parser::query::Value)>;
 
+// Mutations can fail with a message which ends up in "errors" of the response
+pub type ResolverResult = Result<JSONValue, String>;
+
 
 pub struct GenericResolverContext<'a> {
 	pub variables: &'a serde_json::Map<String, JSONValue>,
 	pub fragments: &'a HashMap<String, &'a FragmentDefinition>,
+	pub errors: &'a RefCell<Vec<JSONValue>>,
 }
 
 pub struct ResolverContext<'a> {
+	pub parser: &'a parsing::QueryParser,
+	pub variables: &'a serde_json::Map<String, JSONValue>,
+	pub fragments: &'a HashMap<String, &'a FragmentDefinition>,
+	pub errors: &'a RefCell<Vec<JSONValue>>,
+}
+
+pub struct MutationResolverContext<'a> {
 	pub parser: &'a mut parsing::QueryParser,
 	pub variables: &'a serde_json::Map<String, JSONValue>,
 	pub fragments: &'a HashMap<String, &'a FragmentDefinition>,
+	pub errors: &'a RefCell<Vec<JSONValue>>,
 }
 
 pub struct CanonicalResolverContext<'a> {
@@ -31,33 +44,211 @@ pub fn resolve(
 ) -> JSONValue {
 	match &info.data_type.resolver {
 		Some(v) => match v.kind.as_ref() {
-			"CREATE" => create_resolver(&parent, &args, &context, &info),
-			"UPDATE" => update_resolver(&parent, &args, &context, &info),
-			"DELETE" => delete_resolver(&parent, &args, &context, &info),
+			"CREATE" | "UPDATE" | "DELETE" | "CONNECT" | "DISCONNECT" | "CREATE_MANY" | "UPDATE_MANY"
+			| "DELETE_MANY" | "UPSERT" => JSONValue::Null, // only in resolve_mutation
 			"ALL_REFERENCES" => all_references_resolver(&parent, &args, &context, &info),
+			"SEARCH" => search_resolver(&parent, &args, &context, &info),
 			"SUBTITUTION" => subtitution_resolver(&parent, &args, &context, &info),
-			"DATA" | "BACKREFERENCE" | _ => data_resolver(&parent, &args, &context, &info),
+			"BACKREFERENCE" => backreference_resolver(&parent, &args, &context, &info),
+			"MANY_TO_MANY" => many_to_many_resolver(&parent, &args, &context, &info),
+			"UNLESS_DEPRECATED" => unless_deprecated_resolver(&parent, &args, &context, &info),
+			"DATA" | _ => data_resolver(&parent, &args, &context, &info),
 		},
 		_ => data_resolver(&parent, &args, &context, &info),
 	}
 }
 
+// A mutation field either applies entirely, or not at all
+pub fn resolve_mutation(
+	parent: &JSONValue,
+	args: &ResolverArgs,
+	context: &mut MutationResolverContext,
+	info: &structure::StructureField,
+) -> ResolverResult {
+	let savepoint = context.parser.begin();
+	let result = mutation_resolver(parent, args, context, info);
+	match result {
+		Ok(_) => context.parser.commit(),
+		Err(_) => context.parser.rollback(savepoint),
+	}
+	result
+}
+
+fn mutation_resolver(
+	parent: &JSONValue,
+	args: &ResolverArgs,
+	context: &mut MutationResolverContext,
+	info: &structure::StructureField,
+) -> ResolverResult {
+	match &info.data_type.resolver {
+		Some(v) => match v.kind.as_ref() {
+			"CREATE" => create_resolver(parent, args, context, info),
+			"UPDATE" => update_resolver(parent, args, context, info),
+			"DELETE" => delete_resolver(parent, args, context, info),
+			"CREATE_MANY" => create_many_resolver(parent, args, context, info),
+			"UPDATE_MANY" => update_many_resolver(parent, args, context, info),
+			"DELETE_MANY" => delete_many_resolver(parent, args, context, info),
+			"UPSERT" => upsert_resolver(parent, args, context, info),
+			"CONNECT" => connect_resolver(parent, args, context, info),
+			"DISCONNECT" => disconnect_resolver(parent, args, context, info),
+			_ => Ok(resolve(parent, args, &context.as_query(), info)),
+		},
+		_ => Ok(resolve(parent, args, &context.as_query(), info)),
+	}
+}
+
+impl<'a> MutationResolverContext<'a> {
+	pub fn as_query(&self) -> ResolverContext<'_> {
+		ResolverContext {
+			parser: self.parser,
+			variables: self.variables,
+			fragments: self.fragments,
+			errors: self.errors,
+		}
+	}
+}
+
+pub fn get_arg(
+	args: &ResolverArgs,
+	name: &str,
+	variables: &serde_json::Map<String, JSONValue>,
+) -> JSONValue {
+	match args.iter().find(|(k, _)| k == name) {
+		Some((_, v)) => utility::gql2serde_value(v, variables),
+		_ => JSONValue::Null,
+	}
+}
+
+// Arguments that match a field of the returned type, used as equality filters
+fn equality_filters(
+	args: &ResolverArgs,
+	context: &ResolverContext,
+	info: &structure::StructureField,
+) -> Vec<(String, JSONValue)> {
+	match context.parser.schema.find_object(&info.return_type.name) {
+		structure::StructureItem::Object(obj) => args
+			.iter()
+			.filter(|(name, _)| obj.find_field(name).is_some())
+			.map(|(name, value)| (name.clone(), utility::gql2serde_value(value, context.variables)))
+			.collect(),
+		_ => Vec::new(),
+	}
+}
+
 fn all_references_resolver(
 	_parent: &JSONValue,
+	args: &ResolverArgs,
+	context: &ResolverContext,
+	info: &structure::StructureField,
+) -> JSONValue {
+	let records = &context.parser.database[&info.return_type.name[..]];
+	let filters = equality_filters(args, context, info);
+	let positions = if filters.is_empty() {
+		(0..records.len()).collect()
+	} else {
+		match context.parser.find_positions(&info.return_type.name, &filters) {
+			Ok(v) => v,
+			Err(e) => {
+				context.errors.borrow_mut().push(json!({ "message": e }));
+				return JSONValue::Null;
+			}
+		}
+	};
+	if !info.return_type.is_array {
+		match positions.first() {
+			Some(i) => records[*i]["id"].clone(),
+			None => JSONValue::Null,
+		}
+	} else {
+		json!(positions
+			.iter()
+			.map(|i| records[*i]["id"].clone())
+			.collect::<Vec<JSONValue>>())
+	}
+}
+
+// Ranked ids matching the `query` argument against searchable fields
+fn search_resolver(
+	_parent: &JSONValue,
+	args: &ResolverArgs,
+	context: &ResolverContext,
+	info: &structure::StructureField,
+) -> JSONValue {
+	let query = get_arg(args, "query", context.variables);
+	let (index, records) = match (
+		context.parser.searches.get(&info.return_type.name),
+		context.parser.database.get(&info.return_type.name),
+	) {
+		(Some(i), Some(r)) => (i, r),
+		_ => return json!([]),
+	};
+	json!(index
+		.search(query.as_str().unwrap_or(""))
+		.iter()
+		.map(|(i, _)| records[*i]["id"].clone())
+		.collect::<Vec<JSONValue>>())
+}
+
+// Ids of records which reference field (the first flag) points to parent
+fn backreference_resolver(
+	parent: &JSONValue,
 	_args: &ResolverArgs,
 	context: &ResolverContext,
 	info: &structure::StructureField,
 ) -> JSONValue {
+	let field = match &info.data_type.resolver {
+		Some(v) => &v.flags[0],
+		_ => return JSONValue::Null,
+	};
+	let class = &info.return_type.name;
+	let mut positions = match context.parser.hashmaps.get(class) {
+		Some(Some(h)) => match h.get(&indexing::reverse_key(field)) {
+			Some(hash) => hash.find(&[&parent["id"]]).cloned().unwrap_or_default(),
+			None => Vec::new(),
+		},
+		_ => Vec::new(),
+	};
+	positions.sort();
+	let records = &context.parser.database[class];
 	if !info.return_type.is_array {
-		context.parser.database[&info.return_type.name[..]][0]["id"].clone()
+		match positions.first() {
+			Some(i) => records[*i]["id"].clone(),
+			None => JSONValue::Null,
+		}
 	} else {
-		json!(context.parser.database[&info.return_type.name[..]]
+		json!(positions
 			.iter()
-			.map(|x| x["id"].clone())
+			.map(|i| records[*i]["id"].clone())
 			.collect::<Vec<JSONValue>>())
 	}
 }
 
+// Flags are the join type, the key of parent's id, and the key of the returned id
+fn many_to_many_resolver(
+	parent: &JSONValue,
+	_args: &ResolverArgs,
+	context: &ResolverContext,
+	info: &structure::StructureField,
+) -> JSONValue {
+	let flags = match &info.data_type.resolver {
+		Some(v) => &v.flags,
+		_ => return JSONValue::Null,
+	};
+	let mut positions = match context.parser.hashmaps.get(&flags[0]) {
+		Some(Some(h)) => match h.get(&indexing::reverse_key(&flags[1])) {
+			Some(hash) => hash.find(&[&parent["id"]]).cloned().unwrap_or_default(),
+			None => Vec::new(),
+		},
+		_ => Vec::new(),
+	};
+	positions.sort();
+	let joins = &context.parser.database[&flags[0]];
+	json!(positions
+		.iter()
+		.map(|i| joins[*i][&flags[2]].clone())
+		.collect::<Vec<JSONValue>>())
+}
+
 fn data_resolver(
 	parent: &JSONValue,
 	_args: &ResolverArgs,
@@ -71,45 +262,609 @@ fn data_resolver(
 	}
 }
 
+// Instropection lists like `fields(includeDeprecated: true)`, which leave out
+// deprecated items unless asked
+fn unless_deprecated_resolver(
+	parent: &JSONValue,
+	args: &ResolverArgs,
+	context: &ResolverContext,
+	info: &structure::StructureField,
+) -> JSONValue {
+	let ids = data_resolver(parent, args, context, info);
+	if get_arg(args, "includeDeprecated", context.variables) == json!(true) {
+		return ids;
+	}
+	let class = &info.return_type.name;
+	match ids {
+		JSONValue::Array(arr) => json!(arr
+			.into_iter()
+			.filter(|id| {
+				let filters = [("id".to_owned(), id.clone())];
+				!context
+					.parser
+					.find_positions(class, &filters)
+					.unwrap_or_default()
+					.iter()
+					.any(|i| context.parser.database[class][*i]["isDeprecated"] == json!(true))
+			})
+			.collect::<Vec<JSONValue>>()),
+		v => v,
+	}
+}
+
+// Computed field: flags are the mode ("expr" or "template") and its source,
+// evaluated against the parent record
 fn subtitution_resolver(
-	_parent: &JSONValue,
+	parent: &JSONValue,
 	_args: &ResolverArgs,
-	_context: &ResolverContext,
-	_info: &structure::StructureField,
+	context: &ResolverContext,
+	info: &structure::StructureField,
 ) -> JSONValue {
-	println!("CREATE");
-	JSONValue::Null
+	let result = match &info.computed {
+		Some(e) => expression::evaluate(e, parent),
+		None => return JSONValue::Null,
+	};
+	match result {
+		Ok(v) => v,
+		Err(e) => {
+			context.errors.borrow_mut().push(json!({
+				"message": format!("Unable to compute {}: {}", info.name, e)
+			}));
+			JSONValue::Null
+		}
+	}
 }
 
 
+// Type name which a CREATE/UPDATE/DELETE field works on
+fn mutation_class(info: &structure::StructureField) -> String {
+	match &info.data_type.resolver {
+		Some(v) if !v.flags.is_empty() => v.flags[0].clone(),
+		_ => info.return_type.name.clone(),
+	}
+}
+
+// `where: {field: value}` as equality filters
+fn where_filters(
+	args: &ResolverArgs,
+	variables: &serde_json::Map<String, JSONValue>,
+) -> Vec<(String, JSONValue)> {
+	match get_arg(args, "where", variables) {
+		JSONValue::Object(obj) => obj.into_iter().collect(),
+		_ => Vec::new(),
+	}
+}
+
+// Operations allowed in place of a reference field value, e.g. `author: {connect: {id: 1}}`
+const NESTED_WRITES: [&str; 3] = ["disconnect", "connect", "create"];
+
+fn nested_writes(value: &JSONValue) -> Option<&serde_json::Map<String, JSONValue>> {
+	match value {
+		JSONValue::Object(ops)
+			if !ops.is_empty() && ops.keys().all(|k| NESTED_WRITES.contains(&k.as_str())) =>
+		{
+			Some(ops)
+		}
+		_ => None,
+	}
+}
+
+// Single operation arguments may also be given as a list of them
+fn nested_items(value: &JSONValue) -> Vec<&JSONValue> {
+	match value {
+		JSONValue::Array(arr) => arr.iter().collect(),
+		v => vec![v],
+	}
+}
+
+// The id of the first record of `class` matching a `where`-like object
+fn nested_connect(
+	context: &MutationResolverContext,
+	class: &str,
+	filters: &JSONValue,
+) -> ResolverResult {
+	let conditions = match filters {
+		JSONValue::Object(obj) if !obj.is_empty() => obj
+			.iter()
+			.map(|(k, v)| (k.clone(), v.clone()))
+			.collect::<Vec<(String, JSONValue)>>(),
+		_ => return Err(format!("connect to {} requires a `where`-like object", class)),
+	};
+	match context.parser.find_positions(class, &conditions)?.first() {
+		Some(p) => Ok(context.parser.database[class][*p]["id"].clone()),
+		None => Err(format!("No {} matches {}", class, filters)),
+	}
+}
+
+// Replace nested create/connect/disconnect operations in `record` with the ids they end up with.
+// Nested records are created before `record` itself, so they can be referenced.
+fn apply_nested_writes(
+	context: &mut MutationResolverContext,
+	class: &str,
+	record: &mut JSONValue,
+	current: &JSONValue,
+) -> Result<(), String> {
+	let fields = match context.parser.schema.find_object(class) {
+		structure::StructureItem::Object(o) => integrity::reference_fields(o, &context.parser.schema)
+			.into_iter()
+			.map(|f| (f.name.clone(), f.return_type.name.clone(), f.return_type.is_array))
+			.collect::<Vec<(String, String, bool)>>(),
+		_ => return Ok(()),
+	};
+	for (name, target, is_array) in fields {
+		let ops = match nested_writes(&record[&name]) {
+			Some(ops) => ops.clone(),
+			None => continue,
+		};
+		let mut ids = match &current[&name] {
+			JSONValue::Array(arr) if is_array => arr.clone(),
+			v if is_array && !v.is_null() => vec![v.clone()],
+			_ => Vec::new(),
+		};
+		let mut value = current[&name].clone();
+		for op in &NESTED_WRITES {
+			let arg = match ops.get(*op) {
+				Some(v) => v,
+				None => continue,
+			};
+			match (*op, is_array) {
+				// `disconnect: true` unsets a single reference
+				("disconnect", false) if arg == &json!(true) => value = JSONValue::Null,
+				("disconnect", false) => {
+					return Err(format!("disconnect of {}.{} takes `true`, got {}", class, name, arg))
+				}
+				("disconnect", true) => {
+					for filters in nested_items(arg) {
+						let id = nested_connect(context, &target, filters)?;
+						ids.retain(|x| x != &id);
+					}
+				}
+				("connect", _) => {
+					for filters in nested_items(arg) {
+						let id = nested_connect(context, &target, filters)?;
+						if !ids.contains(&id) {
+							ids.push(id.clone());
+						}
+						value = id;
+					}
+				}
+				_ => {
+					for data in nested_items(arg) {
+						let id = insert_nested(context, &target, data.clone())?;
+						ids.push(id.clone());
+						value = id;
+					}
+				}
+			}
+		}
+		record[&name] = if is_array { json!(ids) } else { value };
+	}
+	Ok(())
+}
+
+// Validate and insert a new record of `class`, returning its id
+fn insert_nested(context: &mut MutationResolverContext, class: &str, mut data: JSONValue) -> ResolverResult {
+	if !data.is_object() {
+		return Err(format!("create of {} requires a `data` object", class));
+	}
+	context.parser.apply_defaults(class, &mut data);
+	scalar::normalize_record(&context.parser.schema, class, &mut data)?;
+	apply_nested_writes(context, class, &mut data, &JSONValue::Null)?;
+	context.parser.check_unique(class, &data, None)?;
+	integrity::check_references(context.parser, class, &data)?;
+	let id = data["id"].clone();
+	context.parser.insert_record(class, data);
+	Ok(id)
+}
+
 fn create_resolver(
 	_parent: &JSONValue,
-	_args: &ResolverArgs,
-	_context: &ResolverContext,
-	_info: &structure::StructureField,
-) -> JSONValue {
-	println!("CREATE");
-	JSONValue::Null
+	args: &ResolverArgs,
+	context: &mut MutationResolverContext,
+	info: &structure::StructureField,
+) -> ResolverResult {
+	let class = mutation_class(info);
+	match get_arg(args, "data", context.variables) {
+		d @ JSONValue::Object(_) => insert_nested(context, &class, d),
+		_ => Err(format!("{} requires a `data` object", info.name)),
+	}
+}
+
+// Merge `data` into the record at `position`, returning its id
+fn update_nested(
+	context: &mut MutationResolverContext,
+	class: &str,
+	position: usize,
+	data: serde_json::Map<String, JSONValue>,
+) -> ResolverResult {
+	// Only the given values are converted, the others are stored already
+	let mut changes = JSONValue::Object(data);
+	context.parser.apply_updated_at(class, &mut changes);
+	scalar::normalize_record(&context.parser.schema, class, &mut changes)?;
+	let current = context.parser.database[class][position].clone();
+	let mut record = current.clone();
+	if let JSONValue::Object(changes) = changes {
+		for (key, value) in changes {
+			record[&key] = value;
+		}
+	}
+	apply_nested_writes(context, class, &mut record, &current)?;
+	context.parser.check_unique(class, &record, Some(position))?;
+	integrity::check_references(context.parser, class, &record)?;
+	integrity::check_id_change(context.parser, class, &current["id"], &record["id"])?;
+	let id = record["id"].clone();
+	context.parser.update_record(class, position, record);
+	Ok(id)
 }
 
 fn update_resolver(
 	_parent: &JSONValue,
-	_args: &ResolverArgs,
-	_context: &ResolverContext,
-	_info: &structure::StructureField,
-) -> JSONValue {
-	println!("UPDATE");
-	JSONValue::Null
+	args: &ResolverArgs,
+	context: &mut MutationResolverContext,
+	info: &structure::StructureField,
+) -> ResolverResult {
+	let class = mutation_class(info);
+	let filters = where_filters(args, context.variables);
+	let data = match get_arg(args, "data", context.variables) {
+		JSONValue::Object(d) => d,
+		_ => return Err(format!("{} requires a `data` object", info.name)),
+	};
+	match context.parser.find_positions(&class, &filters)?.first() {
+		Some(v) if !filters.is_empty() => update_nested(context, &class, *v, data),
+		_ => Ok(JSONValue::Null),
+	}
+}
+
+// Bulk mutations take `where` explicitly, `where: {}` to match every record
+fn bulk_positions(
+	args: &ResolverArgs,
+	context: &MutationResolverContext,
+	info: &structure::StructureField,
+) -> Result<Vec<usize>, String> {
+	match get_arg(args, "where", context.variables) {
+		JSONValue::Object(obj) => context
+			.parser
+			.find_positions(&mutation_class(info), &obj.into_iter().collect::<Vec<_>>()),
+		_ => Err(format!("{} requires a `where` object", info.name)),
+	}
+}
+
+// `createManyPost(data: [{...}, {...}])` inserts all records or none, returning the count
+fn create_many_resolver(
+	_parent: &JSONValue,
+	args: &ResolverArgs,
+	context: &mut MutationResolverContext,
+	info: &structure::StructureField,
+) -> ResolverResult {
+	let class = mutation_class(info);
+	let data = match get_arg(args, "data", context.variables) {
+		JSONValue::Array(arr) => arr,
+		_ => return Err(format!("{} requires a `data` list", info.name)),
+	};
+	let count = data.len();
+	for (i, record) in data.into_iter().enumerate() {
+		insert_nested(context, &class, record).map_err(|e| format!("data[{}]: {}", i, e))?;
+	}
+	Ok(json!(count))
+}
+
+// `updateManyPost(where: {...}, data: {...})` returns the count of updated records
+fn update_many_resolver(
+	_parent: &JSONValue,
+	args: &ResolverArgs,
+	context: &mut MutationResolverContext,
+	info: &structure::StructureField,
+) -> ResolverResult {
+	let class = mutation_class(info);
+	let positions = bulk_positions(args, context, info)?;
+	let data = match get_arg(args, "data", context.variables) {
+		JSONValue::Object(d) => d,
+		_ => return Err(format!("{} requires a `data` object", info.name)),
+	};
+	for position in &positions {
+		update_nested(context, &class, *position, data.clone())?;
+	}
+	Ok(json!(positions.len()))
+}
+
+// `deleteManyPost(where: {...})` returns the count of matched records deleted,
+// not counting the ones deleted by `@relation(onDelete: CASCADE)`
+fn delete_many_resolver(
+	_parent: &JSONValue,
+	args: &ResolverArgs,
+	context: &mut MutationResolverContext,
+	info: &structure::StructureField,
+) -> ResolverResult {
+	let class = mutation_class(info);
+	// Positions shift as records are deleted, so find each one again
+	let records = bulk_positions(args, context, info)?
+		.into_iter()
+		.map(|p| context.parser.database[&class][p].clone())
+		.collect::<Vec<JSONValue>>();
+	let mut count = 0;
+	for record in records {
+		let position = match &record["id"] {
+			JSONValue::Null => context.parser.database[&class].iter().position(|r| r == &record),
+			id => context
+				.parser
+				.find_positions(&class, &[("id".to_owned(), id.clone())])?
+				.first()
+				.cloned(),
+		};
+		if let Some(p) = position {
+			integrity::delete_record(context.parser, &class, p)?;
+			count += 1;
+		}
+	}
+	Ok(json!(count))
+}
+
+// `upsertPost(where: {...}, create: {...}, update: {...})` updates the first match, or creates
+fn upsert_resolver(
+	_parent: &JSONValue,
+	args: &ResolverArgs,
+	context: &mut MutationResolverContext,
+	info: &structure::StructureField,
+) -> ResolverResult {
+	let class = mutation_class(info);
+	let filters = where_filters(args, context.variables);
+	if filters.is_empty() {
+		return Err(format!("{} requires a `where` object", info.name));
+	}
+	match context.parser.find_positions(&class, &filters)?.first() {
+		Some(v) => match get_arg(args, "update", context.variables) {
+			JSONValue::Object(d) => update_nested(context, &class, *v, d),
+			_ => Err(format!("{} requires an `update` object", info.name)),
+		},
+		None => match get_arg(args, "create", context.variables) {
+			d @ JSONValue::Object(_) => insert_nested(context, &class, d),
+			_ => Err(format!("{} requires a `create` object", info.name)),
+		},
+	}
+}
+
+// Connect and disconnect returns the side of the link that has the returned type
+// (the second one for a type linked to itself), or the join record itself
+fn join_result(record: &JSONValue, info: &structure::StructureField) -> JSONValue {
+	let class = &info.return_type.name;
+	let (_, to) = schema::join_keys(class, class);
+	match (&record[&schema::join_key(class)], &record[&to]) {
+		(JSONValue::Null, JSONValue::Null) => record.clone(),
+		(JSONValue::Null, id) | (id, _) => id.clone(),
+	}
+}
+
+// `connectPostTag(data: {post: 1, tag: 2, ...metadata})`
+fn connect_resolver(
+	_parent: &JSONValue,
+	args: &ResolverArgs,
+	context: &mut MutationResolverContext,
+	info: &structure::StructureField,
+) -> ResolverResult {
+	let class = mutation_class(info);
+	let keys = match context.parser.schema.find_object(&class) {
+		structure::StructureItem::Object(o) => integrity::reference_fields(o, &context.parser.schema)
+			.iter()
+			.map(|f| f.name.clone())
+			.collect::<Vec<String>>(),
+		_ => return Err(format!("{} is not a join type", class)),
+	};
+	let data = match get_arg(args, "data", context.variables) {
+		d @ JSONValue::Object(_) => d,
+		_ => return Err(format!("{} requires a `data` object", info.name)),
+	};
+	let filters = keys
+		.iter()
+		.map(|k| (k.clone(), data[k].clone()))
+		.collect::<Vec<(String, JSONValue)>>();
+	if filters.iter().any(|(_, v)| v.is_null()) {
+		return Err(format!("{} requires {}", info.name, keys.join(" and ")));
+	}
+	if !context.parser.find_positions(&class, &filters)?.is_empty() {
+		return Err(format!("{} link already exists", class));
+	}
+	integrity::check_references(context.parser, &class, &data)?;
+	let result = join_result(&data, info);
+	context.parser.insert_record(&class, data);
+	Ok(result)
+}
+
+// `disconnectPostTag(where: {post: 1, tag: 2})` removes every matching link
+fn disconnect_resolver(
+	_parent: &JSONValue,
+	args: &ResolverArgs,
+	context: &mut MutationResolverContext,
+	info: &structure::StructureField,
+) -> ResolverResult {
+	let class = mutation_class(info);
+	let filters = where_filters(args, context.variables);
+	if filters.is_empty() {
+		return Ok(JSONValue::Null);
+	}
+	let mut positions = context.parser.find_positions(&class, &filters)?;
+	// Remove from the back, so swapped records are never the ones left to remove
+	positions.sort();
+	let mut result = JSONValue::Null;
+	for position in positions.into_iter().rev() {
+		result = join_result(&context.parser.remove_record(&class, position), info);
+	}
+	Ok(result)
 }
 
 fn delete_resolver(
 	_parent: &JSONValue,
-	_args: &ResolverArgs,
-	_context: &ResolverContext,
-	_info: &structure::StructureField,
-) -> JSONValue {
-	println!("DELETE");
-	JSONValue::Null
+	args: &ResolverArgs,
+	context: &mut MutationResolverContext,
+	info: &structure::StructureField,
+) -> ResolverResult {
+	let class = mutation_class(info);
+	let filters = where_filters(args, context.variables);
+	match context.parser.find_positions(&class, &filters)?.first() {
+		Some(v) if !filters.is_empty() => integrity::delete_record(context.parser, &class, *v),
+		_ => Ok(JSONValue::Null),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::{Arc, RwLock};
+
+	const SCHEMA: &str = r#"
+type Query {
+  authors: [Author]
+  posts: [Post]
 }
+type Mutation {
+  createPost(data: String): Post
+  updatePost(data: String): Post
+  updateAuthor(data: String): Author
+  updateNote(data: String): Note
+}
+type Author {
+  id: Int
+  name: String
+  posts: [Post] @backref(field: "author")
+  firstPost: Post @backref(field: "author")
+  edited: [Post] @backref(field: "editors")
+}
+type Post {
+  id: Int
+  title: String
+  author: Author
+  editors: [Author]
+}
+type Note {
+  id: Int
+  text: String
+}
+"#;
+
+	fn parser(data: JSONValue) -> parsing::QueryParser {
+		let sch = schema::traverse_schema("test", &graphql_parser::parse_schema(SCHEMA).unwrap());
+		let intros = schema::traverse_schema("instropection", &graphql_parser::schema::Document { definitions: vec![] });
+		let db = serde_json::from_value(data).unwrap();
+		parsing::QueryParser::new(db, sch, intros, Arc::new(RwLock::new(HashMap::new())))
+	}
+
+	fn blog() -> parsing::QueryParser {
+		parser(json!({
+			"Author": [{"id": 1, "name": "Ann"}, {"id": 2, "name": "Bob"}],
+			"Post": [{"id": 1, "title": "a", "author": 1}, {"id": 2, "title": "b", "author": 1}],
+			"Note": [{"id": 1, "text": "x"}]
+		}))
+	}
+
+	fn mutate(p: &mut parsing::QueryParser, text: &str) -> (JSONValue, Vec<JSONValue>) {
+		p.traverse_mutation(&graphql_parser::parse_query(text).unwrap(), &serde_json::Map::new())
+	}
+
+	fn query(p: &parsing::QueryParser, text: &str) -> (JSONValue, Vec<JSONValue>) {
+		p.traverse_query(&graphql_parser::parse_query(text).unwrap(), &serde_json::Map::new())
+	}
+
+	#[test]
+	fn ids_of_referenced_records_are_kept() {
+		let mut p = blog();
+		let (values, errors) = mutate(&mut p, "mutation { updateAuthor(where: {id: 1}, data: {id: 5}) { id } }");
+		assert_eq!(values, json!({"updateAuthor": null}));
+		assert_eq!(errors[0]["message"], json!("Author 1 can't change its id to 5, as Post 1 references it in author"));
+		assert_eq!(p.database["Author"][0]["id"], json!(1));
 
+		// Records nothing references may change it, as may those keeping the same id
+		let (_, errors) = mutate(&mut p, "mutation { updateAuthor(where: {id: 2}, data: {id: 6}) { id } }");
+		assert!(errors.is_empty(), "{:?}", errors);
+		let (_, errors) = mutate(&mut p, "mutation { updateAuthor(where: {id: 1}, data: {id: 1, name: \"Amy\"}) { id } }");
+		assert!(errors.is_empty(), "{:?}", errors);
+		let (values, _) = query(&p, "{ authors { id name } }");
+		assert_eq!(values, json!({"authors": [{"id": 1, "name": "Amy"}, {"id": 6, "name": "Bob"}]}));
 
+		// Once moved away, the author's id may change
+		let (_, errors) = mutate(&mut p, "mutation { a: updatePost(where: {id: 1}, data: {author: 6}) { id } \
+			b: updatePost(where: {id: 2}, data: {author: 6}) { id } c: updateAuthor(where: {id: 1}, data: {id: 5}) { id } }");
+		assert!(errors.is_empty(), "{:?}", errors);
+		assert!(p.verify_indexes());
+	}
+
+	#[test]
+	fn nested_writes_create_and_connect_records() {
+		let mut p = blog();
+		let (values, errors) = mutate(
+			&mut p,
+			"mutation { a: createPost(data: {id: 3, title: \"c\", author: {create: {id: 3, name: \"Cy\"}}, \
+			editors: {create: [{id: 4, name: \"Di\"}], connect: [{name: \"Bob\"}, {id: 1}]}}) { author { name } editors { id } } \
+			b: createPost(data: {id: 4, author: {connect: {name: \"Ann\"}}}) { author { id } } }",
+		);
+		assert!(errors.is_empty(), "{:?}", errors);
+		assert_eq!(values["a"], json!({"author": {"name": "Cy"}, "editors": [{"id": 2}, {"id": 1}, {"id": 4}]}));
+		assert_eq!(values["b"], json!({"author": {"id": 1}}));
+		assert_eq!(p.database["Author"].len(), 4);
+		assert!(p.verify_indexes());
+	}
+
+	#[test]
+	fn nested_writes_disconnect_records() {
+		let mut p = blog();
+		let (_, errors) = mutate(&mut p, "mutation { updatePost(where: {id: 1}, data: {editors: {connect: [{id: 1}, {id: 2}]}}) { id } }");
+		assert!(errors.is_empty(), "{:?}", errors);
+		let (values, errors) = mutate(
+			&mut p,
+			"mutation { updatePost(where: {id: 1}, data: {author: {disconnect: true}, editors: {disconnect: {id: 1}}}) { author { id } editors { id } } }",
+		);
+		assert!(errors.is_empty(), "{:?}", errors);
+		assert_eq!(values, json!({"updatePost": {"author": null, "editors": [{"id": 2}]}}));
+
+		// A single reference is only disconnected by `true`
+		let (values, errors) = mutate(&mut p, "mutation { updatePost(where: {id: 2}, data: {author: {disconnect: {id: 1}}}) { id } }");
+		assert_eq!(values, json!({"updatePost": null}));
+		assert_eq!(errors[0]["message"], json!("disconnect of Post.author takes `true`, got {\"id\":1}"));
+		assert_eq!(p.database["Post"][1]["author"], json!(1));
+		let (_, errors) = mutate(&mut p, "mutation { updatePost(where: {id: 2}, data: {editors: {disconnect: {id: 9}}}) { id } }");
+		assert_eq!(errors[0]["message"], json!("No Author matches {\"id\":9}"));
+	}
+
+	#[test]
+	fn failed_nested_writes_change_nothing() {
+		let mut p = blog();
+		let before = p.database.clone();
+		// The author is created before the post is refused for its duplicated id
+		let (values, errors) = mutate(
+			&mut p,
+			"mutation { createPost(data: {id: 1, title: \"dup\", author: {create: {id: 3, name: \"Cy\"}}, editors: {create: [{id: 4}]}}) { id } }",
+		);
+		assert_eq!(values, json!({"createPost": null}));
+		assert!(!errors.is_empty());
+		// A later nested write failing undoes the earlier ones of the same field
+		let (_, errors) = mutate(
+			&mut p,
+			"mutation { updatePost(where: {id: 2}, data: {editors: {create: [{id: 5}], connect: [{id: 9}]}}) { id } }",
+		);
+		assert_eq!(errors[0]["message"], json!("No Author matches {\"id\":9}"));
+		assert_eq!(p.database, before);
+		assert!(p.verify_indexes());
+	}
+
+	#[test]
+	fn back_references_list_referencing_records() {
+		let mut p = blog();
+		let (_, errors) = mutate(&mut p, "mutation { a: createPost(data: {id: 3, author: 2, editors: [1, 2]}) { id } \
+			b: updatePost(where: {id: 1}, data: {editors: [1]}) { id } }");
+		assert!(errors.is_empty(), "{:?}", errors);
+		let (values, errors) = query(&p, "{ authors { name posts { id } firstPost { id } edited { id } } }");
+		assert!(errors.is_empty(), "{:?}", errors);
+		assert_eq!(
+			values,
+			json!({"authors": [
+				{"name": "Ann", "posts": [{"id": 1}, {"id": 2}], "firstPost": {"id": 1}, "edited": [{"id": 1}, {"id": 3}]},
+				{"name": "Bob", "posts": [{"id": 3}], "firstPost": {"id": 3}, "edited": [{"id": 3}]}
+			]})
+		);
+
+		// They follow the references as they change
+		let (_, errors) = mutate(&mut p, "mutation { updatePost(where: {id: 1}, data: {author: 2}) { id } }");
+		assert!(errors.is_empty(), "{:?}", errors);
+		let (values, _) = query(&p, "{ posts(id: 1) { author { posts { id } } } authors(id: 1) { posts { id } firstPost { id } } }");
+		assert_eq!(values["posts"], json!([{"author": {"posts": [{"id": 1}, {"id": 3}]}}]));
+		assert_eq!(values["authors"], json!([{"posts": [{"id": 2}], "firstPost": {"id": 2}}]));
+	}
+}
@@ -0,0 +1,304 @@
+use super::{structure, utility};
+use serde_json::Value as JSONValue;
+
+// Leaf values: built-in scalars, and enums stored as other values than their names
+
+// Scalars with a built-in implementation, when declared by `scalar DateTime` etc.
+pub const BUILTIN_SCALARS: [&str; 7] = ["DateTime", "Date", "JSON", "Email", "URL", "UUID", "BigInt"];
+
+pub fn is_builtin(name: &str) -> bool {
+	BUILTIN_SCALARS.contains(&name)
+}
+
+// Validate a value of scalar `name`, returning the form it's stored and compared in.
+// Null is left to the nullability of the field.
+pub fn normalize(name: &str, value: &JSONValue) -> Result<JSONValue, String> {
+	if value.is_null() {
+		return Ok(JSONValue::Null);
+	}
+	let text = value.as_str();
+	let invalid = || format!("{} is not a valid {}", value, name);
+	match (name, text) {
+		// Kept in UTC, so those also sort as text
+		("DateTime", Some(t)) => utility::parse_datetime(t)
+			.map(|ms| json!(utility::format_datetime(ms)))
+			.ok_or_else(invalid),
+		("DateTime", None) => value
+			.as_i64()
+			.map(|ms| json!(utility::format_datetime(ms)))
+			.ok_or_else(invalid),
+		("Date", Some(t)) => match utility::parse_datetime(&format!("{}T00:00:00Z", t)) {
+			Some(ms) => Ok(json!(utility::format_datetime(ms)[0..10].to_owned())),
+			None => Err(invalid()),
+		},
+		("Email", Some(t)) => normalize_email(t).map(|x| json!(x)).ok_or_else(invalid),
+		("URL", Some(t)) => match url::Url::parse(t) {
+			Ok(u) if u.has_host() || u.scheme() == "mailto" => Ok(json!(u.as_str())),
+			_ => Err(invalid()),
+		},
+		("UUID", Some(t)) => normalize_uuid(t).map(|x| json!(x)).ok_or_else(invalid),
+		// Kept as text, so values beyond 64 bits don't lose precision
+		("BigInt", Some(t)) => normalize_bigint(t).map(|x| json!(x)).ok_or_else(invalid),
+		("BigInt", None) if value.is_i64() || value.is_u64() => Ok(json!(value.to_string())),
+		("JSON", _) => Ok(value.clone()),
+		_ => Err(invalid()),
+	}
+}
+
+// "Name@Example.COM" is stored as "Name@example.com", domains being case insensitive
+fn normalize_email(text: &str) -> Option<String> {
+	let at = text.rfind('@')?;
+	let (local, domain) = (&text[..at], &text[at + 1..]);
+	let valid = !local.is_empty()
+		&& !local.chars().any(|c| c.is_whitespace() || c == '@')
+		&& domain.contains('.')
+		&& !domain.starts_with('.')
+		&& !domain.ends_with('.')
+		&& domain.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '.');
+	if valid {
+		Some(format!("{}@{}", local, domain.to_lowercase()))
+	} else {
+		None
+	}
+}
+
+fn normalize_uuid(text: &str) -> Option<String> {
+	let groups = text.split('-').map(|g| g.len()).collect::<Vec<usize>>();
+	if groups == [8, 4, 4, 4, 12] && text.chars().all(|c| c == '-' || c.is_ascii_hexdigit()) {
+		Some(text.to_lowercase())
+	} else {
+		None
+	}
+}
+
+// "+007" is stored as "7", "-0" as "0"
+fn normalize_bigint(text: &str) -> Option<String> {
+	let (negative, digits) = match text.as_bytes().first() {
+		Some(b'-') => (true, &text[1..]),
+		Some(b'+') => (false, &text[1..]),
+		_ => (false, text),
+	};
+	if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+		return None;
+	}
+	let digits = digits.trim_start_matches('0');
+	Some(match (negative, digits) {
+		(_, "") => "0".to_owned(),
+		(true, d) => format!("-{}", d),
+		(false, d) => d.to_owned(),
+	})
+}
+
+// Name of the built-in scalar a field holds, if any
+pub fn field_scalar<'a>(
+	field: &'a structure::StructureField,
+	schema: &structure::StructureIndex,
+) -> Option<&'a str> {
+	let name = field.return_type.name.as_str();
+	match schema.find_object(name) {
+		structure::StructureItem::Scalar(_) if is_builtin(name) => Some(name),
+		_ => None,
+	}
+}
+
+// A leaf value of `field` as given in queries, converted to how it's stored:
+// built-in scalars are normalized and enum names are translated to their stored values
+fn to_stored(
+	schema: &structure::StructureIndex,
+	field: &structure::StructureField,
+	value: &JSONValue,
+) -> Result<JSONValue, String> {
+	map_leaves(schema, field, value, &|leaf| match schema.find_object(&field.return_type.name) {
+		structure::StructureItem::Enum(e) => e
+			.encode(leaf)
+			.ok_or_else(|| format!("{} has no value {}", e.name, leaf)),
+		_ => match field_scalar(field, schema) {
+			Some(name) => normalize(name, leaf),
+			None => Ok(leaf.clone()),
+		},
+	})
+}
+
+// Like to_stored, but for values already stored
+fn check_stored(
+	schema: &structure::StructureIndex,
+	field: &structure::StructureField,
+	value: &JSONValue,
+) -> Result<JSONValue, String> {
+	map_leaves(schema, field, value, &|leaf| match schema.find_object(&field.return_type.name) {
+		structure::StructureItem::Enum(e) => match e.decode(leaf) {
+			Some(_) => Ok(leaf.clone()),
+			None => Err(format!("{} has no value stored as {}", e.name, leaf)),
+		},
+		_ => match field_scalar(field, schema) {
+			Some(name) => normalize(name, leaf),
+			None => Ok(leaf.clone()),
+		},
+	})
+}
+
+// Apply `f` to each non-null item of array values, except for JSON which is taken whole
+fn map_leaves(
+	schema: &structure::StructureIndex,
+	field: &structure::StructureField,
+	value: &JSONValue,
+	f: &dyn Fn(&JSONValue) -> Result<JSONValue, String>,
+) -> Result<JSONValue, String> {
+	match value {
+		JSONValue::Null => Ok(JSONValue::Null),
+		JSONValue::Array(arr) if field_scalar(field, schema) != Some("JSON") => Ok(json!(arr
+			.iter()
+			.map(|x| if x.is_null() { Ok(JSONValue::Null) } else { f(x) })
+			.collect::<Result<Vec<JSONValue>, String>>()?)),
+		v => f(v),
+	}
+}
+
+// Fields holding enums or built-in scalars
+fn leaf_fields<'a>(
+	schema: &'a structure::StructureIndex,
+	class: &str,
+) -> Vec<&'a structure::StructureField> {
+	match schema.find_object(class) {
+		structure::StructureItem::Object(o) => o
+			.fields
+			.iter()
+			.filter(|f| match schema.find_object(&f.return_type.name) {
+				structure::StructureItem::Enum(_) => true,
+				_ => field_scalar(f, schema).is_some(),
+			})
+			.collect(),
+		_ => Vec::new(),
+	}
+}
+
+// Convert every enum and built-in scalar field of a record of `class` written by a mutation
+pub fn normalize_record(
+	schema: &structure::StructureIndex,
+	class: &str,
+	record: &mut JSONValue,
+) -> Result<(), String> {
+	for field in leaf_fields(schema, class) {
+		let stored = to_stored(schema, field, &record[&field.name])
+			.map_err(|e| format!("{}.{}: {}", class, field.name, e))?;
+		if !stored.is_null() {
+			record[&field.name] = stored;
+		}
+	}
+	Ok(())
+}
+
+// Equality filters compare stored values, so an invalid value is an error
+// rather than a filter matching nothing
+pub fn normalize_filters(
+	schema: &structure::StructureIndex,
+	class: &str,
+	filters: &mut [(String, JSONValue)],
+) -> Result<(), String> {
+	let fields = leaf_fields(schema, class);
+	for (name, value) in filters.iter_mut() {
+		if let Some(field) = fields.iter().find(|f| &f.name == name) {
+			*value = to_stored(schema, field, value).map_err(|e| format!("{}.{}: {}", class, name, e))?;
+		}
+	}
+	Ok(())
+}
+
+// Normalize stored values when loading, printing those which aren't valid
+pub fn normalize_database(
+	db: &mut std::collections::HashMap<String, Vec<JSONValue>>,
+	schema: &structure::StructureIndex,
+) {
+	for obj in &schema.objects {
+		let fields = leaf_fields(schema, &obj.name);
+		for record in db.get_mut(&obj.name).into_iter().flatten() {
+			for field in &fields {
+				match check_stored(schema, field, &record[&field.name]) {
+					Ok(JSONValue::Null) => {}
+					Ok(v) => record[&field.name] = v,
+					Err(e) => println!(
+						"Warning: {}.{}: {} (in record {})",
+						obj.name, field.name, e, record["id"]
+					),
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn accepts(name: &str, cases: &[(JSONValue, &str)]) {
+		for (value, stored) in cases {
+			assert_eq!(normalize(name, value), Ok(json!(stored)), "{} {}", name, value);
+		}
+	}
+
+	fn refuses(name: &str, values: &[JSONValue]) {
+		for value in values {
+			assert_eq!(normalize(name, value), Err(format!("{} is not a valid {}", value, name)));
+		}
+	}
+
+	#[test]
+	fn emails_keep_their_local_part() {
+		accepts("Email", &[(json!("Name@Example.COM"), "Name@example.com"), (json!("a.b+c@d-e.co.uk"), "a.b+c@d-e.co.uk")]);
+		let refused = ["", "ann", "@b.com", "a b@c.com", "a@b", "a@.b.com", "a@b.com.", "a@b_c.com", "a@@b.com"];
+		refuses("Email", &refused.iter().map(|t| json!(t)).collect::<Vec<JSONValue>>());
+		refuses("Email", &[json!(1), json!(true)]);
+	}
+
+	#[test]
+	fn urls_need_a_host() {
+		accepts(
+			"URL",
+			&[
+				(json!("HTTPS://Example.com"), "https://example.com/"),
+				(json!("http://example.com:8080/a?b=c#d"), "http://example.com:8080/a?b=c#d"),
+				(json!("mailto:ann@example.com"), "mailto:ann@example.com"),
+			],
+		);
+		refuses("URL", &[json!("example.com"), json!("/a/b"), json!("data:text/plain,x"), json!("http://"), json!(3)]);
+	}
+
+	#[test]
+	fn uuids_are_lowercased() {
+		accepts("UUID", &[(json!("123E4567-E89B-12D3-A456-426614174000"), "123e4567-e89b-12d3-a456-426614174000")]);
+		let refused = ["123e4567e89b12d3a456426614174000", "123e4567-e89b-12d3-a456-42661417400", "123e4567-e89b-12d3-a456-42661417400g"];
+		refuses("UUID", &refused.iter().map(|t| json!(t)).collect::<Vec<JSONValue>>());
+		refuses("UUID", &[json!(1)]);
+	}
+
+	#[test]
+	fn big_ints_are_kept_as_text() {
+		accepts(
+			"BigInt",
+			&[
+				(json!("+007"), "7"),
+				(json!("-0"), "0"),
+				(json!("-123456789012345678901234567890"), "-123456789012345678901234567890"),
+				(json!(-5), "-5"),
+				(json!(u64::MAX), "18446744073709551615"),
+			],
+		);
+		refuses("BigInt", &[json!(""), json!("-"), json!("1.5"), json!("1e3"), json!(" 1"), json!(1.5), json!(false)]);
+	}
+
+	#[test]
+	fn dates_are_kept_in_utc() {
+		accepts(
+			"DateTime",
+			&[
+				(json!("2020-01-31T19:00:00+07:00"), "2020-01-31T12:00:00.000Z"),
+				(json!("2020-02-29T00:00:00Z"), "2020-02-29T00:00:00.000Z"),
+				(json!(0), "1970-01-01T00:00:00.000Z"),
+			],
+		);
+		refuses("DateTime", &[json!("2020-02-30T00:00:00Z"), json!("2020-01-31"), json!("yesterday"), json!(1.5)]);
+		accepts("Date", &[(json!("2020-02-29"), "2020-02-29")]);
+		refuses("Date", &[json!("2021-02-29"), json!("2020-1-31"), json!("2020-01-31T00:00:00Z"), json!(0)]);
+		assert_eq!(normalize("Date", &JSONValue::Null), Ok(JSONValue::Null));
+	}
+}
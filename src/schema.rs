@@ -1,5 +1,7 @@
-use super::{parsing, structure};
-use graphql_parser::schema::{Definition, Document, ObjectType, Type, TypeDefinition};
+use super::{parsing, sdl, structure, utility};
+use graphql_parser::schema::{
+	Definition, Directive, Document, Field, InputValue, ObjectType, Type, TypeDefinition,
+};
 use serde_json::Value as JSONValue;
 use std::collections::HashMap;
 use std::ops::Deref;
@@ -9,6 +11,7 @@ pub fn get_field_type(t: &Type) -> structure::StructureReturnType {
 		is_array: false,
 		name: String::new(),
 		is_nullable: true,
+		is_item_required: false,
 	};
 	let mut t = t.clone();
 	loop {
@@ -17,8 +20,13 @@ pub fn get_field_type(t: &Type) -> structure::StructureReturnType {
 				r.is_array = true;
 				tt.deref().clone()
 			}
+			// `[Int!]!` is a required array of required items
 			Type::NonNullType(tt) => {
-				r.is_nullable = false;
+				if r.is_array {
+					r.is_item_required = true;
+				} else {
+					r.is_nullable = false;
+				}
 				tt.deref().clone()
 			}
 			Type::NamedType(name) => {
@@ -34,6 +42,7 @@ pub fn get_data_type(
 	t: &structure::StructureReturnType,
 	d: &str,
 	n: &str,
+	f: &str,
 ) -> structure::StructureDataType {
 	let mut data_type = match t.name.as_ref() {
 		"ID" => "string",
@@ -43,40 +52,169 @@ pub fn get_data_type(
 		"Boolean" => "bool",
 		n @ _ => n,
 	};
-	if d.contains("@type as i32;") {
-		data_type = "i32";
+	// e.g. "@type as i64;" to store an Int as 64-bit
+	if let Some(i) = d.find("@type as ") {
+		if let Some(kind) = d[i + 9..].split(';').next() {
+			data_type = kind.trim();
+		}
 	}
 	structure::StructureDataType {
 		resolver: match n {
+			// As printed by `gqldbms schema`
+			"Query" if f.starts_with("search__of_") => Some(structure::StructureDataResolver {
+				kind: "SEARCH".to_owned(),
+				args: Vec::default(),
+				flags: Vec::default(),
+			}),
 			"Query" => Some(structure::StructureDataResolver {
 				kind: "ALL_REFERENCES".to_owned(),
 				args: Vec::default(),
 				flags: Vec::default(),
 			}),
-			"Mutation" => match &n[0..6] {
-				"create" => Some(structure::StructureDataResolver {
+			"Mutation" if f.starts_with("connect") => Some(structure::StructureDataResolver {
+				kind: "CONNECT".to_owned(),
+				args: Vec::default(),
+				flags: vec![f[7..].to_owned()],
+			}),
+			"Mutation" if f.starts_with("disconnect") => Some(structure::StructureDataResolver {
+				kind: "DISCONNECT".to_owned(),
+				args: Vec::default(),
+				flags: vec![f[10..].to_owned()],
+			}),
+			// Bulk mutations return counts, which also tells `createManyX: Int` from `createManyX: ManyX`
+			"Mutation"
+				if t.name == "Int"
+					&& !t.is_array
+					&& matches!(f.get(0..10), Some("createMany" | "updateMany" | "deleteMany")) =>
+			{
+				Some(structure::StructureDataResolver {
+					kind: format!("{}_MANY", f[0..6].to_uppercase()),
+					args: Vec::default(),
+					flags: vec![f[10..].to_owned()],
+				})
+			}
+			"Mutation" if f.starts_with("upsert") => Some(structure::StructureDataResolver {
+				kind: "UPSERT".to_owned(),
+				args: Vec::default(),
+				flags: vec![f[6..].to_owned()],
+			}),
+			"Mutation" => match f.get(0..6) {
+				Some("create") => Some(structure::StructureDataResolver {
 					kind: "CREATE".to_owned(),
 					args: Vec::default(),
-					flags: vec![n[6..].to_owned()],
+					flags: vec![f[6..].to_owned()],
 				}),
-				"update" => Some(structure::StructureDataResolver {
+				Some("update") => Some(structure::StructureDataResolver {
 					kind: "UPDATE".to_owned(),
 					args: Vec::default(),
-					flags: vec![n[6..].to_owned()],
+					flags: vec![f[6..].to_owned()],
 				}),
-				"delete" => Some(structure::StructureDataResolver {
+				Some("delete") => Some(structure::StructureDataResolver {
 					kind: "DELETE".to_owned(),
 					args: Vec::default(),
-					flags: vec![n[6..].to_owned()],
+					flags: vec![f[6..].to_owned()],
 				}),
 				_ => None,
 			},
 			_ => None,
 		},
 		kind: data_type.to_owned(),
+		default: None,
+	}
+}
+
+// Read an argument of a schema directive as JSON (directives can't use variables)
+pub fn get_directive_arg(directive: &Directive, name: &str) -> JSONValue {
+	match directive.arguments.iter().find(|(k, _)| k == name) {
+		Some((_, v)) => utility::gql2serde_value(v, &serde_json::Map::new()),
+		_ => JSONValue::Null,
+	}
+}
+
+fn traverse_indexes(directives: &[Directive]) -> Vec<structure::StructureTypeIndex> {
+	directives
+		.iter()
+		.filter(|d| d.name == "index")
+		.map(|d| structure::StructureTypeIndex {
+			fields: match get_directive_arg(d, "fields") {
+				JSONValue::Array(arr) => arr
+					.iter()
+					.filter_map(|x| x.as_str().map(|x| x.to_owned()))
+					.collect(),
+				JSONValue::String(s) => vec![s],
+				_ => Vec::new(),
+			},
+			unique: get_directive_arg(d, "unique").as_bool().unwrap_or(false),
+		})
+		.filter(|i| !i.fields.is_empty())
+		.collect()
+}
+
+// Field of a join record which holds the id of `class`, e.g. "post" for Post
+pub fn join_key(class: &str) -> String {
+	let mut chars = class.chars();
+	match chars.next() {
+		Some(c) => c.to_lowercase().chain(chars).collect(),
+		None => String::new(),
+	}
+}
+
+// Fields of a join record holding the ids of both sides, e.g. ("post", "tag").
+// A type linked to itself gets ("userFrom", "userTo"), read from the first to the second.
+pub fn join_keys(from: &str, to: &str) -> (String, String) {
+	if from == to {
+		(join_key(from) + "From", join_key(to) + "To")
+	} else {
+		(join_key(from), join_key(to))
 	}
 }
 
+// Hidden types for join collections of many-to-many fields which aren't declared
+// in the schema. Join records are deleted along with either side.
+pub fn join_types(schema: &structure::StructureIndex) -> Vec<structure::StructureType> {
+	let mut joins: Vec<structure::StructureType> = Vec::new();
+	for obj in &schema.objects {
+		for field in &obj.fields {
+			let flags = match &field.data_type.resolver {
+				Some(r) if r.kind == "MANY_TO_MANY" => &r.flags,
+				_ => continue,
+			};
+			if let structure::StructureItem::Object(_) = schema.find_object(&flags[0]) {
+				continue;
+			}
+			let index = match joins.iter().position(|j| j.name == flags[0]) {
+				Some(i) => i,
+				None => {
+					joins.push(structure::StructureType {
+						name: flags[0].clone(),
+						description: String::new(),
+						fields: Vec::new(),
+						indexes: Vec::new(),
+						renamed_from: None,
+						is_hidden: true,
+						hashed_fields: HashMap::new(),
+					});
+					joins.len() - 1
+				}
+			};
+			for (key, class) in &[(&flags[1], &obj.name), (&flags[2], &field.return_type.name)] {
+				if joins[index].find_field(key).is_none() {
+					let mut side = structure::StructureField::from(
+						key.to_string(),
+						String::new(),
+						class.to_string(),
+						false,
+						None,
+					);
+					side.on_delete = Some("CASCADE".to_owned());
+					joins[index].add_field(side);
+				}
+			}
+		}
+	}
+	joins
+}
+
 fn traverse_object(object: &ObjectType) -> structure::StructureType {
 	let mut fields = Vec::new();
 	for field in &object.fields {
@@ -85,12 +223,108 @@ fn traverse_object(object: &ObjectType) -> structure::StructureType {
 			Some(v) => v.clone(),
 			_ => String::new(),
 		};
-		let data_type = get_data_type(&return_type, description.as_ref(), object.name.as_ref());
+		let mut data_type = get_data_type(
+			&return_type,
+			description.as_ref(),
+			object.name.as_ref(),
+			field.name.as_ref(),
+		);
+		// `posts: [Post] @backref(field: "author")` looks up Post.author
+		if let Some(d) = field.directives.iter().find(|d| d.name == "backref") {
+			if let Some(target) = get_directive_arg(d, "field").as_str() {
+				data_type.resolver = Some(structure::StructureDataResolver {
+					kind: "BACKREFERENCE".to_owned(),
+					args: Vec::default(),
+					flags: vec![target.to_owned()],
+				});
+			}
+		}
+		// `tags: [Tag] @manyToMany(through: "PostTag")` links through {"post": .., "tag": ..}
+		if let Some(d) = field.directives.iter().find(|d| d.name == "manyToMany") {
+			if let Some(through) = get_directive_arg(d, "through").as_str() {
+				let (mut from, mut to) = join_keys(&object.name, &return_type.name);
+				if get_directive_arg(d, "inverse").as_bool().unwrap_or(false) {
+					std::mem::swap(&mut from, &mut to);
+				}
+				data_type.resolver = Some(structure::StructureDataResolver {
+					kind: "MANY_TO_MANY".to_owned(),
+					args: Vec::default(),
+					flags: vec![through.to_owned(), from, to],
+				});
+			}
+		}
+		// `fullName: String @computed(template: "{firstName} {lastName}")`
+		// or `wordCount: Int @computed(expr: "words(body)")`
+		if let Some(d) = field.directives.iter().find(|d| d.name == "computed") {
+			for mode in &["expr", "template"] {
+				// Parsed by StructureIndex::perform_indexing, which refuses invalid ones
+				if let Some(source) = get_directive_arg(d, mode).as_str() {
+					data_type.resolver = Some(structure::StructureDataResolver {
+						kind: "SUBTITUTION".to_owned(),
+						args: Vec::default(),
+						flags: vec![mode.to_string(), source.to_owned()],
+					});
+				}
+			}
+		}
+		// `id: ID @id(strategy: ULID)` chooses how a missing id is generated on create.
+		// Ids are generated even without the directive: integer ids are INCREMENT and string ids
+		// are UUID. Only NONE leaves them missing, so creates without one fail check_unique.
+		if field.name == "id" {
+			let strategy = match field.directives.iter().find(|d| d.name == "id") {
+				Some(d) => get_directive_arg(d, "strategy").as_str().map(|x| x.to_owned()),
+				None => None,
+			};
+			let strategy = match (strategy.as_deref(), data_type.kind.as_ref()) {
+				(Some("NONE"), _) => None,
+				(Some(s @ "INCREMENT"), _) | (Some(s @ "UUID"), _) | (Some(s @ "ULID"), _) => Some(s),
+				(Some(s), _) => {
+					println!("Warning: {}.id has unknown strategy {}", object.name, s);
+					None
+				}
+				(None, "i32") | (None, "i64") | (None, "u64") => Some("INCREMENT"),
+				(None, "string") => Some("UUID"),
+				_ => None,
+			};
+			data_type.default = strategy.map(|s| structure::StructureDataDefault {
+				kind: s.to_owned(),
+				reference: JSONValue::Null,
+			});
+		}
+		// `@default(value: 0)`, `@default(now: true)` or `@default(sequence: true)` on create,
+		// `@createdAt` is set on create and `@updatedAt` on every create and update
+		if let Some(d) = field.directives.iter().find(|d| d.name == "default") {
+			let (kind, reference) = if get_directive_arg(d, "now") == json!(true) {
+				("NOW", JSONValue::Null)
+			} else if get_directive_arg(d, "sequence") == json!(true) {
+				("SEQUENCE", JSONValue::Null)
+			} else {
+				("LITERAL", get_directive_arg(d, "value"))
+			};
+			data_type.default = Some(structure::StructureDataDefault {
+				kind: kind.to_owned(),
+				reference,
+			});
+		}
+		for (name, kind) in &[("createdAt", "NOW"), ("updatedAt", "UPDATED_AT")] {
+			if field.directives.iter().any(|d| d.name == *name) {
+				data_type.default = Some(structure::StructureDataDefault {
+					kind: kind.to_string(),
+					reference: JSONValue::Null,
+				});
+			}
+		}
 		fields.push(structure::StructureField {
 			name: field.name.clone(),
 			description: description,
 			return_type: return_type,
 			data_type: data_type,
+			args: traverse_arguments(&field.arguments),
+			is_searchable: field.directives.iter().any(|d| d.name == "searchable"),
+			on_delete: on_delete(&object.name, field),
+			renamed_from: renamed_from(&field.directives),
+			deprecated: deprecated(&field.directives),
+			computed: None,
 		});
 	}
 	structure::StructureType {
@@ -101,14 +335,165 @@ fn traverse_object(object: &ObjectType) -> structure::StructureType {
 			.unwrap_or(&"".to_owned())
 			.clone(),
 		fields: fields,
+		indexes: traverse_indexes(&object.directives)
+			.into_iter()
+			.chain(
+				object
+					.fields
+					.iter()
+					.filter(|f| f.directives.iter().any(|d| d.name == "unique"))
+					.map(|f| structure::StructureTypeIndex {
+						fields: vec![f.name.clone()],
+						unique: true,
+					}),
+			)
+			.collect(),
+		renamed_from: renamed_from(&object.directives),
+		is_hidden: false,
 		hashed_fields: HashMap::new(),
 	}
 }
 
+// Reason of `@deprecated(reason: "Use name")` on fields and enum values
+fn deprecated(directives: &[Directive]) -> Option<String> {
+	let d = directives.iter().find(|d| d.name == "deprecated")?;
+	let reason = get_directive_arg(d, "reason");
+	Some(reason.as_str().unwrap_or("No longer supported").to_owned())
+}
+
+// Arguments of fields and directives, or fields of input objects
+fn traverse_arguments(arguments: &[InputValue]) -> Vec<structure::StructureArgument> {
+	arguments
+		.iter()
+		.map(|a| structure::StructureArgument {
+			name: a.name.clone(),
+			description: a.description.clone().unwrap_or_default(),
+			return_type: get_field_type(&a.value_type),
+			default: a
+				.default_value
+				.as_ref()
+				.map(|v| utility::gql2serde_value(v, &serde_json::Map::new())),
+		})
+		.collect()
+}
+
+// `@relation(onDelete:)` of a field. Anything else than the three actions is refused,
+// rather than silently restricting deletes
+fn on_delete(class: &str, field: &Field) -> Option<String> {
+	let action = field
+		.directives
+		.iter()
+		.find(|d| d.name == "relation")
+		.and_then(|d| get_directive_arg(d, "onDelete").as_str().map(|x| x.to_owned()));
+	match action.as_deref() {
+		None | Some("RESTRICT") | Some("CASCADE") | Some("SET_NULL") => action,
+		Some(v) => panic!(
+			"{}.{} has unknown onDelete {}, expected RESTRICT, CASCADE or SET_NULL",
+			class, field.name, v
+		),
+	}
+}
+
+// Stored fields of a type as optional arguments, e.g. `values__of_Post(authorId: Int)`.
+// References are given by the id of the record they point to.
+pub fn filter_arguments(
+	schema: &structure::StructureIndex,
+	obj: &structure::StructureType,
+) -> Vec<structure::StructureArgument> {
+	obj.fields
+		.iter()
+		.filter(|f| !f.return_type.is_array && f.data_type.resolver.is_none())
+		.filter_map(|f| {
+			let name = match schema.find_object(&f.return_type.name) {
+				structure::StructureItem::Object(o) => o.find_field("id")?.return_type.name.clone(),
+				structure::StructureItem::None
+					if !["Int", "Float", "String", "Boolean", "ID"].contains(&f.return_type.name.as_str()) =>
+				{
+					return None
+				}
+				_ => f.return_type.name.clone(),
+			};
+			Some(structure::StructureArgument {
+				name: f.name.clone(),
+				description: String::new(),
+				return_type: structure::StructureReturnType {
+					name,
+					is_array: false,
+					is_nullable: true,
+					is_item_required: false,
+				},
+				default: None,
+			})
+		})
+		.collect()
+}
+
+// Argument of a generated field, e.g. `where: PostWhere!`
+pub fn argument(name: &str, type_name: &str, is_array: bool, is_nullable: bool) -> structure::StructureArgument {
+	structure::StructureArgument {
+		name: name.to_owned(),
+		description: String::new(),
+		return_type: structure::StructureReturnType {
+			name: type_name.to_owned(),
+			is_array,
+			is_nullable,
+			// Lists of generated arguments are lists of records, never holding null
+			is_item_required: is_array,
+		},
+		default: None,
+	}
+}
+
+// Input types of the generated mutations of a type: `XWhere` holding the equality filters
+// of `where`, and `XData` holding the stored fields of `data`, all optional.
+// None when the type has nothing to filter or to store.
+pub fn mutation_inputs(
+	schema: &structure::StructureIndex,
+	obj: &structure::StructureType,
+) -> Option<(structure::StructureInput, structure::StructureInput)> {
+	let filters = filter_arguments(schema, obj);
+	let data = obj
+		.fields
+		.iter()
+		.filter(|f| f.data_type.resolver.is_none())
+		.filter_map(|f| {
+			let name = match schema.find_object(&f.return_type.name) {
+				structure::StructureItem::Object(o) => o.find_field("id")?.return_type.name.clone(),
+				_ => f.return_type.name.clone(),
+			};
+			let mut arg = argument(&f.name, &name, f.return_type.is_array, true);
+			arg.return_type.is_item_required = f.return_type.is_item_required;
+			Some(arg)
+		})
+		.collect::<Vec<structure::StructureArgument>>();
+	if filters.is_empty() || data.is_empty() {
+		return None;
+	}
+	Some((
+		structure::StructureInput {
+			name: format!("{}Where", obj.name),
+			description: String::new(),
+			fields: filters,
+		},
+		structure::StructureInput {
+			name: format!("{}Data", obj.name),
+			description: String::new(),
+			fields: data,
+		},
+	))
+}
+
+// `@renamed(from: "oldName")` on types and fields
+fn renamed_from(directives: &[Directive]) -> Option<String> {
+	let d = directives.iter().find(|d| d.name == "renamed")?;
+	get_directive_arg(d, "from").as_str().map(|x| x.to_owned())
+}
+
 pub fn traverse_schema(name: &str, doc: &Document) -> structure::StructureIndex {
 	let mut objects = Vec::new();
 	let mut enums = Vec::new();
 	let mut scalars = Vec::new();
+	let mut inputs = Vec::new();
 	for def in &doc.definitions {
 		match &def {
 			Definition::TypeDefinition(typedef) => match &typedef {
@@ -119,16 +504,35 @@ pub fn traverse_schema(name: &str, doc: &Document) -> structure::StructureIndex
 				TypeDefinition::Object(object) => {
 					objects.push(traverse_object(&object));
 				}
+				TypeDefinition::InputObject(input) => inputs.push(structure::StructureInput {
+					name: input.name.clone(),
+					description: input.description.clone().unwrap_or_default(),
+					fields: traverse_arguments(&input.fields),
+				}),
 				TypeDefinition::Enum(enu) => {
+					// `PUBLISHED @stored(value: 1)` stores the value as 1, otherwise as its name
 					let enus = enu
 						.values
 						.iter()
-						.map(|x| (x.name.clone(), json!(x.name)))
+						.map(|x| {
+							let stored = match x.directives.iter().find(|d| d.name == "stored") {
+								Some(d) => get_directive_arg(d, "value"),
+								None => json!(x.name),
+							};
+							(x.name.clone(), stored)
+						})
 						.collect::<HashMap<String, JSONValue>>();
+					let deprecated = enu
+						.values
+						.iter()
+						.filter_map(|x| Some((x.name.clone(), deprecated(&x.directives)?)))
+						.collect::<HashMap<String, String>>();
 					enums.push(structure::StructureEnum {
 						name: enu.name.clone(),
 						description: enu.description.as_ref().unwrap_or(&"".to_owned()).clone(),
 						values: enus,
+						deprecated,
+						order: enu.values.iter().map(|x| x.name.clone()).collect(),
 					});
 				}
 				_ => {}
@@ -141,64 +545,314 @@ pub fn traverse_schema(name: &str, doc: &Document) -> structure::StructureIndex
 		objects: objects,
 		enums: enums,
 		scalars: scalars,
+		inputs,
+		hash: String::new(),
 		hashed_objects: HashMap::new(),
 	})
 	.into_perform_indexing()
 }
 
+// Types and directives every schema has, as of the GraphQL spec. `instropection.gql` may add more.
+const INSTROPECTION: &str = r#"
+type __Schema {
+  id: ID
+  description: String
+  types: [__Type!]!
+  queryType: __Type!
+  mutationType: __Type
+  subscriptionType: __Type
+  directives: [__Directive!]!
+}
+type __Type {
+  id: ID
+  kind: __TypeKind!
+  name: String
+  description: String
+  fields(includeDeprecated: Boolean = false): [__Field!]
+  interfaces: [__Type!]
+  possibleTypes: [__Type!]
+  enumValues(includeDeprecated: Boolean = false): [__EnumValue!]
+  inputFields(includeDeprecated: Boolean = false): [__InputValue!]
+  ofType: __Type
+  specifiedByURL: String
+}
+enum __TypeKind {
+  SCALAR
+  OBJECT
+  INTERFACE
+  UNION
+  ENUM
+  INPUT_OBJECT
+  LIST
+  NON_NULL
+}
+type __Field {
+  id: ID
+  name: String!
+  description: String
+  args(includeDeprecated: Boolean = false): [__InputValue!]!
+  type: __Type!
+  isDeprecated: Boolean!
+  deprecationReason: String
+}
+type __InputValue {
+  id: ID
+  name: String!
+  description: String
+  type: __Type!
+  defaultValue: String
+  isDeprecated: Boolean!
+  deprecationReason: String
+}
+type __EnumValue {
+  id: ID
+  name: String!
+  description: String
+  isDeprecated: Boolean!
+  deprecationReason: String
+}
+type __Directive {
+  id: ID
+  name: String!
+  description: String
+  locations: [__DirectiveLocation!]!
+  args(includeDeprecated: Boolean = false): [__InputValue!]!
+  isRepeatable: Boolean!
+}
+enum __DirectiveLocation {
+  QUERY
+  MUTATION
+  SUBSCRIPTION
+  FIELD
+  FRAGMENT_DEFINITION
+  FRAGMENT_SPREAD
+  INLINE_FRAGMENT
+  VARIABLE_DEFINITION
+  SCHEMA
+  SCALAR
+  OBJECT
+  FIELD_DEFINITION
+  ARGUMENT_DEFINITION
+  INTERFACE
+  UNION
+  ENUM
+  ENUM_VALUE
+  INPUT_OBJECT
+  INPUT_FIELD_DEFINITION
+}
+
+"Applies every field of the mutation that succeeds, instead of all or none."
+directive @bestEffort on MUTATION
+"Builds a composite index, which is also a constraint when unique."
+directive @index(fields: [String!]!, unique: Boolean = false) repeatable on OBJECT
+"No two records may have the same value."
+directive @unique on FIELD_DEFINITION
+"What happens to this record when the one it references is deleted: RESTRICT, CASCADE or SET_NULL."
+directive @relation(onDelete: String = "RESTRICT") on FIELD_DEFINITION
+"Records of the field type which `field` references this record."
+directive @backref(field: String!) on FIELD_DEFINITION
+"Records of the field type linked to this record through join records of `through`."
+directive @manyToMany(through: String!, inverse: Boolean) on FIELD_DEFINITION
+"How a missing id is generated: INCREMENT, UUID, ULID or NONE."
+directive @id(strategy: String) on FIELD_DEFINITION
+"Value given to the field when a record is created without it."
+directive @default(value: Literal, now: Boolean, sequence: Boolean) on FIELD_DEFINITION
+"Time the record is created."
+directive @createdAt on FIELD_DEFINITION
+"Time the record is created or last updated."
+directive @updatedAt on FIELD_DEFINITION
+"Value the enum value is stored as."
+directive @stored(value: Literal!) on ENUM_VALUE
+"Marks an element as no longer supported."
+directive @deprecated(reason: String = "No longer supported") on FIELD_DEFINITION | ENUM_VALUE
+"Previous name of the type or field, so its data moves along when migrating."
+directive @renamed(from: String!) on OBJECT | FIELD_DEFINITION
+"Value computed from an expression or a template over sibling fields, never stored."
+directive @computed(expr: String, template: String) on FIELD_DEFINITION
+"Adds the field to the full-text index of its type."
+directive @searchable on FIELD_DEFINITION
+
+"A string, number or boolean, or the name of an enum value, as written in the schema."
+scalar Literal
+"#;
+
+// graphql_parser doesn't read `repeatable` yet: the definitions without it, and the names of
+// the directives declared so
+fn strip_repeatable(sdl: &str) -> (String, Vec<String>) {
+	let mut names = Vec::new();
+	let text = sdl
+		.lines()
+		.map(|line| match (line.strip_prefix("directive @"), line.find(" repeatable on ")) {
+			(Some(rest), Some(i)) => {
+				names.push(rest.split(['(', ' ']).next().unwrap_or_default().to_owned());
+				format!("{}{}", &line[..i], &line[i + " repeatable".len()..])
+			}
+			_ => line.to_owned(),
+		})
+		.collect::<Vec<String>>()
+		.join("\n");
+	(text, names)
+}
+
 pub struct InstropectionParser {
 	pub schema: structure::StructureIndex,
 	pub database: parsing::DatabaseIndex,
 }
 
+// Types made of wrappers around a named type, e.g. `[Int!]` is
+// {"kind": "LIST", "ofType": {"kind": "NON_NULL", "ofType": "Int"}}
+fn type_ref(t: &structure::StructureReturnType) -> JSONValue {
+	let wrap = |kind: &str, of: JSONValue| json!({ "kind": kind, "name": null, "ofType": of });
+	let mut r = json!(t.name);
+	if t.is_array {
+		if t.is_item_required {
+			r = wrap("NON_NULL", r);
+		}
+		r = wrap("LIST", r);
+	}
+	if !t.is_nullable {
+		r = wrap("NON_NULL", r);
+	}
+	r
+}
+
+fn description(text: &str) -> JSONValue {
+	if text.is_empty() {
+		JSONValue::Null
+	} else {
+		json!(text)
+	}
+}
+
+fn input_value(
+	id: String,
+	arg: &structure::StructureArgument,
+	enums: &[&structure::StructureEnum],
+) -> JSONValue {
+	let is_enum = enums.iter().any(|e| e.name == arg.return_type.name);
+	json!({
+		"id": id,
+		"name": arg.name,
+		"description": description(&arg.description),
+		"type": type_ref(&arg.return_type),
+		"defaultValue": arg.default.as_ref().map(|v| sdl::print_value(v, is_enum)),
+		"isDeprecated": false,
+		"deprecationReason": null,
+	})
+}
+
 pub fn build_schema_instropection(
 	doc: &structure::StructureIndex,
 	instropection: structure::StructureIndex,
 ) -> InstropectionParser {
+	let (builtin, repeatable) = strip_repeatable(INSTROPECTION);
+	let builtin = graphql_parser::parse_schema(&builtin).expect("Instropection schema is not valid");
+	// Declarations of instropection.gql complete the built-in ones
+	let mut schema = traverse_schema("instropection", &builtin);
+	for object in instropection.objects {
+		match schema.find_object_mut(&object.name) {
+			structure::StructureItemMut::Object(o) => {
+				for field in object.fields {
+					if o.find_field(&field.name).is_none() {
+						o.add_field(field);
+					}
+				}
+			}
+			_ => schema.add_object(object),
+		}
+	}
+	for object in instropection.enums {
+		if let structure::StructureItem::None = schema.find_object(&object.name) {
+			schema.add_enum(object);
+		}
+	}
+	// Lists with an `includeDeprecated` argument leave deprecated items out unless it's true
+	for object in schema.objects.iter_mut() {
+		for field in object.fields.iter_mut() {
+			if field.args.iter().any(|a| a.name == "includeDeprecated") {
+				field.data_type.resolver = Some(structure::StructureDataResolver {
+					kind: "UNLESS_DEPRECATED".to_owned(),
+					args: Vec::default(),
+					flags: Vec::default(),
+				});
+			}
+		}
+	}
+
 	let mut fields = Vec::new();
 	let mut types = Vec::new();
 	let mut enums = Vec::new();
+	let mut values = Vec::new();
+	let mut directives = Vec::new();
+	let all_enums = doc.enums.iter().chain(schema.enums.iter()).collect::<Vec<_>>();
 
-	for object in &doc.scalars {
+	// Scalars of directive arguments are built in, unless the schema declares one of that name
+	let builtin_scalars = schema.scalars.iter().filter(|s| !doc.scalars.iter().any(|x| x.name == s.name));
+	for object in doc.scalars.iter().chain(builtin_scalars) {
 		types.push(json!({
 			"id": object.name.clone(),
 			"name": object.name.clone(),
 			"kind": "SCALAR",
-			"description": &object.description,
-			"interfaces": []
+			"description": description(&object.description),
 		}));
 	}
 
-	for object in &doc.objects {
+	// Meta fields like `__schema` are implied rather than listed, and join types are hidden
+	for object in doc.objects.iter().chain(schema.objects.iter()).filter(|o| !o.is_hidden) {
 		let mut subfields = Vec::new();
-		for field in &object.fields {
+		for field in object.fields.iter().filter(|f| !f.name.starts_with("__")) {
+			let id = format!("{}.{}", object.name, field.name);
+			let mut args = Vec::new();
+			for arg in &field.args {
+				args.push(format!("{}.{}", id, arg.name));
+				values.push(input_value(format!("{}.{}", id, arg.name), arg, &all_enums));
+			}
 			fields.push(json!({
-				"id": format!("{}.{}", object.name, field.name),
+				"id": id,
 				"name": field.name,
-				"description": field.description,
-				"isDeprecated": false,
-				"args": [],
-				"type": field.return_type.name.clone(),
+				"description": description(&field.description),
+				"isDeprecated": field.deprecated.is_some(),
+				"deprecationReason": field.deprecated,
+				"args": args,
+				"type": type_ref(&field.return_type),
 			}));
-			subfields.push(object.name.clone() + "." + field.name.as_ref());
+			subfields.push(id);
 		}
 		types.push(json!({
 			"id": object.name.clone(),
 			"name": object.name.clone(),
 			"kind": "OBJECT",
-			"description": &object.description,
+			"description": description(&object.description),
 			"fields": subfields,
 			"interfaces": []
 		}));
 	}
 
-	for object in &doc.enums {
+	for object in &doc.inputs {
+		let mut subfields = Vec::new();
+		for field in &object.fields {
+			let id = format!("{}.{}", object.name, field.name);
+			values.push(input_value(id.clone(), field, &all_enums));
+			subfields.push(id);
+		}
+		types.push(json!({
+			"id": object.name.clone(),
+			"name": object.name.clone(),
+			"kind": "INPUT_OBJECT",
+			"description": description(&object.description),
+			"inputFields": subfields,
+		}));
+	}
+
+	for object in &all_enums {
 		let mut subvalues = Vec::new();
-		for (key, _) in &object.values {
+		for key in object.names() {
 			enums.push(json!({
 				"id": object.name.clone()+"."+ key.as_ref(),
 				"name": key.clone(),
-				"description": "".to_owned(),
+				"description": null,
+				"isDeprecated": object.deprecated.contains_key(key),
+				"deprecationReason": object.deprecated.get(key),
 			}));
 			subvalues.push(object.name.clone() + "." + key.as_ref());
 		}
@@ -206,45 +860,217 @@ pub fn build_schema_instropection(
 			"id": object.name.clone(),
 			"name": object.name.clone(),
 			"kind": "ENUM",
-			"description": &object.description,
+			"description": description(&object.description),
 			"enumValues": subvalues,
-			"interfaces": []
 		}));
 	}
 
-	let declared_types = types
-		.iter()
-		.map(|x| x["id"].clone())
-		.collect::<Vec<serde_json::Value>>();
-
 	for primitiv in vec!["ID", "Float", "Int", "String", "Boolean"] {
 		types.push(json!({
 			"id": primitiv.clone(),
 			"name": primitiv.clone(),
 			"kind": "SCALAR",
 			"description": null,
-			"interfaces": []
 		}));
 	}
+
+	for def in &builtin.definitions {
+		if let Definition::DirectiveDefinition(d) = def {
+			let id = format!("@{}", d.name);
+			let mut args = Vec::new();
+			for arg in traverse_arguments(&d.arguments) {
+				args.push(format!("{}.{}", id, arg.name));
+				values.push(input_value(format!("{}.{}", id, arg.name), &arg, &all_enums));
+			}
+			directives.push(json!({
+				"id": id,
+				"name": d.name,
+				"description": d.description,
+				"locations": d.locations.iter().map(|l| l.as_str()).collect::<Vec<&str>>(),
+				"args": args,
+				"isRepeatable": repeatable.contains(&d.name),
+			}));
+		}
+	}
+
+	let root = |name: &str| match doc.find_object(name) {
+		structure::StructureItem::Object(_) => json!(name),
+		_ => JSONValue::Null,
+	};
 	InstropectionParser {
-		schema: instropection,
 		database: [
 			(
 				"__Schema".to_owned(),
 				vec![json!({
 					"id": "__Schema",
+					"description": null,
 					"queryType": "Query",
-					"types": declared_types,
-					"directives": [],
-					"mutationType": null,
+					"mutationType": root("Mutation"),
+					"subscriptionType": root("Subscription"),
+					"types": types.iter().map(|x| x["id"].clone()).collect::<Vec<JSONValue>>(),
+					"directives": directives.iter().map(|x| x["id"].clone()).collect::<Vec<JSONValue>>(),
 				})],
 			),
 			("__Type".to_owned(), types),
 			("__Field".to_owned(), fields),
+			("__InputValue".to_owned(), values),
 			("__EnumValue".to_owned(), enums),
+			("__Directive".to_owned(), directives),
 		]
 		.iter()
 		.cloned()
 		.collect(),
+		schema,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::{Arc, RwLock};
+
+	// As sent by GraphiQL
+	const INTROSPECTION_QUERY: &str = r#"
+query IntrospectionQuery {
+  __schema {
+    queryType { name }
+    mutationType { name }
+    subscriptionType { name }
+    types { ...FullType }
+    directives { name description locations isRepeatable args { ...InputValue } }
+  }
+}
+fragment FullType on __Type {
+  kind
+  name
+  description
+  fields(includeDeprecated: true) {
+    name
+    description
+    args { ...InputValue }
+    type { ...TypeRef }
+    isDeprecated
+    deprecationReason
+  }
+  inputFields { ...InputValue }
+  interfaces { ...TypeRef }
+  enumValues(includeDeprecated: true) { name description isDeprecated deprecationReason }
+  possibleTypes { ...TypeRef }
+}
+fragment InputValue on __InputValue {
+  name
+  description
+  type { ...TypeRef }
+  defaultValue
+}
+fragment TypeRef on __Type {
+  kind
+  name
+  ofType {
+    kind
+    name
+    ofType {
+      kind
+      name
+      ofType {
+        kind
+        name
+        ofType { kind name ofType { kind name ofType { kind name ofType { kind name } } } }
+      }
+    }
+  }
+}
+"#;
+
+	const SCHEMA: &str = r#"
+scalar DateTime
+type Query { posts: [Post] }
+type Mutation { createPost(data: String): Post }
+enum Status { DRAFT PUBLISHED @deprecated }
+input PostFilter { status: Status, after: DateTime }
+type Author { id: Int
+ posts: [Post] @backref(field: "author") }
+type Post @index(fields: ["author", "status"]) { id: Int
+ title: String @searchable
+ status: Status!
+ at: DateTime
+ author: Author
+ tags: [Tag] @manyToMany(through: "PostTag") }
+type Tag { id: ID!
+ posts: [Post] @manyToMany(through: "PostTag") }
+"#;
+
+	// Named type at the end of a type reference, checking its wrappers on the way
+	fn named(t: &JSONValue) -> &str {
+		match t["kind"].as_str().unwrap() {
+			"NON_NULL" | "LIST" => {
+				assert!(t["name"].is_null(), "{}", t);
+				assert!(t["kind"] != "NON_NULL" || t["ofType"]["kind"] != "NON_NULL", "{}", t);
+				named(&t["ofType"])
+			}
+			_ => t["name"].as_str().unwrap(),
+		}
+	}
+
+	#[test]
+	fn graphiql_introspection_gives_a_valid_schema() {
+		let sch = traverse_schema("test", &graphql_parser::parse_schema(SCHEMA).unwrap());
+		let intros = traverse_schema("instropection", &Document { definitions: vec![] });
+		let p = parsing::QueryParser::new(HashMap::new(), sch, intros, Arc::new(RwLock::new(HashMap::new())));
+		let query = graphql_parser::parse_query(INTROSPECTION_QUERY).unwrap();
+		let (data, errors) = p.traverse_query(&query, &serde_json::Map::new());
+		assert!(errors.is_empty(), "{:?}", errors);
+		let schema = &data["__schema"];
+		assert_eq!(schema["queryType"], json!({"name": "Query"}));
+		assert_eq!(schema["mutationType"], json!({"name": "Mutation"}));
+		assert_eq!(schema["subscriptionType"], JSONValue::Null);
+
+		let types = schema["types"].as_array().unwrap();
+		let kinds = types
+			.iter()
+			.map(|t| (t["name"].as_str().unwrap(), t["kind"].as_str().unwrap()))
+			.collect::<HashMap<&str, &str>>();
+		assert_eq!(kinds.len(), types.len(), "type names are unique");
+		for name in &["Query", "Post", "__Schema", "__Type", "__Directive", "String", "Boolean", "Literal"] {
+			assert!(kinds.contains_key(name), "{} is listed", name);
+		}
+		let mut refs = Vec::new();
+		for t in types {
+			let (name, kind) = (t["name"].as_str().unwrap(), t["kind"].as_str().unwrap());
+			let is = |k: &str| kind == k;
+			assert_eq!(t["fields"].is_array(), is("OBJECT") || is("INTERFACE"), "fields of {}", name);
+			assert_eq!(t["inputFields"].is_array(), is("INPUT_OBJECT"), "inputFields of {}", name);
+			assert_eq!(t["enumValues"].is_array(), is("ENUM"), "enumValues of {}", name);
+			assert_eq!(t["interfaces"].is_array(), is("OBJECT"), "interfaces of {}", name);
+			assert!(t["possibleTypes"].is_null(), "possibleTypes of {}", name);
+			for field in t["fields"].as_array().into_iter().flatten() {
+				assert!(field["isDeprecated"].is_boolean());
+				refs.push((named(&field["type"]).to_owned(), true));
+				for arg in field["args"].as_array().unwrap() {
+					refs.push((named(&arg["type"]).to_owned(), false));
+				}
+			}
+			for field in t["inputFields"].as_array().into_iter().flatten() {
+				refs.push((named(&field["type"]).to_owned(), false));
+			}
+			if is("ENUM") {
+				assert!(!t["enumValues"].as_array().unwrap().is_empty(), "values of {}", name);
+			}
+		}
+		let deprecated = types.iter().find(|t| t["name"] == "Status").unwrap()["enumValues"][1].clone();
+		assert_eq!(deprecated["isDeprecated"], json!(true));
+		for directive in schema["directives"].as_array().unwrap() {
+			assert!(!directive["locations"].as_array().unwrap().is_empty());
+			assert_eq!(directive["isRepeatable"], json!(directive["name"] == "index"), "{}", directive["name"]);
+			for arg in directive["args"].as_array().unwrap() {
+				refs.push((named(&arg["type"]).to_owned(), false));
+			}
+		}
+		// Outputs are objects, enums or scalars, and inputs are input objects, enums or scalars
+		for (name, is_output) in refs {
+			let kind = kinds.get(name.as_str()).unwrap_or_else(|| panic!("{} is referenced but not listed", name));
+			let valid = if is_output { ["OBJECT", "ENUM", "SCALAR"] } else { ["INPUT_OBJECT", "ENUM", "SCALAR"] };
+			assert!(valid.contains(kind), "{} is a {}", name, kind);
+		}
 	}
 }
@@ -0,0 +1,289 @@
+use super::{schema, structure};
+use serde_json::Value as JSONValue;
+
+// Print a schema back as SDL, which reads into the same schema again.
+// Introspection types and hidden join types are left out, as those are made up on load.
+pub fn print_schema(schema: &structure::StructureIndex) -> String {
+	let mut blocks = Vec::new();
+	for obj in &schema.objects {
+		if obj.is_hidden || obj.name.starts_with("__") {
+			continue;
+		}
+		blocks.push(print_object(schema, obj));
+	}
+	for input in &schema.inputs {
+		blocks.push(print_input(schema, input));
+	}
+	for enu in schema.enums.iter().filter(|e| !e.name.starts_with("__")) {
+		blocks.push(print_enum(enu));
+	}
+	for scl in &schema.scalars {
+		blocks.push(format!("{}scalar {}\n", print_description(&scl.description, ""), scl.name));
+	}
+	blocks.join("\n")
+}
+
+fn print_object(schema: &structure::StructureIndex, obj: &structure::StructureType) -> String {
+	// Trailing single-field unique indexes in field order are what `@unique` gives,
+	// the others are kept on the type so they're read in the same order
+	let position = |i: &structure::StructureTypeIndex| match (i.unique, i.fields.as_slice()) {
+		(true, [f]) => obj.fields.iter().position(|x| &x.name == f),
+		_ => None,
+	};
+	let mut split = obj.indexes.len();
+	while split > 0 {
+		match (position(&obj.indexes[split - 1]), obj.indexes.get(split).and_then(position)) {
+			(Some(a), Some(b)) if a < b => split -= 1,
+			(Some(_), None) if split == obj.indexes.len() => split -= 1,
+			_ => break,
+		}
+	}
+	let mut out = print_description(&obj.description, "");
+	out.push_str("type ");
+	out.push_str(&obj.name);
+	for index in &obj.indexes[..split] {
+		let fields = index.fields.iter().map(|f| json!(f)).collect::<Vec<JSONValue>>();
+		out.push_str(&format!(" @index(fields: {}", print_value(&json!(fields), false)));
+		if index.unique {
+			out.push_str(", unique: true");
+		}
+		out.push(')');
+	}
+	if let Some(from) = &obj.renamed_from {
+		out.push_str(&format!(" @renamed(from: {})", json!(from)));
+	}
+	out.push_str(" {\n");
+	for field in &obj.fields {
+		let unique = obj.indexes[split..].iter().any(|i| i.fields[0] == field.name);
+		out.push_str(&print_field(schema, field, unique));
+	}
+	out.push_str("}\n");
+	out
+}
+
+fn print_field(schema: &structure::StructureIndex, field: &structure::StructureField, unique: bool) -> String {
+	let is_enum = |t: &structure::StructureReturnType| {
+		matches!(schema.find_object(&t.name), structure::StructureItem::Enum(_))
+	};
+	let mut out = print_description(&field.description, "  ");
+	out.push_str("  ");
+	out.push_str(&field.name);
+	if !field.args.is_empty() {
+		let args = field
+			.args
+			.iter()
+			.map(|a| {
+				let mut arg = String::new();
+				if !a.description.is_empty() {
+					arg.push_str(&format!("{} ", json!(a.description)));
+				}
+				arg.push_str(&print_argument(schema, a));
+				arg
+			})
+			.collect::<Vec<String>>();
+		out.push_str(&format!("({})", args.join(", ")));
+	}
+	out.push_str(": ");
+	out.push_str(&print_type(&field.return_type));
+	if field.name == "id" {
+		// Ids without `@id` are INCREMENT when integers, UUID when strings
+		let implied = match field.data_type.kind.as_ref() {
+			"i32" | "i64" | "u64" => Some("INCREMENT"),
+			"string" => Some("UUID"),
+			_ => None,
+		};
+		match (&field.data_type.default, implied) {
+			(Some(d), _)
+				if ["INCREMENT", "UUID", "ULID"].contains(&d.kind.as_str())
+					&& Some(d.kind.as_str()) != implied =>
+			{
+				out.push_str(&format!(" @id(strategy: {})", d.kind))
+			}
+			(None, Some(_)) => out.push_str(" @id(strategy: NONE)"),
+			_ => {}
+		}
+	}
+	match &field.data_type.default {
+		Some(d) if d.kind == "LITERAL" => out.push_str(&format!(
+			" @default(value: {})",
+			print_value(&d.reference, is_enum(&field.return_type))
+		)),
+		Some(d) if d.kind == "NOW" => out.push_str(" @default(now: true)"),
+		Some(d) if d.kind == "SEQUENCE" => out.push_str(" @default(sequence: true)"),
+		Some(d) if d.kind == "UPDATED_AT" => out.push_str(" @updatedAt"),
+		_ => {}
+	}
+	if unique {
+		out.push_str(" @unique");
+	}
+	if let Some(on_delete) = &field.on_delete {
+		out.push_str(&format!(" @relation(onDelete: {})", on_delete));
+	}
+	match &field.data_type.resolver {
+		Some(r) if r.kind == "BACKREFERENCE" => {
+			out.push_str(&format!(" @backref(field: {})", json!(r.flags[0])))
+		}
+		Some(r) if r.kind == "MANY_TO_MANY" => {
+			out.push_str(&format!(" @manyToMany(through: {}", json!(r.flags[0])));
+			// A type linked to itself, read from the second key
+			if schema::join_keys(&field.return_type.name, &field.return_type.name).1 == r.flags[1] {
+				out.push_str(", inverse: true");
+			}
+			out.push(')');
+		}
+		Some(r) if r.kind == "SUBTITUTION" => {
+			out.push_str(&format!(" @computed({}: {})", r.flags[0], json!(r.flags[1])))
+		}
+		_ => {}
+	}
+	if field.is_searchable {
+		out.push_str(" @searchable");
+	}
+	if let Some(from) = &field.renamed_from {
+		out.push_str(&format!(" @renamed(from: {})", json!(from)));
+	}
+	if let Some(reason) = &field.deprecated {
+		out.push_str(&format!(" @deprecated(reason: {})", json!(reason)));
+	}
+	out.push('\n');
+	out
+}
+
+// e.g. `status: Status = DRAFT`
+fn print_argument(schema: &structure::StructureIndex, arg: &structure::StructureArgument) -> String {
+	let mut out = format!("{}: {}", arg.name, print_type(&arg.return_type));
+	if let Some(v) = &arg.default {
+		let is_enum = matches!(schema.find_object(&arg.return_type.name), structure::StructureItem::Enum(_));
+		out.push_str(&format!(" = {}", print_value(v, is_enum)));
+	}
+	out
+}
+
+fn print_input(schema: &structure::StructureIndex, input: &structure::StructureInput) -> String {
+	let mut out = print_description(&input.description, "");
+	out.push_str(&format!("input {} {{\n", input.name));
+	for field in &input.fields {
+		out.push_str(&print_description(&field.description, "  "));
+		out.push_str(&format!("  {}\n", print_argument(schema, field)));
+	}
+	out.push_str("}\n");
+	out
+}
+
+fn print_enum(enu: &structure::StructureEnum) -> String {
+	let names = enu.names();
+	let mut out = print_description(&enu.description, "");
+	out.push_str(&format!("enum {} {{\n", enu.name));
+	for name in names {
+		out.push_str("  ");
+		out.push_str(name);
+		if enu.values[name] != json!(name) {
+			out.push_str(&format!(" @stored(value: {})", print_value(&enu.values[name], false)));
+		}
+		if let Some(reason) = enu.deprecated.get(name) {
+			out.push_str(&format!(" @deprecated(reason: {})", json!(reason)));
+		}
+		out.push('\n');
+	}
+	out.push_str("}\n");
+	out
+}
+
+fn print_description(description: &str, indent: &str) -> String {
+	if description.is_empty() {
+		return String::new();
+	}
+	format!("{}\"\"\"{}\"\"\"\n", indent, description.replace("\"\"\"", "\\\"\"\""))
+}
+
+// e.g. `[Int!]!`
+fn print_type(t: &structure::StructureReturnType) -> String {
+	let mut out = t.name.clone();
+	if t.is_array {
+		if t.is_item_required {
+			out.push('!');
+		}
+		out = format!("[{}]", out);
+	}
+	if !t.is_nullable {
+		out.push('!');
+	}
+	out
+}
+
+// A JSON value as GraphQL literal. Strings of enums are printed bare.
+pub fn print_value(v: &JSONValue, is_enum: bool) -> String {
+	match v {
+		JSONValue::String(s) if is_enum => s.clone(),
+		JSONValue::Array(arr) => format!(
+			"[{}]",
+			arr.iter().map(|x| print_value(x, is_enum)).collect::<Vec<String>>().join(", ")
+		),
+		JSONValue::Object(obj) => format!(
+			"{{{}}}",
+			obj.iter()
+				.map(|(k, x)| format!("{}: {}", k, print_value(x, false)))
+				.collect::<Vec<String>>()
+				.join(", ")
+		),
+		v => v.to_string(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::parsing;
+	use std::collections::HashMap;
+	use std::sync::{Arc, RwLock};
+
+	// Schema as served, with the fields the server adds
+	fn served(sdl: &str) -> structure::StructureIndex {
+		let sch = schema::traverse_schema("test", &graphql_parser::parse_schema(sdl).unwrap());
+		let intros = schema::traverse_schema("instropection", &graphql_parser::schema::Document { definitions: vec![] });
+		parsing::QueryParser::new(HashMap::new(), sch, intros, Arc::new(RwLock::new(HashMap::new()))).schema
+	}
+
+	const SCHEMA: &str = r#"
+type Query {
+  posts: [Post]
+}
+type Mutation {
+  createPost(data: String): Post
+}
+enum Status {
+  PUBLISHED @stored(value: 1)
+  DRAFT
+  ARCHIVED @deprecated(reason: "Use DRAFT")
+}
+type Post {
+  id: Int
+  title: String @searchable
+  status: Status @default(value: DRAFT)
+}
+"#;
+
+	#[test]
+	fn printed_schema_reads_back_the_same() {
+		let printed = print_schema(&served(SCHEMA));
+		let again = served(&printed);
+		assert_eq!(print_schema(&again), printed);
+		let query = match again.find_object("Query") {
+			structure::StructureItem::Object(o) => o,
+			_ => unreachable!(),
+		};
+		let kind = |name: &str| query.find_field(name).unwrap().data_type.resolver.as_ref().unwrap().kind.clone();
+		assert_eq!(kind("search__of_Post"), "SEARCH");
+		assert_eq!(kind("values__of_Post"), "ALL_REFERENCES");
+		assert!(printed.contains("  search__of_Post(query: String!): [Post]!\n"));
+		assert!(printed.contains("  values__of_Post(id: Int, title: String, status: Status): [Post]!\n"));
+		assert!(printed.contains("  upsertPost(where: PostWhere!, create: PostData!, update: PostData!): Post\n"));
+	}
+
+	#[test]
+	fn enum_values_keep_their_order() {
+		let printed = print_schema(&served(SCHEMA));
+		let expected = "enum Status {\n  PUBLISHED @stored(value: 1)\n  DRAFT\n  ARCHIVED @deprecated(reason: \"Use DRAFT\")\n}\n";
+		assert!(printed.contains(expected), "{}", printed);
+	}
+}
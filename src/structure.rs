@@ -1,3 +1,4 @@
+use super::expression;
 use serde_json::Value as JSONValue;
 use std::collections::HashMap;
 use serde_derive::{Serialize, Deserialize};
@@ -8,6 +9,12 @@ pub struct StructureIndex {
 	pub objects: Vec<StructureType>,
 	pub enums: Vec<StructureEnum>,
 	pub scalars: Vec<StructureScalar>,
+	// Input objects, only used as argument types
+	#[serde(default)]
+	pub inputs: Vec<StructureInput>,
+	// Hash of the schema.gql this is made from, to notice when it's edited
+	#[serde(default)]
+	pub hash: String,
 	#[serde(skip)]
 	pub hashed_objects: HashMap<String, (usize, usize)>,
 }
@@ -28,8 +35,16 @@ pub enum StructureItemMut<'a> {
 impl StructureIndex {
 	pub fn perform_indexing(&mut self) {
 		for (i, obj) in self.objects.iter_mut().enumerate() {
+			let class = &obj.name;
 			for (j, fld) in obj.fields.iter_mut().enumerate() {
 				obj.hashed_fields.insert(fld.name.clone(), j);
+				// Invalid expressions are refused when the schema loads, rather than on each read
+				if let Some(r) = fld.data_type.resolver.as_ref().filter(|r| r.kind == "SUBTITUTION") {
+					match expression::parse_computed(&r.flags[0], &r.flags[1]) {
+						Ok(e) => fld.computed = Some(e),
+						Err(e) => panic!("{}.{} has invalid {}: {}", class, fld.name, r.flags[0], e),
+					}
+				}
 			}
 			self.hashed_objects.insert(obj.name.clone(), (0, i));
 		}
@@ -78,16 +93,56 @@ pub struct StructureScalar {
 	pub description: String,
 }
 #[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct StructureInput {
+	pub name: String,
+	pub description: String,
+	pub fields: Vec<StructureArgument>,
+}
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct StructureEnum {
 	pub name: String,
 	pub description: String,
+	// GraphQL name to the value it's stored as
 	pub values: HashMap<String, JSONValue>,
+	// Reasons of values marked `@deprecated`
+	#[serde(default)]
+	pub deprecated: HashMap<String, String>,
+	// GraphQL names in declaration order
+	#[serde(default)]
+	pub order: Vec<String>,
+}
+impl StructureEnum {
+	// GraphQL names in declaration order, or sorted for schemas saved without it
+	pub fn names(&self) -> Vec<&String> {
+		if self.order.len() == self.values.len() {
+			return self.order.iter().collect();
+		}
+		let mut names = self.values.keys().collect::<Vec<&String>>();
+		names.sort();
+		names
+	}
+	// Stored value of a GraphQL name
+	pub fn encode(&self, name: &JSONValue) -> Option<JSONValue> {
+		name.as_str().and_then(|n| self.values.get(n)).cloned()
+	}
+	// GraphQL name of a stored value
+	pub fn decode(&self, stored: &JSONValue) -> Option<String> {
+		self.values.iter().find(|(_, v)| *v == stored).map(|(k, _)| k.clone())
+	}
 }
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct StructureType {
 	pub name: String,
 	pub description: String,
 	pub fields: Vec<StructureField>,
+	#[serde(default)]
+	pub indexes: Vec<StructureTypeIndex>,
+	// Previous name by `@renamed(from:)`, for migrations
+	#[serde(default)]
+	pub renamed_from: Option<String>,
+	// Made up by the server rather than declared, like join types of many-to-many fields
+	#[serde(skip)]
+	pub is_hidden: bool,
 	#[serde(skip)]
 	pub hashed_fields: HashMap<String, usize>,
 }
@@ -108,13 +163,54 @@ impl StructureType {
 			_ => None,
 		}
 	}
+	// Index keys that no two records may share: "id" then the unique indexes
+	pub fn unique_keys(&self) -> Vec<String> {
+		let mut keys = Vec::new();
+		if self.find_field("id").is_some() {
+			keys.push("id".to_owned());
+		}
+		for index in self.indexes.iter().filter(|i| i.unique) {
+			keys.push(index.key());
+		}
+		keys
+	}
 }
+
+// Declared by `@index(fields: [...], unique: Boolean)` on a type, or `@unique` on a field
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct StructureTypeIndex {
+	pub fields: Vec<String>,
+	pub unique: bool,
+}
+impl StructureTypeIndex {
+	// Key of this index inside DatabaseHashmaps, e.g. "authorId,status"
+	pub fn key(&self) -> String {
+		self.fields.join(",")
+	}
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct StructureField {
 	pub name: String,
 	pub description: String,
 	pub data_type: StructureDataType,
 	pub return_type: StructureReturnType,
+	#[serde(default)]
+	pub args: Vec<StructureArgument>,
+	#[serde(default)]
+	pub is_searchable: bool,
+	// For references: "RESTRICT" (default), "CASCADE" or "SET_NULL"
+	#[serde(default)]
+	pub on_delete: Option<String>,
+	// Previous name by `@renamed(from:)`, for migrations
+	#[serde(default)]
+	pub renamed_from: Option<String>,
+	// Reason of `@deprecated`, for fields kept only for older clients
+	#[serde(default)]
+	pub deprecated: Option<String>,
+	// Parsed `@computed` expression, so records don't parse it again on every read
+	#[serde(skip)]
+	pub computed: Option<expression::Expression>,
 }
 
 impl StructureField {
@@ -132,12 +228,20 @@ impl StructureField {
 					"Boolean" => "bool",
 					n @ _ => n,
 				}.to_string(),
+				default: None,
 			},
 			return_type: StructureReturnType {
 				name: kind,
 				is_array: array,
 				is_nullable: false,
+				is_item_required: false,
 			},
+			args: Vec::new(),
+			is_searchable: false,
+			on_delete: None,
+			renamed_from: None,
+			deprecated: None,
+			computed: None,
 		}
 	}
 }
@@ -146,6 +250,9 @@ impl StructureField {
 pub struct StructureDataType {
 	pub kind: String,
 	pub resolver: Option<StructureDataResolver>,
+	// Value given to a field missing on create
+	#[serde(default)]
+	pub default: Option<StructureDataDefault>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -153,8 +260,22 @@ pub struct StructureReturnType {
 	pub name: String,
 	pub is_array: bool,
 	pub is_nullable: bool,
+	// Whether items of an array can't be null, e.g. `[Int!]`
+	#[serde(default)]
+	pub is_item_required: bool,
+}
+
+// Argument of a field, e.g. `id: Int = 1` of `author(id: Int = 1): Author`
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct StructureArgument {
+	pub name: String,
+	pub description: String,
+	pub return_type: StructureReturnType,
+	pub default: Option<JSONValue>,
 }
 
+// Kind is "LITERAL" (the reference value), "NOW", "UPDATED_AT" (also refreshed on update),
+// "SEQUENCE", or for ids the strategy to generate them: "INCREMENT", "UUID" or "ULID"
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct StructureDataDefault {
 	pub kind: String,
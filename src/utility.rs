@@ -1,9 +1,15 @@
-use super::{parsing, schema, structure};
+use super::{binary, migration, parsing, schema, structure};
 use graphql_parser::query::Value as GraphValue;
 use serde_json::Value as JSONValue;
 use std::fs::File;
 use std::io::{Read, Write};
+use std::path::Path;
 use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn read_file(uri: &str) -> String {
 	let mut file = File::open(uri).expect(&format!("Unable to open `{}`", uri)[..]);
@@ -31,28 +37,295 @@ pub fn read_db_file(path: &str) -> String {
 	data
 }
 
-fn write_file(uri: &str, text: Vec<u8>) {
+pub fn write_file(uri: &str, text: Vec<u8>) {
 	let mut file = File::create(uri.clone()).expect(&format!("Unable to open `database/{}`", uri)[..]);
 	file.write_all(&text)
 		.expect(&format!("Unable to read `database/{}` (Invalid UTF-8 file?)", uri)[..]);
 }
 
-pub fn gql2serde_value(v: &GraphValue) -> JSONValue {
+pub fn gql2serde_value(v: &GraphValue, variables: &serde_json::Map<String, JSONValue>) -> JSONValue {
 	match v {
 		GraphValue::Boolean(b) => json!(b),
 		GraphValue::Int(i) => json!(i.as_i64()),
 		GraphValue::Float(f) => json!(f),
 		GraphValue::String(s) => json!(s),
-		GraphValue::Variable(s) => json!(s),
+		GraphValue::Enum(s) => json!(s),
+		GraphValue::Variable(s) => variables.get(s).cloned().unwrap_or(JSONValue::Null),
+		GraphValue::List(l) => json!(l
+			.iter()
+			.map(|x| gql2serde_value(x, variables))
+			.collect::<Vec<JSONValue>>()),
+		GraphValue::Object(o) => json!(o
+			.iter()
+			.map(|(k, x)| (k.clone(), gql2serde_value(x, variables)))
+			.collect::<serde_json::Map<String, JSONValue>>()),
 		_ => json!(null),
 	}
 }
 
-fn read_database(db: &str) -> parsing::DatabaseIndex {
+pub fn millis_since_epoch() -> u128 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_millis())
+		.unwrap_or(0)
+}
+
+// Milliseconds since epoch as UTC "2020-01-31T12:34:56.789Z"
+pub fn format_datetime(millis: i64) -> String {
+	let (days, rest) = (millis.div_euclid(86_400_000), millis.rem_euclid(86_400_000));
+	// Civil date from days since epoch (Howard Hinnant's algorithm)
+	let z = days + 719_468;
+	let era = z.div_euclid(146_097);
+	let doe = z.rem_euclid(146_097);
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+	format!(
+		"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+		year,
+		month,
+		day,
+		rest / 3_600_000,
+		rest / 60_000 % 60,
+		rest / 1000 % 60,
+		rest % 1000
+	)
+}
+
+// Milliseconds since epoch of "2020-01-31T12:34:56.789+07:00" (seconds, fraction and
+// offset are optional, no offset is UTC), or None when it's not such a date and time
+pub fn parse_datetime(text: &str) -> Option<i64> {
+	let number = |s: &str| -> Option<i64> {
+		if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) {
+			s.parse().ok()
+		} else {
+			None
+		}
+	};
+	let (date, time) = (text.get(0..10)?, text.get(11..)?);
+	if !text.is_ascii()
+		|| !matches!(text.as_bytes()[10], b'T' | b't' | b' ') || &date[4..5] != "-" || &date[7..8] != "-" {
+		return None;
+	}
+	let (year, month, day) = (number(&date[0..4])?, number(&date[5..7])?, number(&date[8..10])?);
+	let (clock, offset) = match time.find(['Z', 'z', '+', '-']) {
+		Some(i) => time.split_at(i),
+		None => (time, ""),
+	};
+	let offset_minutes = match offset {
+		"" | "Z" | "z" => 0,
+		o if o.len() == 6 && &o[3..4] == ":" => {
+			let minutes = number(&o[1..3])? * 60 + number(&o[4..6])?;
+			if o.starts_with('-') { -minutes } else { minutes }
+		}
+		_ => return None,
+	};
+	let (clock, fraction) = match clock.find('.') {
+		Some(i) if i + 1 < clock.len() => (&clock[..i], &clock[i + 1..]),
+		Some(_) => return None,
+		None => (clock, "000"),
+	};
+	let parts = clock.split(':').map(number).collect::<Option<Vec<i64>>>()?;
+	let (hour, minute, second) = match parts.as_slice() {
+		[h, m] => (*h, *m, 0),
+		[h, m, s] => (*h, *m, *s),
+		_ => return None,
+	};
+	let millis = number(format!("{:0<3}", fraction).get(0..3)?)?;
+	let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+	let month_days = [31, if leap { 29 } else { 28 }, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+	if !(1..=12).contains(&month) || day < 1 || day > month_days[month as usize - 1]
+		|| hour > 23 || minute > 59 || second > 59
+	{
+		return None;
+	}
+	// Days since epoch from the civil date (Howard Hinnant's algorithm)
+	let y = if month <= 2 { year - 1 } else { year };
+	let era = y.div_euclid(400);
+	let yoe = y.rem_euclid(400);
+	let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+	let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+	let days = era * 146_097 + doe - 719_468;
+	Some(((days * 24 + hour) * 60 + minute - offset_minutes) * 60_000 + second * 1000 + millis)
+}
+
+// Random bits without extra dependencies: SipHash with the randomly seeded keys of RandomState
+pub fn random_u64() -> u64 {
+	static COUNTER: AtomicU64 = AtomicU64::new(0);
+	let mut hasher = RandomState::new().build_hasher();
+	hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+	hasher.write_u128(millis_since_epoch());
+	hasher.finish()
+}
+
+// Random (version 4) UUID, e.g. "0b6a4f5e-3c1d-4f8a-9d2e-7f1a2b3c4d5e"
+pub fn uuid_v4() -> String {
+	let bits = ((random_u64() as u128) << 64) | random_u64() as u128;
+	let bits = (bits & !(0xf << 76) & !(0x3 << 62)) | (0x4 << 76) | (0x2 << 62);
+	let hex = format!("{:032x}", bits);
+	format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+// ULID: 48 bits of milliseconds then 80 random bits, in Crockford's base32.
+// Those sort by creation time.
+pub fn ulid() -> String {
+	const ALPHABET: &[u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+	let random = (((random_u64() as u128) << 64) | random_u64() as u128) & ((1 << 80) - 1);
+	let bits = ((millis_since_epoch() & ((1 << 48) - 1)) << 80) | random;
+	(0..26)
+		.map(|i| ALPHABET[((bits >> (5 * (25 - i))) & 31) as usize] as char)
+		.collect()
+}
+
+pub fn read_database(db: &str) -> parsing::DatabaseIndex {
 	let data = read_file(db);
 	serde_json::from_str(&data).expect("File `database/data.json` is not valid JSON object!")
 }
 
+// Where a database is stored: `data.bin` in the binary format, a `data` directory with a JSON
+// file per type in the split format, else `data.json`
+pub fn data_path(name: &str) -> String {
+	let (bin_path, dir_path) = (format!("database/{}/data.bin", name), format!("database/{}/data", name));
+	if std::fs::metadata(&bin_path).is_ok() {
+		bin_path
+	} else if std::fs::metadata(&dir_path).is_ok_and(|m| m.is_dir()) {
+		dir_path
+	} else {
+		format!("database/{}/data.json", name)
+	}
+}
+
+fn format_path(name: &str, format: &str) -> Result<String, String> {
+	match format {
+		"json" => Ok(format!("database/{}/data.json", name)),
+		"binary" => Ok(format!("database/{}/data.bin", name)),
+		"split" => Ok(format!("database/{}/data", name)),
+		f => Err(format!("Unknown storage format {}, use json, binary or split", f)),
+	}
+}
+
+fn is_split(path: &str) -> bool {
+	!path.ends_with(".json") && !path.ends_with(".bin")
+}
+
+pub fn read_data(name: &str) -> Result<parsing::DatabaseIndex, String> {
+	let path = data_path(name);
+	if is_split(&path) {
+		return read_split(&path);
+	}
+	let data = std::fs::read(&path).map_err(|e| format!("Unable to read `{}`: {}", path, e))?;
+	if binary::is_binary(&data) {
+		binary::decode(&data).map_err(|e| format!("`{}` is not valid: {}", path, e))
+	} else {
+		serde_json::from_slice(&data).map_err(|e| format!("`{}` is not valid: {}", path, e))
+	}
+}
+
+// Every `<Type>.json` of a split database
+fn read_split(dir: &str) -> Result<parsing::DatabaseIndex, String> {
+	split_collections(dir)?
+		.into_iter()
+		.map(|class| {
+			let records = read_collection_file(&Path::new(dir).join(format!("{}.json", class)))?;
+			Ok((class, records))
+		})
+		.collect()
+}
+
+// Names of the types a split database has a file of
+fn split_collections(dir: &str) -> Result<Vec<String>, String> {
+	let entries = std::fs::read_dir(dir).map_err(|e| format!("Unable to read `{}`: {}", dir, e))?;
+	let mut classes = Vec::new();
+	for entry in entries {
+		let path = entry.map_err(|e| format!("Unable to read `{}`: {}", dir, e))?.path();
+		if let (Some(class), Some("json")) = (path.file_stem(), path.extension().and_then(|e| e.to_str())) {
+			classes.push(class.to_string_lossy().into_owned());
+		}
+	}
+	Ok(classes)
+}
+
+fn read_collection_file(path: &Path) -> Result<Vec<JSONValue>, String> {
+	let data = std::fs::read(path).map_err(|e| format!("Unable to read `{}`: {}", path.display(), e))?;
+	serde_json::from_slice(&data).map_err(|e| format!("`{}` is not valid: {}", path.display(), e))
+}
+
+// Records of one type, reading no more than needed: its own file of a split database, its
+// section of `data.bin`, or else all of `data.json`
+pub fn read_collection(name: &str, class: &str) -> Result<Option<Vec<JSONValue>>, String> {
+	let path = data_path(name);
+	if is_split(&path) {
+		let file = Path::new(&path).join(format!("{}.json", class));
+		if file.exists() {
+			read_collection_file(&file).map(Some)
+		} else {
+			Ok(None)
+		}
+	} else if path.ends_with(".bin") {
+		let data = std::fs::read(&path).map_err(|e| format!("Unable to read `{}`: {}", path, e))?;
+		binary::decode_collection(&data, class).map_err(|e| format!("`{}` is not valid: {}", path, e))
+	} else {
+		Ok(read_data(name)?.remove(class))
+	}
+}
+
+// Write collections in the format the database is stored in
+pub fn write_data<'a>(name: &str, collections: impl IntoIterator<Item = (&'a String, &'a Vec<JSONValue>)>) {
+	let path = data_path(name);
+	if is_split(&path) {
+		write_split(&path, collections);
+	} else if path.ends_with(".bin") {
+		write_file(path.as_ref(), binary::encode(collections));
+	} else {
+		let db = collections.into_iter().collect::<HashMap<&String, &Vec<JSONValue>>>();
+		write_file(path.as_ref(), json!(db).to_string().as_bytes().to_vec());
+	}
+}
+
+fn write_split<'a>(dir: &str, collections: impl IntoIterator<Item = (&'a String, &'a Vec<JSONValue>)>) {
+	std::fs::create_dir_all(dir).unwrap_or_else(|e| panic!("Unable to create `{}`: {}", dir, e));
+	for (class, records) in collections {
+		write_collection(dir, class, records);
+	}
+}
+
+// One record per line, so a changed record is a changed line in diffs
+fn write_collection(dir: &str, class: &str, records: &[JSONValue]) {
+	let lines = records.iter().map(|r| r.to_string()).collect::<Vec<String>>();
+	let text = if lines.is_empty() {
+		"[]\n".to_owned()
+	} else {
+		format!("[\n{}\n]\n", lines.join(",\n"))
+	};
+	write_file(&format!("{}/{}.json", dir, class), text.into_bytes());
+}
+
+// Store a database as "json", "binary" or "split". What it's converted from is kept with
+// a `.bak` suffix, replacing an earlier backup. Returns whether anything was converted.
+pub fn convert_data(name: &str, format: &str) -> Result<bool, String> {
+	let (from, to) = (data_path(name), format_path(name, format)?);
+	if from == to {
+		return Ok(false);
+	}
+	// Files already there would be read as collections of the converted database
+	if format == "split" && std::fs::metadata(&to).is_ok() {
+		return Err(format!("`{}` already exists, move it away to convert {}", to, name));
+	}
+	let db = read_data(name)?;
+	match format {
+		"binary" => write_file(to.as_ref(), binary::encode(&db)),
+		"json" => write_file(to.as_ref(), json!(db).to_string().into_bytes()),
+		_ => write_split(&to, &db),
+	}
+	let backup = format!("{}.bak", from);
+	let _ = std::fs::remove_dir_all(&backup).or_else(|_| std::fs::remove_file(&backup));
+	std::fs::rename(&from, &backup).map_err(|e| format!("Unable to rename `{}`: {}", from, e))?;
+	Ok(true)
+}
+
 fn read_structure(db: &str) -> structure::StructureIndex {
 	let data = read_file(db);
 	serde_json::from_str::<structure::StructureIndex>(&data)
@@ -65,24 +338,182 @@ fn read_schema(path: &str) -> graphql_parser::schema::Document {
 		.expect(&format!("File `database/{}` is not valid GraphQL schema!", path)[..])
 }
 
-pub fn load_db(name: &str, directory: parsing::DatabaseDirectory) -> parsing::QueryParser {
-	let (json_path, schema_path, gql_path, instropection_path) = (
-		format!("database/{}/data.json", name),
+// FNV-1a, which unlike DefaultHasher stays the same across builds
+pub fn hash_text(text: &str) -> String {
+	let hash = text.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |h, b| {
+		(h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+	});
+	format!("{:016x}", hash)
+}
+
+// The schema of schema.gql, and how the data would migrate to it from the cached schema.json.
+// Nothing to migrate when schema.json is missing or made from the same schema.gql.
+pub fn plan_migration(
+	name: &str,
+	database: &parsing::DatabaseIndex,
+) -> (structure::StructureIndex, Option<migration::Migration>) {
+	let (schema_path, gql_path) = (
 		format!("database/{}/schema.json", name),
 		format!("database/{}/schema.gql", name),
+	);
+	let cached = if std::fs::metadata(&schema_path).is_ok() {
+		Some(read_structure(schema_path.as_ref()).into_perform_indexing())
+	} else {
+		None
+	};
+	if std::fs::metadata(&gql_path).is_err() {
+		return (cached.expect("Neither `schema.gql` nor `schema.json` exists!"), None);
+	}
+	let source = read_file(gql_path.as_ref());
+	let hash = hash_text(&source);
+	match cached {
+		Some(sch) if sch.hash == hash => (sch, None),
+		cached => {
+			let mut sch = schema::traverse_schema(name, &read_schema(gql_path.as_ref()));
+			sch.hash = hash;
+			let migration = match cached {
+				// Written before hashes were kept, and made from the same schema.gql
+				Some(old) if old.hash.is_empty() && same_structure(&old, &sch) => None,
+				Some(old) => Some(migration::plan(&old, &sch, database)),
+				None => None,
+			};
+			(sch, migration)
+		}
+	}
+}
+
+// Whether both schemas are the same, regardless of the schema.gql they're made from
+fn same_structure(a: &structure::StructureIndex, b: &structure::StructureIndex) -> bool {
+	let without_hash = |s: &structure::StructureIndex| {
+		let mut v = json!(s);
+		v["hash"] = JSONValue::Null;
+		v
+	};
+	without_hash(a) == without_hash(b)
+}
+
+// Whether schema.json is made from the present schema.gql, so the records need no migration
+pub fn is_migrated(name: &str) -> bool {
+	let (schema_path, gql_path) = (
+		format!("database/{}/schema.json", name),
+		format!("database/{}/schema.gql", name),
+	);
+	if std::fs::metadata(&schema_path).is_err() {
+		return false;
+	}
+	match std::fs::read_to_string(&gql_path) {
+		Ok(source) => read_structure(schema_path.as_ref()).hash == hash_text(&source),
+		Err(_) => true,
+	}
+}
+
+// Write the migrated data and the schema it's for. Of a split database, the files of types
+// the migration renamed or removed are removed too.
+pub fn apply_migration(name: &str, sch: &structure::StructureIndex, migration: &migration::Migration) {
+	let schema_path = format!("database/{}/schema.json", name);
+	write_data(name, &migration.database);
+	let path = data_path(name);
+	if is_split(&path) {
+		for class in migration.removed.iter().filter(|c| !migration.database.contains_key(*c)) {
+			let _ = std::fs::remove_file(Path::new(&path).join(format!("{}.json", class)));
+		}
+	}
+	write_file(schema_path.as_ref(), json!(sch).to_string().as_bytes().to_vec());
+}
+
+pub fn load_db(name: &str, directory: parsing::DatabaseDirectory) -> parsing::QueryParser {
+	let (schema_path, instropection_path) = (
+		format!("database/{}/schema.json", name),
 		format!("database/instropection.gql"),
 	);
 
-	let db = read_database(json_path.as_ref());
-	let sch = if std::fs::metadata(schema_path.clone()).is_ok() {
-		read_structure(schema_path.as_ref()).into_perform_indexing()
+	// Types of a split database are read when first used, unless schema.gql has changes to migrate
+	let path = data_path(name);
+	let is_lazy = is_split(&path) && is_migrated(name);
+	let mut db = if is_lazy {
+		HashMap::new()
 	} else {
-		let sch = schema::traverse_schema(name, &read_schema(gql_path.as_ref()));
-		write_file(schema_path.as_ref(), json!(sch).to_string().as_bytes().to_vec());
-		sch
+		read_data(name).unwrap_or_else(|e| panic!("{}", e))
+	};
+	// Safe changes of schema.gql are applied right away, breaking ones wait for `gqldbms migrate`
+	let sch = match plan_migration(name, &db) {
+		(sch, None) => {
+			// Only when it's new, or lacks the hash of schema.gql
+			if !is_migrated(name) {
+				write_file(schema_path.as_ref(), json!(sch).to_string().as_bytes().to_vec());
+			}
+			sch
+		}
+		(sch, Some(m)) if !m.is_breaking() => {
+			print!("Migrating {}:\n{}", name, m.report());
+			apply_migration(name, &sch, &m);
+			db = m.database;
+			sch
+		}
+		(_, Some(m)) => {
+			print!("Warning: schema.gql of {} has breaking changes:\n{}", name, m.report());
+			println!("Run `gqldbms migrate {}` to apply them. Until then, the previous schema is used.", name);
+			read_structure(schema_path.as_ref()).into_perform_indexing()
+		}
 	};
 	let intros = schema::traverse_schema("instropection", &read_schema(instropection_path.as_ref()));
-	parsing::QueryParser::new(db, sch, intros, directory)
+	let mut parser = parsing::QueryParser::new(db, sch, intros, directory);
+	if is_lazy {
+		parser.unloaded = split_collections(&path)
+			.unwrap_or_else(|e| panic!("{}", e))
+			.into_iter()
+			.filter(|c| {
+				!c.starts_with("__") && matches!(parser.schema.find_object(c), structure::StructureItem::Object(_))
+			})
+			.collect();
+		let name = name.to_owned();
+		parser.loader = Some(Arc::new(move |class: &str| read_collection(&name, class)));
+	}
+	// Sequences may be ahead of the stored ids, when the last records were deleted
+	if let Ok(data) = std::fs::read_to_string(format!("database/{}/sequences.json", name)) {
+		let saved: HashMap<String, i64> = serde_json::from_str(&data)
+			.expect("File `sequences.json` is not valid JSON object!");
+		parser.restore_sequences(saved);
+	}
+	parser
+}
+
+// Schema of a database as the server would serve it, without writing any file. Records are
+// only read when schema.gql has changes, to plan their migration. Like load_db, breaking
+// changes of schema.gql aren't used until migrated.
+pub fn load_schema(name: &str, directory: parsing::DatabaseDirectory) -> structure::StructureIndex {
+	let schema_path = format!("database/{}/schema.json", name);
+	// Records are only read to plan a migration
+	let sch = if is_migrated(name) {
+		read_structure(schema_path.as_ref()).into_perform_indexing()
+	} else {
+		match plan_migration(name, &read_data(name).unwrap_or_else(|e| panic!("{}", e))) {
+			(_, Some(m)) if m.is_breaking() => read_structure(schema_path.as_ref()).into_perform_indexing(),
+			(sch, _) => sch,
+		}
+	};
+	let intros = schema::traverse_schema("instropection", &read_schema("database/instropection.gql"));
+	parsing::QueryParser::new(HashMap::new(), sch, intros, directory).schema
+}
+
+// Write the (non-instropection) collections back to where they're stored. Of a split
+// database, only the files of changed collections are written.
+pub fn save_db(parser: &mut parsing::QueryParser) {
+	let path = data_path(&parser.schema.name);
+	let collections = parser.database.iter().filter(|(k, _)| !k.starts_with("__"));
+	if is_split(&path) {
+		std::fs::create_dir_all(&path).unwrap_or_else(|e| panic!("Unable to create `{}`: {}", path, e));
+		for (class, records) in collections.filter(|(k, _)| parser.changed.contains(*k)) {
+			write_collection(&path, class, records);
+		}
+	} else {
+		write_data(&parser.schema.name, collections);
+	}
+	parser.changed.clear();
+	if !parser.sequences.is_empty() {
+		let sequences_path = format!("database/{}/sequences.json", parser.schema.name);
+		write_file(sequences_path.as_ref(), json!(parser.sequences).to_string().as_bytes().to_vec());
+	}
 }
 
 pub fn load_canonical(directory: parsing::DatabaseDirectory) -> parsing::QueryParser {
@@ -122,4 +553,90 @@ pub fn load_canonical(directory: parsing::DatabaseDirectory) -> parsing::QueryPa
 	let mut res = parsing::QueryParser::new(db, sch, intros, directory);
 	res.is_canonical = true;
 	res
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn datetimes_are_kept_in_utc() {
+		let cases = [
+			("2020-01-31T12:34:56.789Z", "2020-01-31T12:34:56.789Z"),
+			("2020-01-31t12:34:56z", "2020-01-31T12:34:56.000Z"),
+			("2020-01-31 12:34", "2020-01-31T12:34:00.000Z"),
+			("2020-01-31T12:34:56.7Z", "2020-01-31T12:34:56.700Z"),
+			("2020-01-31T12:34:56.78912Z", "2020-01-31T12:34:56.789Z"),
+			("2020-01-31T05:00:00+07:00", "2020-01-30T22:00:00.000Z"),
+			("2020-12-31T23:30:00-01:30", "2021-01-01T01:00:00.000Z"),
+			("1969-12-31T23:59:59.999Z", "1969-12-31T23:59:59.999Z"),
+			("1970-01-01T00:00:00Z", "1970-01-01T00:00:00.000Z"),
+		];
+		for (text, expected) in &cases {
+			assert_eq!(parse_datetime(text).map(format_datetime).as_deref(), Some(*expected), "{}", text);
+		}
+		assert_eq!(parse_datetime("1970-01-01T00:00:01Z"), Some(1000));
+		assert_eq!(parse_datetime("1969-12-31T23:59:59.999Z"), Some(-1));
+	}
+
+	#[test]
+	fn leap_days_are_only_on_leap_years() {
+		assert!(parse_datetime("2020-02-29T00:00:00Z").is_some());
+		assert!(parse_datetime("2000-02-29T00:00:00Z").is_some());
+		assert!(parse_datetime("2019-02-29T00:00:00Z").is_none());
+		assert!(parse_datetime("1900-02-29T00:00:00Z").is_none());
+		assert_eq!(
+			format_datetime(parse_datetime("2020-02-28T23:00:00-02:00").unwrap()),
+			"2020-02-29T01:00:00.000Z"
+		);
+	}
+
+	#[test]
+	fn invalid_datetimes_are_refused() {
+		let cases = [
+			"",
+			"2020-01-31",
+			"2020-01-31T",
+			"2020/01/31T12:00:00Z",
+			"2020-13-01T12:00:00Z",
+			"2020-00-01T12:00:00Z",
+			"2020-04-31T12:00:00Z",
+			"2020-01-00T12:00:00Z",
+			"2020-01-31T24:00:00Z",
+			"2020-01-31T12:60:00Z",
+			"2020-01-31T12:00:60Z",
+			"2020-01-31T12:00:00+0700",
+			"2020-01-31T12:00:00+07",
+			"2020-01-31T12Z",
+			"2020-01-31T12:00:00.Z",
+			"2020-01-31T12:00:00.abcZ",
+			"2020-01-31X12:00:00Z",
+			"2020-01-31T12:00:00Zfoo",
+			"２020-01-31T12:00:00Z",
+		];
+		for text in &cases {
+			assert_eq!(parse_datetime(text), None, "{}", text);
+		}
+	}
+
+	#[test]
+	fn datetimes_round_trip() {
+		// About a week apart, over four centuries and before 1970
+		let mut millis = -12_219_292_800_000i64;
+		while millis < 32_503_680_000_000 {
+			let text = format_datetime(millis);
+			assert_eq!(parse_datetime(&text), Some(millis), "{}", text);
+			millis += 7 * 86_400_000 + 3_723_004;
+		}
+	}
+
+	#[test]
+	fn legacy_schema_is_the_same_when_its_structure_is() {
+		let parse = |sdl: &str| schema::traverse_schema("test", &graphql_parser::parse_schema(sdl).unwrap());
+		let mut legacy = parse("type Post { id: Int\n title: String }");
+		let mut edited = parse("type Post {\n  id: Int\n  title: String\n}");
+		legacy.hash = String::new();
+		edited.hash = hash_text("type Post {\n  id: Int\n  title: String\n}");
+		assert!(same_structure(&legacy, &edited));
+		assert!(!same_structure(&legacy, &parse("type Post { id: Int\n title: String! }")));
+	}
+}